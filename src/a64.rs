@@ -0,0 +1,316 @@
+//! A small AArch64 encoder covering the instructions the JIT emits. Every
+//! instruction is one little-endian 32-bit word; branches go to `Label`s and
+//! are patched once all labels are bound.
+
+use crate::cell::CellWidth;
+
+use std::convert::{TryFrom, TryInto};
+
+/// A general purpose register, `X0` to `X30`. Register 31 is the zero
+/// register or the stack pointer depending on the instruction.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Reg(pub u8);
+
+pub const X0:  Reg = Reg(0);
+pub const X1:  Reg = Reg(1);
+pub const X9:  Reg = Reg(9);
+pub const X10: Reg = Reg(10);
+pub const X11: Reg = Reg(11);
+pub const X12: Reg = Reg(12);
+pub const X16: Reg = Reg(16);
+pub const X20: Reg = Reg(20);
+pub const X21: Reg = Reg(21);
+pub const X22: Reg = Reg(22);
+pub const X23: Reg = Reg(23);
+pub const X24: Reg = Reg(24);
+pub const X30: Reg = Reg(30);
+pub const XZR: Reg = Reg(31);
+
+/// Width of a register operand
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Width {
+    /// 32-bit `W` register
+    W,
+
+    /// 64-bit `X` register
+    X,
+}
+
+impl Width {
+    /// The `sf` bit selecting 64-bit operation
+    fn sf(self) -> u32 {
+        match self {
+            Width::W => 0,
+            Width::X => 1 << 31,
+        }
+    }
+}
+
+/// Memory access sizes
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Size {
+    Byte,
+    Half,
+    Word,
+    Double,
+}
+
+impl Size {
+    /// log2 of the access size, also the `size` field of loads and stores
+    fn scale(self) -> u32 {
+        self as u32
+    }
+
+    /// Register width holding a value of this size
+    pub fn width(self) -> Width {
+        if self == Size::Double { Width::X } else { Width::W }
+    }
+}
+
+impl From<CellWidth> for Size {
+    fn from(width: CellWidth) -> Size {
+        match width {
+            CellWidth::U8  => Size::Byte,
+            CellWidth::U16 => Size::Half,
+            CellWidth::U32 => Size::Word,
+            CellWidth::U64 => Size::Double,
+        }
+    }
+}
+
+/// Add or subtract
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AddSub {
+    Add,
+    Sub,
+}
+
+impl AddSub {
+    /// The `op` bit
+    fn op(self) -> u32 {
+        match self {
+            AddSub::Add => 0,
+            AddSub::Sub => 1 << 30,
+        }
+    }
+}
+
+/// Branch conditions
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Cond {
+    /// Equal (`b.eq`)
+    Eq = 0x0,
+
+    /// Not equal (`b.ne`)
+    Ne = 0x1,
+
+    /// Unsigned higher or same (`b.hs`)
+    Hs = 0x2,
+
+    /// Unsigned lower (`b.lo`)
+    Lo = 0x3,
+
+    /// Unsigned higher (`b.hi`)
+    Hi = 0x8,
+}
+
+/// A branch target, bound to a position with `Assembler::bind`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Label(usize);
+
+/// Offset fields of the branch instructions
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Field {
+    /// `b`, bits 0..26
+    Imm26,
+
+    /// `b.cond`, `cbz` and `cbnz`, bits 5..24
+    Imm19,
+}
+
+/// A branch waiting for its label to be bound
+struct Fixup {
+    /// Position of the instruction in the code
+    at: usize,
+
+    field: Field,
+
+    label: Label,
+}
+
+/// Machine code being assembled
+#[derive(Default)]
+pub struct Assembler {
+    code: Vec<u8>,
+
+    /// Position of every label, once bound
+    labels: Vec<Option<usize>>,
+
+    fixups: Vec<Fixup>,
+}
+
+impl Assembler {
+    pub fn new() -> Self {
+        Assembler::default()
+    }
+
+    /// Current position in the code
+    pub fn position(&self) -> usize {
+        self.code.len()
+    }
+
+    /// Create a label which is not bound yet
+    pub fn new_label(&mut self) -> Label {
+        self.labels.push(None);
+        Label(self.labels.len() - 1)
+    }
+
+    /// Bind `label` to the current position
+    pub fn bind(&mut self, label: Label) {
+        assert!(self.labels[label.0].is_none(), "label bound twice");
+        self.labels[label.0] = Some(self.code.len());
+    }
+
+    /// Resolve all branches and return the machine code
+    pub fn finish(mut self) -> Vec<u8> {
+        for fixup in std::mem::take(&mut self.fixups) {
+            let target = self.labels[fixup.label.0].expect("branch to unbound label");
+            let offset = (target as i64 - fixup.at as i64) / 4;
+            let (bits, shift) = match fixup.field {
+                Field::Imm26 => (26, 0),
+                Field::Imm19 => (19, 5),
+            };
+            assert!(offset >= -(1 << (bits - 1)) && offset < 1 << (bits - 1),
+                    "branch out of range");
+
+            let mask = (1u32 << bits) - 1;
+            let word = u32::from_le_bytes(self.code[fixup.at..fixup.at + 4].try_into().unwrap());
+            let word = word | (offset as u32 & mask) << shift;
+            self.code[fixup.at..fixup.at + 4].copy_from_slice(&word.to_le_bytes());
+        }
+        self.code
+    }
+
+    fn emit(&mut self, word: u32) {
+        self.code.extend_from_slice(&word.to_le_bytes());
+    }
+
+    /// `add`/`sub rd, rn, #imm`. Immediates above 12 bits must be multiples
+    /// of 4096 up to 24 bits.
+    pub fn add_sub_imm(&mut self, op: AddSub, width: Width, rd: Reg, rn: Reg, imm: u32) {
+        let (imm, shift) = if imm < 1 << 12 {
+            (imm, 0)
+        } else {
+            assert!(imm & 0xfff == 0 && imm < 1 << 24, "immediate not encodable");
+            (imm >> 12, 1 << 22)
+        };
+        self.emit(width.sf() | op.op() | 0x1100_0000 | shift | imm << 10
+                  | (rn.0 as u32) << 5 | rd.0 as u32);
+    }
+
+    /// `add`/`sub rd, rn, rm`
+    pub fn add_sub_reg(&mut self, op: AddSub, width: Width, rd: Reg, rn: Reg, rm: Reg) {
+        self.emit(width.sf() | op.op() | 0x0b00_0000 | (rm.0 as u32) << 16
+                  | (rn.0 as u32) << 5 | rd.0 as u32);
+    }
+
+    /// `madd rd, rn, rm, ra`, `rd = ra + rn * rm`
+    pub fn madd(&mut self, width: Width, rd: Reg, rn: Reg, rm: Reg, ra: Reg) {
+        self.emit(width.sf() | 0x1b00_0000 | (rm.0 as u32) << 16 | (ra.0 as u32) << 10
+                  | (rn.0 as u32) << 5 | rd.0 as u32);
+    }
+
+    /// `cmp rn, #imm`, a 12-bit immediate
+    pub fn cmp_imm(&mut self, width: Width, rn: Reg, imm: u32) {
+        assert!(imm < 1 << 12, "immediate not encodable");
+        self.emit(width.sf() | 0x7100_0000 | imm << 10 | (rn.0 as u32) << 5 | XZR.0 as u32);
+    }
+
+    /// `cmp rn, rm`
+    pub fn cmp_reg(&mut self, width: Width, rn: Reg, rm: Reg) {
+        self.emit(width.sf() | 0x6b00_0000 | (rm.0 as u32) << 16
+                  | (rn.0 as u32) << 5 | XZR.0 as u32);
+    }
+
+    /// `mov rd, rm` on 64-bit registers
+    pub fn mov_reg(&mut self, rd: Reg, rm: Reg) {
+        self.emit(0xaa00_03e0 | (rm.0 as u32) << 16 | rd.0 as u32);
+    }
+
+    /// Load a 64-bit immediate with the shortest `movz`/`movn` and `movk`
+    /// sequence
+    pub fn mov_imm(&mut self, rd: Reg, imm: u64) {
+        let halves = || (0..4).map(|hw| (hw, (imm >> (hw * 16)) as u16));
+
+        // Start from all ones when more halfwords are 0xffff than zero
+        let inverted = halves().filter(|&(_, half)| half == 0xffff).count()
+            > halves().filter(|&(_, half)| half == 0).count();
+        let fill = if inverted { 0xffff } else { 0 };
+
+        let mut needed: Vec<(u32, u16)> = halves().filter(|&(_, half)| half != fill).collect();
+        if needed.is_empty() {
+            needed.push((0, fill));
+        }
+
+        for (idx, &(hw, half)) in needed.iter().enumerate() {
+            let word = match (idx, inverted) {
+                (0, true)  => 0x9280_0000 | (!half as u32) << 5,
+                (0, false) => 0xd280_0000 | (half as u32) << 5,
+                _          => 0xf280_0000 | (half as u32) << 5,
+            };
+            self.emit(word | hw << 21 | rd.0 as u32);
+        }
+    }
+
+    /// `ldr{b,h}`/`ldr rt, [rn]`, zero extending into `rt`
+    pub fn load(&mut self, size: Size, rt: Reg, rn: Reg) {
+        self.emit(size.scale() << 30 | 0x3940_0000 | (rn.0 as u32) << 5 | rt.0 as u32);
+    }
+
+    /// `str{b,h}`/`str rt, [rn]`
+    pub fn store(&mut self, size: Size, rt: Reg, rn: Reg) {
+        self.emit(size.scale() << 30 | 0x3900_0000 | (rn.0 as u32) << 5 | rt.0 as u32);
+    }
+
+    /// `ldr xt, [xn, #offset]`, with `offset` a multiple of eight
+    pub fn load_offset(&mut self, rt: Reg, rn: Reg, offset: usize) {
+        assert!(offset.is_multiple_of(8) && offset / 8 < 1 << 12, "offset not encodable");
+        let imm = u32::try_from(offset / 8).unwrap();
+        self.emit(0xf940_0000 | imm << 10 | (rn.0 as u32) << 5 | rt.0 as u32);
+    }
+
+    /// `blr rn`
+    pub fn blr(&mut self, rn: Reg) {
+        self.emit(0xd63f_0000 | (rn.0 as u32) << 5);
+    }
+
+    /// `ret rn`
+    pub fn ret(&mut self, rn: Reg) {
+        self.emit(0xd65f_0000 | (rn.0 as u32) << 5);
+    }
+
+    /// `b label`
+    pub fn b(&mut self, label: Label) {
+        self.branch(0x1400_0000, Field::Imm26, label);
+    }
+
+    /// `b.cond label`
+    pub fn b_cond(&mut self, cond: Cond, label: Label) {
+        self.branch(0x5400_0000 | cond as u32, Field::Imm19, label);
+    }
+
+    /// `cbz rt, label`
+    pub fn cbz(&mut self, width: Width, rt: Reg, label: Label) {
+        self.branch(width.sf() | 0x3400_0000 | rt.0 as u32, Field::Imm19, label);
+    }
+
+    /// `cbnz rt, label`
+    pub fn cbnz(&mut self, width: Width, rt: Reg, label: Label) {
+        self.branch(width.sf() | 0x3500_0000 | rt.0 as u32, Field::Imm19, label);
+    }
+
+    fn branch(&mut self, word: u32, field: Field, label: Label) {
+        self.fixups.push(Fixup { at: self.code.len(), field, label });
+        self.emit(word);
+    }
+}
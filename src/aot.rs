@@ -0,0 +1,344 @@
+//! Ahead-of-time compilation to a standalone x86-64 Linux executable. The
+//! program is compiled by the x86-64 JIT backend and wrapped in a static
+//! ELF64 together with a small runtime, so the result needs neither this
+//! crate nor a libc.
+//!
+//! The runtime implements the `HostCalls` table with raw syscalls, buffers
+//! the output like `OutputBuffer` does and maps the exit status of the code
+//! to the exit codes of the command line tool. The tape lives in its own
+//! zero initialised segment with `GUARD_SIZE` bytes of unmapped address
+//! space below it and a `GUARD_SIZE` mapping without any access right above
+//! it, reserved by the runtime at startup, so unchecked code which walks off
+//! the tape faults instead of overwriting the runtime state or whatever the
+//! kernel put behind the tape.
+
+use crate::emu::VmExit;
+use crate::io::OUTPUT_BUFFER_SIZE;
+use crate::ir::Program;
+use crate::jit::{generate_jit_for, HostCalls, JitOptions, Target};
+use crate::jit::{HOST_EOF, HOST_ERROR};
+use crate::optimize::Optimizer;
+use crate::tape::GUARD_SIZE;
+use crate::x64::{Alu, Assembler, Cond, Mem, Reg, Size};
+
+use std::mem::offset_of;
+
+/// Address the executable is loaded at
+const BASE: u64 = 0x40_0000;
+
+const PAGE_SIZE: u64 = 4096;
+
+/// Size of the ELF header
+const EHDR_SIZE: u64 = 64;
+
+/// Size of one program header
+const PHDR_SIZE: u64 = 56;
+
+/// Text, runtime data, tape and stack segments
+const PHDR_COUNT: u64 = 4;
+
+/// Offsets in the runtime data, which starts with the `read` and `write`
+/// pointers of a `HostCalls` table. The generated code uses no other field.
+const OUTPUT_LEN: i32 = 16;
+const OUTPUT_BUFFER: i32 = 24;
+
+const _: () = assert!(offset_of!(HostCalls, read) < OUTPUT_LEN as usize
+                      && offset_of!(HostCalls, write) < OUTPUT_LEN as usize);
+
+const SYS_READ: u64 = 0;
+const SYS_WRITE: u64 = 1;
+const SYS_MMAP: u64 = 9;
+const SYS_EXIT: u64 = 60;
+
+const MAP_PRIVATE: u64 = 0x02;
+const MAP_FIXED: u64 = 0x10;
+const MAP_ANONYMOUS: u64 = 0x20;
+const MAP_NORESERVE: u64 = 0x4000;
+
+/// Written to stderr when a bounds check fails
+const OUT_OF_BOUNDS: &[u8] = b"data pointer out of bounds\n";
+
+/// Written to stderr when a read or write failed
+const IO_ERROR: &[u8] = b"could not read input or write output\n";
+
+/// Options for a standalone executable
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AotOptions {
+    /// Code generation options, as for the JIT
+    pub jit: JitOptions,
+
+    /// Number of cells on the tape
+    pub tape_cells: usize,
+
+    /// Flush the output after every `.`
+    pub unbuffered: bool,
+
+    /// The passes run before code generation
+    pub optimizer: Optimizer,
+}
+
+impl Default for AotOptions {
+    fn default() -> Self {
+        AotOptions {
+            jit: JitOptions::default(),
+            tape_cells: 30000,
+            unbuffered: false,
+            optimizer: Optimizer::default(),
+        }
+    }
+}
+
+/// Addresses the runtime refers to
+#[derive(Clone, Copy, Default, PartialEq)]
+struct Layout {
+    /// Runtime data, see `OUTPUT_LEN`
+    data: u64,
+
+    tape: u64,
+    tape_end: u64,
+
+    /// `OUT_OF_BOUNDS` followed by `IO_ERROR`
+    messages: u64,
+}
+
+/// Exit with the code in `rdi`
+fn exit(asm: &mut Assembler) {
+    asm.mov_reg_imm(Reg::Rax, SYS_EXIT);
+    asm.syscall();
+}
+
+/// Write `len` bytes at `addr` to stderr
+fn write_stderr(asm: &mut Assembler, addr: u64, len: usize) {
+    asm.mov_reg_imm(Reg::Rax, SYS_WRITE);
+    asm.mov_reg_imm(Reg::Rdi, 2);
+    asm.mov_reg_imm(Reg::Rsi, addr);
+    asm.mov_reg_imm(Reg::Rdx, len as u64);
+    asm.syscall();
+}
+
+/// Assemble the runtime for the text segment at `text`, returning it with
+/// the offset of its entry point. The program code must follow it directly.
+fn runtime(text: u64, layout: &Layout, options: &AotOptions) -> (Assembler, usize) {
+    let mut asm = Assembler::new();
+    let flush = asm.new_label();
+    let leave = asm.new_label();
+    let program = asm.new_label();
+
+    // Write the buffered output to stdout, returning zero or `HOST_ERROR`
+    asm.bind(flush);
+    let flush_loop = asm.new_label();
+    let flushed = asm.new_label();
+    let write_error = asm.new_label();
+    asm.mov_reg_reg(Reg::Rsi, Reg::R12);
+    asm.alu_reg_imm(Alu::Add, Reg::Rsi, OUTPUT_BUFFER);
+    asm.mov_reg_mem(Reg::Rdx, Mem::new(Reg::R12, OUTPUT_LEN));
+    asm.mov_mem_imm(Size::Qword, Mem::new(Reg::R12, OUTPUT_LEN), 0);
+    asm.bind(flush_loop);
+    asm.test_reg_reg(Reg::Rdx, Reg::Rdx);
+    asm.jcc(Cond::Equal, flushed);
+    asm.mov_reg_imm(Reg::Rax, SYS_WRITE);
+    asm.mov_reg_imm(Reg::Rdi, 1);
+    asm.syscall();
+    asm.test_reg_reg(Reg::Rax, Reg::Rax);
+    asm.jcc(Cond::Sign, write_error);
+    asm.alu_reg_reg(Alu::Add, Reg::Rsi, Reg::Rax);
+    asm.alu_reg_reg(Alu::Sub, Reg::Rdx, Reg::Rax);
+    asm.jmp(flush_loop);
+    asm.bind(flushed);
+    asm.zero(Reg::Rax);
+    asm.bind(leave);
+    asm.ret();
+    asm.bind(write_error);
+    asm.mov_reg_imm(Reg::Rax, HOST_ERROR as u64);
+    asm.ret();
+
+    // The read host call, one byte per syscall so no input is consumed
+    // beyond what the program reads
+    let read = asm.position();
+    let got_byte = asm.new_label();
+    let eof = asm.new_label();
+    asm.call(flush);
+    asm.test_reg_reg(Reg::Rax, Reg::Rax);
+    asm.jcc(Cond::NotEqual, leave);
+    asm.alu_reg_imm(Alu::Sub, Reg::Rsp, 8);
+    asm.mov_reg_imm(Reg::Rax, SYS_READ);
+    asm.zero(Reg::Rdi);
+    asm.mov_reg_reg(Reg::Rsi, Reg::Rsp);
+    asm.mov_reg_imm(Reg::Rdx, 1);
+    asm.syscall();
+    asm.movzx_reg_mem8(Reg::Rcx, Mem::new(Reg::Rsp, 0));
+    asm.alu_reg_imm(Alu::Add, Reg::Rsp, 8);
+    asm.alu_reg_imm(Alu::Cmp, Reg::Rax, 1);
+    asm.jcc(Cond::Equal, got_byte);
+    asm.test_reg_reg(Reg::Rax, Reg::Rax);
+    asm.jcc(Cond::Equal, eof);
+    asm.mov_reg_imm(Reg::Rax, HOST_ERROR as u64);
+    asm.ret();
+    asm.bind(eof);
+    asm.mov_reg_imm(Reg::Rax, HOST_EOF as u64);
+    asm.ret();
+    asm.bind(got_byte);
+    asm.mov_reg_reg(Reg::Rax, Reg::Rcx);
+    asm.ret();
+
+    // The write host call, appending the byte in `sil` to the buffer
+    let write = asm.position();
+    asm.mov_reg_mem(Reg::Rax, Mem::new(Reg::R12, OUTPUT_LEN));
+    asm.alu_reg_reg(Alu::Add, Reg::Rax, Reg::R12);
+    asm.mov_mem_reg(Size::Byte, Mem::new(Reg::Rax, OUTPUT_BUFFER), Reg::Rsi);
+    asm.alu_mem_imm(Alu::Add, Size::Qword, Mem::new(Reg::R12, OUTPUT_LEN), 1);
+    if options.unbuffered {
+        asm.jmp(flush);
+    } else {
+        asm.alu_reg_imm(Alu::Cmp, Reg::Rsi, b'\n' as i32);
+        asm.jcc(Cond::Equal, flush);
+        asm.alu_mem_imm(Alu::Cmp, Size::Qword, Mem::new(Reg::R12, OUTPUT_LEN),
+                        OUTPUT_BUFFER_SIZE as i64);
+        asm.jcc(Cond::AboveEqual, flush);
+        asm.zero(Reg::Rax);
+        asm.ret();
+    }
+
+    // Entry point: reserve the guard above the tape, fill in the host
+    // calls, run the program with the usual registers, flush and exit with
+    // the status of the program
+    let start = asm.position();
+    let exit_ok = asm.new_label();
+    let input_exhausted = asm.new_label();
+    let io_error = asm.new_label();
+    let exit_now = asm.new_label();
+    asm.mov_reg_imm(Reg::Rax, SYS_MMAP);
+    asm.mov_reg_imm(Reg::Rdi, align_up(layout.tape_end, PAGE_SIZE));
+    asm.mov_reg_imm(Reg::Rsi, GUARD_SIZE as u64);
+    asm.zero(Reg::Rdx);  // PROT_NONE
+    asm.mov_reg_imm(Reg::R10, MAP_PRIVATE | MAP_FIXED | MAP_ANONYMOUS | MAP_NORESERVE);
+    asm.mov_reg_imm(Reg::R8, u64::MAX);  // no file
+    asm.zero(Reg::R9);
+    asm.syscall();
+    asm.test_reg_reg(Reg::Rax, Reg::Rax);
+    asm.jcc(Cond::Sign, io_error);
+    asm.mov_reg_imm(Reg::R12, layout.data);
+    asm.mov_reg_imm(Reg::Rax, text + read as u64);
+    asm.mov_mem_reg(Size::Qword, Mem::new(Reg::R12, offset_of!(HostCalls, read) as i32), Reg::Rax);
+    asm.mov_reg_imm(Reg::Rax, text + write as u64);
+    asm.mov_mem_reg(Size::Qword, Mem::new(Reg::R12, offset_of!(HostCalls, write) as i32), Reg::Rax);
+    asm.mov_reg_imm(Reg::R13, layout.tape);
+    asm.mov_reg_imm(Reg::R14, layout.tape);
+    asm.mov_reg_imm(Reg::R15, layout.tape_end);
+    asm.call(program);
+
+    asm.mov_reg_reg(Reg::Rbx, Reg::Rax);
+    asm.call(flush);
+    asm.test_reg_reg(Reg::Rax, Reg::Rax);
+    asm.jcc(Cond::NotEqual, io_error);
+    asm.mov_reg_imm(Reg::Rax, HOST_ERROR as u64);
+    asm.cmp_reg_reg(Reg::Rbx, Reg::Rax);
+    asm.jcc(Cond::Equal, io_error);
+
+    // Zero is a normal exit and `INPUT_EXHAUSTED` the only tag with the top
+    // bit set, anything else is the offset of a failed bounds check
+    asm.test_reg_reg(Reg::Rbx, Reg::Rbx);
+    asm.jcc(Cond::Equal, exit_ok);
+    asm.jcc(Cond::Sign, input_exhausted);
+    write_stderr(&mut asm, layout.messages, OUT_OF_BOUNDS.len());
+    asm.mov_reg_imm(Reg::Rdi, 2);
+    asm.jmp(exit_now);
+
+    asm.bind(io_error);
+    write_stderr(&mut asm, layout.messages + OUT_OF_BOUNDS.len() as u64, IO_ERROR.len());
+    asm.mov_reg_imm(Reg::Rdi, 1);
+    asm.jmp(exit_now);
+
+    asm.bind(input_exhausted);
+    asm.mov_reg_imm(Reg::Rdi, 4);
+    asm.jmp(exit_now);
+
+    asm.bind(exit_ok);
+    asm.zero(Reg::Rdi);
+    asm.bind(exit_now);
+    exit(&mut asm);
+
+    asm.bind(program);
+    (asm, start)
+}
+
+fn align_up(value: u64, align: u64) -> u64 {
+    value.div_ceil(align) * align
+}
+
+/// Append a program header
+fn program_header(elf: &mut Vec<u8>, kind: u32, flags: u32, offset: u64, vaddr: u64,
+                  filesz: u64, memsz: u64) {
+    elf.extend_from_slice(&kind.to_le_bytes());
+    elf.extend_from_slice(&flags.to_le_bytes());
+    elf.extend_from_slice(&offset.to_le_bytes());
+    elf.extend_from_slice(&vaddr.to_le_bytes());
+    elf.extend_from_slice(&vaddr.to_le_bytes());
+    elf.extend_from_slice(&filesz.to_le_bytes());
+    elf.extend_from_slice(&memsz.to_le_bytes());
+    elf.extend_from_slice(&PAGE_SIZE.to_le_bytes());
+}
+
+/// Compile the program, optimized by `AotOptions::optimizer`, into a static
+/// x86-64 Linux executable
+pub fn generate_elf(program: &Program, options: &AotOptions) -> Result<Vec<u8>, VmExit> {
+    const PT_LOAD: u32 = 1;
+    const PT_GNU_STACK: u32 = 0x6474_e551;
+    const PF_X: u32 = 1;
+    const PF_W: u32 = 2;
+    const PF_R: u32 = 4;
+
+    let code = generate_jit_for(Target::X86_64, &options.optimizer.run(program), &options.jit)?;
+
+    // The runtime refers to addresses behind the program and its immediates
+    // grow once they pass 2 GiB, so lay it out until its size settles
+    let text = BASE + EHDR_SIZE + PHDR_COUNT * PHDR_SIZE;
+    let data_size = OUTPUT_BUFFER as u64 + OUTPUT_BUFFER_SIZE as u64;
+    let tape_size = (options.tape_cells * options.jit.cell_width.bytes()) as u64;
+    let mut layout = Layout::default();
+    let (runtime_code, start) = loop {
+        let (asm, start) = runtime(text, &layout, options);
+        let runtime_len = asm.position() as u64;
+
+        let messages = text + runtime_len + code.len() as u64;
+        let data = align_up(messages + (OUT_OF_BOUNDS.len() + IO_ERROR.len()) as u64, PAGE_SIZE);
+        let tape = align_up(data + data_size, PAGE_SIZE) + GUARD_SIZE as u64;
+        let settled = Layout { data, tape, tape_end: tape + tape_size, messages };
+
+        if settled == layout {
+            break (asm.finish(), start);
+        }
+        layout = settled;
+    };
+    let text_end = layout.messages + (OUT_OF_BOUNDS.len() + IO_ERROR.len()) as u64;
+
+    let mut elf = Vec::new();
+    elf.extend_from_slice(b"\x7fELF");
+    elf.extend_from_slice(&[2, 1, 1, 0]);  // 64-bit, little-endian, version 1, SysV
+    elf.extend_from_slice(&[0; 8]);
+    elf.extend_from_slice(&2u16.to_le_bytes());   // ET_EXEC
+    elf.extend_from_slice(&62u16.to_le_bytes());  // EM_X86_64
+    elf.extend_from_slice(&1u32.to_le_bytes());
+    elf.extend_from_slice(&(text + start as u64).to_le_bytes());
+    elf.extend_from_slice(&EHDR_SIZE.to_le_bytes());  // program headers
+    elf.extend_from_slice(&0u64.to_le_bytes());       // no section headers
+    elf.extend_from_slice(&0u32.to_le_bytes());
+    elf.extend_from_slice(&(EHDR_SIZE as u16).to_le_bytes());
+    elf.extend_from_slice(&(PHDR_SIZE as u16).to_le_bytes());
+    elf.extend_from_slice(&(PHDR_COUNT as u16).to_le_bytes());
+    elf.extend_from_slice(&[0; 6]);
+
+    program_header(&mut elf, PT_LOAD, PF_R | PF_X, 0, BASE, text_end - BASE, text_end - BASE);
+    program_header(&mut elf, PT_LOAD, PF_R | PF_W, 0, layout.data, 0, data_size);
+    program_header(&mut elf, PT_LOAD, PF_R | PF_W, 0, layout.tape, 0, tape_size);
+    program_header(&mut elf, PT_GNU_STACK, PF_R | PF_W, 0, 0, 0, 0);
+    assert_eq!(elf.len() as u64, text - BASE);
+
+    elf.extend_from_slice(&runtime_code);
+    elf.extend_from_slice(&code);
+    elf.extend_from_slice(OUT_OF_BOUNDS);
+    elf.extend_from_slice(IO_ERROR);
+    assert_eq!(elf.len() as u64, text_end - BASE);
+
+    Ok(elf)
+}
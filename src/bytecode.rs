@@ -0,0 +1,583 @@
+//! Compact bytecode for the interpreter of `Engine::Bytecode`. The IR is
+//! compiled ahead of the run into instructions which carry everything they
+//! need: loop targets point past the matching bracket, `+` and `-` become a
+//! single wrapping addition and pointer movements carry their direction.
+//! Running the bytecode is a single `match` per instruction, without unsafe
+//! code, so it works on every host the JIT does not support.
+//!
+//! A pointer movement followed by `[` or `]` becomes one instruction which
+//! moves and runs the test of the bracket. Loop bodies end in a pointer
+//! movement once the `offsets` pass ran, and `>[` starts many loops, so
+//! this saves a good part of the dispatches. The bracket keeps its own
+//! instruction for the jumps which land on it, so instruction `idx` is
+//! always operation `idx` of the IR and shares its source offset.
+//!
+//! Bytecode does not depend on the cell width, `Bytecode::to_bytes` writes
+//! it in a stable binary format so programs can be parsed and optimized
+//! once and run many times:
+//!
+//! | field        | encoding                                            |
+//! |--------------|-----------------------------------------------------|
+//! | magic        | `BFBC`                                              |
+//! | version      | `u16`, little-endian, `VERSION`                     |
+//! | count        | unsigned varint, number of instructions             |
+//! | instructions | opcode byte followed by its operands                |
+//! | offsets      | signed varint, source offset of every instruction   |
+//! |              | as the difference to the one before                |
+//! | checksum     | `u32`, little-endian, CRC-32 of everything before   |
+//!
+//! Varints are LEB128. Pointer movements and cell offsets are signed and
+//! zigzag encoded, cell values are too, as the two's complement of their
+//! 64-bit value, so that `-` takes a single byte. Jump targets are unsigned
+//! instruction indices. The opcodes are, in order from `0`: `Move`, `Add`,
+//! `Write`, `Read`, `Clear`, `Scan`, `MulAdd`, `AddAt`, `SetAt`,
+//! `WriteConst` (a raw byte), `JumpIfZero`, `JumpIfNonZero`,
+//! `MoveJumpIfZero` and `MoveJumpIfNonZero`, with their operands in the
+//! order of their fields.
+
+use crate::cell::Cell;
+use crate::emu::{EofPolicy, VmExit};
+use crate::io::{BfInput, OutputBuffer};
+use crate::ir::{BfOperation, Program};
+
+use std::convert::TryFrom;
+use std::fmt;
+
+/// First bytes of serialized bytecode
+pub const MAGIC: [u8; 4] = *b"BFBC";
+
+/// Version of the serialized format written by `Bytecode::to_bytes`, bumped
+/// whenever instructions or their encoding change
+pub const VERSION: u16 = 1;
+
+/// A bytecode instruction. Jump targets are instruction indices.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Instruction {
+    /// Move the pointer by the given number of cells
+    Move(isize),
+
+    /// Add to the current cell, wrapping at the cell width
+    Add(u64),
+
+    /// `.`
+    Write,
+
+    /// `,`
+    Read,
+
+    /// Clear the current cell
+    Clear,
+
+    /// Move the pointer by the given stride until the current cell is zero
+    Scan(isize),
+
+    /// Add the current cell times `factor` to the cell `offset` cells away
+    MulAdd { offset: isize, factor: u64 },
+
+    /// Add `delta` to the cell `offset` cells away
+    AddAt { offset: isize, delta: u64 },
+
+    /// Store `value` in the cell `offset` cells away
+    SetAt { offset: isize, value: u64 },
+
+    /// Write a known byte
+    WriteConst(u8),
+
+    /// `[`, jump to the instruction after the matching `]` when the current
+    /// cell is zero
+    JumpIfZero(usize),
+
+    /// `]`, jump to the first instruction of the loop body when the current
+    /// cell is not zero
+    JumpIfNonZero(usize),
+
+    /// `Move` followed by the `JumpIfZero` of a `[`, continuing after that
+    /// `[` when the cell moved to is not zero
+    MoveJumpIfZero { delta: isize, target: usize },
+
+    /// `Move` followed by the `JumpIfNonZero` of a `]`, continuing after
+    /// that `]` when the cell moved to is zero
+    MoveJumpIfNonZero { delta: isize, target: usize },
+}
+
+/// A program compiled to bytecode
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Bytecode {
+    /// The instructions, one per operation of the IR
+    pub instructions: Vec<Instruction>,
+
+    /// Source offset of every instruction
+    pub offsets: Vec<usize>,
+}
+
+impl Bytecode {
+    /// Compile `program`, which should be optimized already
+    pub fn compile(program: &Program) -> Bytecode {
+        let instructions = program.ops.iter().enumerate()
+            .map(|(idx, &operation)| {
+                let next = program.ops.get(idx + 1).copied();
+                compile_op(operation, next)
+            })
+            .collect();
+
+        Bytecode { instructions, offsets: program.offsets.clone() }
+    }
+
+    /// Number of instructions
+    pub fn len(&self) -> usize {
+        self.instructions.len()
+    }
+
+    /// Whether there are no instructions
+    pub fn is_empty(&self) -> bool {
+        self.instructions.is_empty()
+    }
+
+    /// Whether `bytes` look like serialized bytecode rather than source
+    pub fn is_bytecode(bytes: &[u8]) -> bool {
+        bytes.starts_with(&MAGIC)
+    }
+
+    /// Serialize the bytecode, see the module documentation for the format
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&VERSION.to_le_bytes());
+        write_unsigned(&mut bytes, self.len() as u64);
+
+        for instruction in &self.instructions {
+            match *instruction {
+                Instruction::Move(delta) => {
+                    bytes.push(0);
+                    write_signed(&mut bytes, delta as i64);
+                },
+                Instruction::Add(delta) => {
+                    bytes.push(1);
+                    write_signed(&mut bytes, delta as i64);
+                },
+                Instruction::Write => bytes.push(2),
+                Instruction::Read => bytes.push(3),
+                Instruction::Clear => bytes.push(4),
+                Instruction::Scan(stride) => {
+                    bytes.push(5);
+                    write_signed(&mut bytes, stride as i64);
+                },
+                Instruction::MulAdd { offset, factor } => {
+                    bytes.push(6);
+                    write_signed(&mut bytes, offset as i64);
+                    write_signed(&mut bytes, factor as i64);
+                },
+                Instruction::AddAt { offset, delta } => {
+                    bytes.push(7);
+                    write_signed(&mut bytes, offset as i64);
+                    write_signed(&mut bytes, delta as i64);
+                },
+                Instruction::SetAt { offset, value } => {
+                    bytes.push(8);
+                    write_signed(&mut bytes, offset as i64);
+                    write_signed(&mut bytes, value as i64);
+                },
+                Instruction::WriteConst(byte) => bytes.extend_from_slice(&[9, byte]),
+                Instruction::JumpIfZero(target) => {
+                    bytes.push(10);
+                    write_unsigned(&mut bytes, target as u64);
+                },
+                Instruction::JumpIfNonZero(target) => {
+                    bytes.push(11);
+                    write_unsigned(&mut bytes, target as u64);
+                },
+                Instruction::MoveJumpIfZero { delta, target } => {
+                    bytes.push(12);
+                    write_signed(&mut bytes, delta as i64);
+                    write_unsigned(&mut bytes, target as u64);
+                },
+                Instruction::MoveJumpIfNonZero { delta, target } => {
+                    bytes.push(13);
+                    write_signed(&mut bytes, delta as i64);
+                    write_unsigned(&mut bytes, target as u64);
+                },
+            }
+        }
+
+        let mut last = 0;
+        for &offset in &self.offsets {
+            write_signed(&mut bytes, offset as i64 - last as i64);
+            last = offset;
+        }
+
+        let checksum = crc32(&bytes);
+        bytes.extend_from_slice(&checksum.to_le_bytes());
+        bytes
+    }
+
+    /// Load bytecode serialized by `to_bytes`, checking the version, the
+    /// checksum and that every jump stays within the program
+    pub fn from_bytes(bytes: &[u8]) -> Result<Bytecode, LoadError> {
+        if !Bytecode::is_bytecode(bytes) {
+            return Err(LoadError::NotBytecode);
+        }
+        if bytes.len() < MAGIC.len() + 2 + 4 {
+            return Err(LoadError::Truncated);
+        }
+        let version = u16::from_le_bytes([bytes[4], bytes[5]]);
+        if version != VERSION {
+            return Err(LoadError::UnsupportedVersion(version));
+        }
+        let (data, checksum) = bytes.split_at(bytes.len() - 4);
+        if crc32(data).to_le_bytes() != checksum {
+            return Err(LoadError::ChecksumMismatch);
+        }
+
+        let mut reader = Reader { data, pos: MAGIC.len() + 2 };
+        let len = reader.unsigned()?;
+        let mut instructions = Vec::new();
+        for idx in 0..len {
+            let instruction = match reader.byte()? {
+                0  => Instruction::Move(reader.signed()?),
+                1  => Instruction::Add(reader.value()?),
+                2  => Instruction::Write,
+                3  => Instruction::Read,
+                4  => Instruction::Clear,
+                5  => Instruction::Scan(reader.signed()?),
+                6  => Instruction::MulAdd { offset: reader.signed()?, factor: reader.value()? },
+                7  => Instruction::AddAt { offset: reader.signed()?, delta: reader.value()? },
+                8  => Instruction::SetAt { offset: reader.signed()?, value: reader.value()? },
+                9  => Instruction::WriteConst(reader.byte()?),
+                10 => Instruction::JumpIfZero(reader.unsigned()?),
+                11 => Instruction::JumpIfNonZero(reader.unsigned()?),
+                12 => Instruction::MoveJumpIfZero {
+                    delta: reader.signed()?,
+                    target: reader.unsigned()?,
+                },
+                13 => Instruction::MoveJumpIfNonZero {
+                    delta: reader.signed()?,
+                    target: reader.unsigned()?,
+                },
+                opcode => return Err(LoadError::UnknownOpcode { idx, opcode }),
+            };
+            instructions.push(instruction);
+        }
+
+        // A target of `len` ends the program
+        for (idx, instruction) in instructions.iter().enumerate() {
+            let target = match *instruction {
+                Instruction::JumpIfZero(target) |
+                Instruction::JumpIfNonZero(target) |
+                Instruction::MoveJumpIfZero { target, .. } |
+                Instruction::MoveJumpIfNonZero { target, .. } => target,
+                _ => continue,
+            };
+            if target > len {
+                return Err(LoadError::InvalidTarget { idx });
+            }
+        }
+
+        let mut offsets = Vec::new();
+        let mut last: usize = 0;
+        for _ in 0..len {
+            last = last.checked_add_signed(reader.signed()?).ok_or(LoadError::Malformed)?;
+            offsets.push(last);
+        }
+        if reader.pos != data.len() {
+            return Err(LoadError::Malformed);
+        }
+
+        Ok(Bytecode { instructions, offsets })
+    }
+
+    /// Run the bytecode on `machine`, a tape of `cells` cells of type `C`,
+    /// until it steps past the last instruction
+    pub(crate) fn run<C: Cell>(&self, machine: &mut Machine<'_>, cells: usize)
+            -> Result<(), VmExit> {
+        let mut idx: usize = 0;
+
+        while let Some(&instruction) = self.instructions.get(idx) {
+            match instruction {
+                Instruction::Move(delta) => {
+                    machine.ptr = machine.cell(cells, self.offsets[idx], delta)?;
+                },
+                Instruction::Add(delta) => {
+                    C::load(machine.tape, machine.ptr).wrapping_add(delta)
+                        .store(machine.tape, machine.ptr);
+                },
+                Instruction::Write => {
+                    machine.write(C::load(machine.tape, machine.ptr).low_byte());
+                },
+                Instruction::Read => machine.read::<C>(self.offsets[idx])?,
+                Instruction::Clear => C::ZERO.store(machine.tape, machine.ptr),
+                Instruction::Scan(stride) => {
+                    while C::load(machine.tape, machine.ptr) != C::ZERO {
+                        machine.ptr = machine.cell(cells, self.offsets[idx], stride)?;
+                    }
+                },
+                Instruction::MulAdd { offset, factor } => {
+                    let target = machine.cell(cells, self.offsets[idx], offset)?;
+                    let product = C::load(machine.tape, machine.ptr).wrapping_mul(factor);
+                    C::load(machine.tape, target).wrapping_add(product.into())
+                        .store(machine.tape, target);
+                },
+                Instruction::AddAt { offset, delta } => {
+                    let target = machine.cell(cells, self.offsets[idx], offset)?;
+                    C::load(machine.tape, target).wrapping_add(delta)
+                        .store(machine.tape, target);
+                },
+                Instruction::SetAt { offset, value } => {
+                    let target = machine.cell(cells, self.offsets[idx], offset)?;
+                    C::ZERO.wrapping_add(value).store(machine.tape, target);
+                },
+                Instruction::WriteConst(byte) => machine.write(byte),
+                Instruction::JumpIfZero(target) => {
+                    if C::load(machine.tape, machine.ptr) == C::ZERO {
+                        idx = target;
+                        continue;
+                    }
+                },
+                Instruction::JumpIfNonZero(target) => {
+                    if C::load(machine.tape, machine.ptr) != C::ZERO {
+                        idx = target;
+                        continue;
+                    }
+                },
+                Instruction::MoveJumpIfZero { delta, target } => {
+                    machine.ptr = machine.cell(cells, self.offsets[idx], delta)?;
+                    if C::load(machine.tape, machine.ptr) == C::ZERO {
+                        idx = target;
+                        continue;
+                    }
+                    // Past the `[`
+                    idx += 1;
+                },
+                Instruction::MoveJumpIfNonZero { delta, target } => {
+                    machine.ptr = machine.cell(cells, self.offsets[idx], delta)?;
+                    if C::load(machine.tape, machine.ptr) != C::ZERO {
+                        idx = target;
+                        continue;
+                    }
+                    // Past the `]`
+                    idx += 1;
+                },
+            }
+
+            idx += 1;
+        }
+
+        Ok(())
+    }
+}
+
+/// Compile `operation`, followed by `next` if it is not the last one
+fn compile_op(operation: BfOperation, next: Option<BfOperation>) -> Instruction {
+    match operation {
+        BfOperation::IncPtr(times) | BfOperation::DecPtr(times) => {
+            let delta = match operation {
+                BfOperation::IncPtr(_) => times as isize,
+                _ => -(times as isize),
+            };
+            match next {
+                Some(BfOperation::LoopStart(end)) => {
+                    Instruction::MoveJumpIfZero { delta, target: end + 1 }
+                },
+                Some(BfOperation::LoopEnd(start)) => {
+                    Instruction::MoveJumpIfNonZero { delta, target: start + 1 }
+                },
+                _ => Instruction::Move(delta),
+            }
+        },
+        BfOperation::IncData(times) => Instruction::Add(times),
+        BfOperation::DecData(times) => Instruction::Add(times.wrapping_neg()),
+        BfOperation::WriteStdout => Instruction::Write,
+        BfOperation::ReadStdin => Instruction::Read,
+        BfOperation::SetZero => Instruction::Clear,
+        BfOperation::ScanRight(stride) => Instruction::Scan(stride as isize),
+        BfOperation::ScanLeft(stride) => Instruction::Scan(-(stride as isize)),
+        BfOperation::MulAdd { offset, factor } => Instruction::MulAdd { offset, factor },
+        BfOperation::Add { offset, delta } => Instruction::AddAt { offset, delta },
+        BfOperation::Set { offset, value } => Instruction::SetAt { offset, value },
+        BfOperation::WriteConst(byte) => Instruction::WriteConst(byte),
+        // Skip the loop, past its `]`
+        BfOperation::LoopStart(end) => Instruction::JumpIfZero(end + 1),
+        // Back to the first operation of the body, the `[` need not test
+        // the cell again
+        BfOperation::LoopEnd(start) => Instruction::JumpIfNonZero(start + 1),
+    }
+}
+
+/// What the bytecode runs against, borrowed from the `Emu` for the run
+pub(crate) struct Machine<'m> {
+    /// The tape
+    pub tape: &'m mut [u8],
+
+    /// Index of the current cell
+    pub ptr: usize,
+
+    /// Source of `,`
+    pub input: &'m mut dyn BfInput,
+
+    /// Sink of `.`
+    pub output: &'m mut OutputBuffer,
+
+    /// What `,` does once the input is exhausted
+    pub eof_policy: EofPolicy,
+}
+
+impl Machine<'_> {
+    /// Write one byte of output
+    fn write(&mut self, byte: u8) {
+        self.output.write_byte(byte)
+            .unwrap_or_else(|err| panic!("Could not write output: {}", err))
+    }
+
+    /// Execute a `,` at source offset `offset`, applying the EOF policy when
+    /// the input is exhausted. Pending output is flushed first, it may be
+    /// the prompt for this input.
+    fn read<C: Cell>(&mut self, offset: usize) -> Result<(), VmExit> {
+        self.output.flush()
+            .unwrap_or_else(|err| panic!("Could not write output: {}", err));
+        let byte = self.input.read_byte()
+            .unwrap_or_else(|err| panic!("Could not read input: {}", err));
+
+        let cell = match (byte, self.eof_policy) {
+            (Some(byte), _) => C::from_byte(byte),
+            (None, EofPolicy::Unchanged) => return Ok(()),
+            (None, EofPolicy::Zero) => C::ZERO,
+            (None, EofPolicy::MinusOne) => C::ZERO.wrapping_sub(1),
+            (None, EofPolicy::Stop) => return Err(VmExit::InputExhausted { offset }),
+        };
+        cell.store(self.tape, self.ptr);
+        Ok(())
+    }
+
+    /// The cell `delta` cells away on a tape of `cells` cells, or the exit
+    /// of the instruction at source offset `offset` moving there
+    fn cell(&self, cells: usize, offset: usize, delta: isize) -> Result<usize, VmExit> {
+        // Loaded bytecode may move by any amount, a move which overflows is
+        // off the tape too
+        let target = (self.ptr as isize).checked_add(delta)
+            .filter(|&target| target >= 0 && (target as usize) < cells);
+        target.map(|target| target as usize).ok_or(VmExit::PtrOob {
+            offset: Some(offset),
+            ptr: (self.ptr as isize).saturating_add(delta),
+        })
+    }
+}
+
+/// Reasons why serialized bytecode could not be loaded
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LoadError {
+    /// The data does not start with `MAGIC`
+    NotBytecode,
+
+    /// The data was written in another version of the format
+    UnsupportedVersion(u16),
+
+    /// The checksum does not match, the data is corrupted
+    ChecksumMismatch,
+
+    /// The data ends in the middle of the program
+    Truncated,
+
+    /// An operand does not fit its instruction or there is data after the
+    /// last source offset
+    Malformed,
+
+    /// Instruction `idx` has an opcode of no instruction
+    UnknownOpcode { idx: usize, opcode: u8 },
+
+    /// Instruction `idx` jumps past the end of the program
+    InvalidTarget { idx: usize },
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::NotBytecode => write!(f, "not bytecode"),
+            LoadError::UnsupportedVersion(version) => {
+                write!(f, "bytecode version {} is not supported, expected {}", version, VERSION)
+            },
+            LoadError::ChecksumMismatch => write!(f, "checksum mismatch, the bytecode is corrupted"),
+            LoadError::Truncated => write!(f, "bytecode is truncated"),
+            LoadError::Malformed => write!(f, "bytecode is malformed"),
+            LoadError::UnknownOpcode { idx, opcode } => {
+                write!(f, "unknown opcode {} at instruction {}", opcode, idx)
+            },
+            LoadError::InvalidTarget { idx } => {
+                write!(f, "instruction {} jumps past the end of the program", idx)
+            },
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+/// Append `value` as an unsigned LEB128 varint
+fn write_unsigned(bytes: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        bytes.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    bytes.push(value as u8);
+}
+
+/// Append `value` zigzag encoded as an unsigned varint
+fn write_signed(bytes: &mut Vec<u8>, value: i64) {
+    write_unsigned(bytes, ((value << 1) ^ (value >> 63)) as u64);
+}
+
+/// CRC-32 as used by zip and PNG
+fn crc32(bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(!0, |crc, &byte| {
+        (0..8).fold(crc ^ byte as u32, |crc, _| {
+            if crc & 1 != 0 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 }
+        })
+    })
+}
+
+/// Decoder for the operands of serialized bytecode
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn byte(&mut self) -> Result<u8, LoadError> {
+        let byte = *self.data.get(self.pos).ok_or(LoadError::Truncated)?;
+        self.pos += 1;
+        Ok(byte)
+    }
+
+    fn varint(&mut self) -> Result<u64, LoadError> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            let bits = u64::from(byte & 0x7f);
+            if bits << shift >> shift != bits {
+                return Err(LoadError::Malformed);
+            }
+            value |= bits << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(LoadError::Malformed)
+    }
+
+    /// An instruction index or source offset
+    fn unsigned(&mut self) -> Result<usize, LoadError> {
+        usize::try_from(self.varint()?).map_err(|_| LoadError::Malformed)
+    }
+
+    /// A zigzag encoded varint
+    fn zigzag(&mut self) -> Result<i64, LoadError> {
+        let value = self.varint()?;
+        Ok((value >> 1) as i64 ^ -((value & 1) as i64))
+    }
+
+    /// A pointer movement or cell offset
+    fn signed(&mut self) -> Result<isize, LoadError> {
+        isize::try_from(self.zigzag()?).map_err(|_| LoadError::Malformed)
+    }
+
+    /// A cell value
+    fn value(&mut self) -> Result<u64, LoadError> {
+        Ok(self.zigzag()? as u64)
+    }
+}
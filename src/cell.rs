@@ -0,0 +1,173 @@
+//! Cell widths. The tape is always stored as bytes, a cell of N bytes lives
+//! at byte `ptr * N` in little-endian order. `,` stores the input byte zero
+//! extended to the cell width and `.` outputs the low byte of the cell.
+
+use std::convert::TryInto;
+use std::fmt;
+
+/// Width of a single tape cell
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CellWidth {
+    /// 8-bit cells, the classic Brainfuck tape
+    #[default]
+    U8,
+
+    /// 16-bit cells
+    U16,
+
+    /// 32-bit cells
+    U32,
+
+    /// 64-bit cells
+    U64,
+}
+
+impl CellWidth {
+    /// Look up a cell width by its size in bits
+    pub fn from_bits(bits: usize) -> Option<CellWidth> {
+        match bits {
+            8  => Some(CellWidth::U8),
+            16 => Some(CellWidth::U16),
+            32 => Some(CellWidth::U32),
+            64 => Some(CellWidth::U64),
+            _  => None,
+        }
+    }
+
+    /// Size of a cell in bytes
+    pub fn bytes(self) -> usize {
+        match self {
+            CellWidth::U8  => 1,
+            CellWidth::U16 => 2,
+            CellWidth::U32 => 4,
+            CellWidth::U64 => 8,
+        }
+    }
+
+    /// Size of a cell in bits
+    pub fn bits(self) -> usize {
+        self.bytes() * 8
+    }
+
+    /// Truncate `value` to the cell width
+    pub fn truncate(self, value: u64) -> u64 {
+        match self {
+            CellWidth::U64 => value,
+            _ => value & ((1u64 << self.bits()) - 1),
+        }
+    }
+}
+
+impl fmt::Display for CellWidth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-bit", self.bits())
+    }
+}
+
+/// A tape cell as seen by the interpreters
+pub trait Cell: Copy + PartialEq + Into<u64> {
+    /// The width this type implements
+    const WIDTH: CellWidth;
+
+    /// The zero cell
+    const ZERO: Self;
+
+    /// Read the cell at index `ptr` of the byte tape
+    fn load(memory: &[u8], ptr: usize) -> Self;
+
+    /// Write the cell at index `ptr` of the byte tape
+    fn store(self, memory: &mut [u8], ptr: usize);
+
+    /// Add `delta`, wrapping at the cell width
+    fn wrapping_add(self, delta: u64) -> Self;
+
+    /// Subtract `delta`, wrapping at the cell width
+    fn wrapping_sub(self, delta: u64) -> Self;
+
+    /// Multiply by `factor`, wrapping at the cell width
+    fn wrapping_mul(self, factor: u64) -> Self;
+
+    /// Zero extend an input byte
+    fn from_byte(byte: u8) -> Self;
+
+    /// The low byte, written by `.`
+    fn low_byte(self) -> u8;
+}
+
+impl Cell for u8 {
+    const WIDTH: CellWidth = CellWidth::U8;
+    const ZERO: Self = 0;
+
+    fn load(memory: &[u8], ptr: usize) -> Self {
+        memory[ptr]
+    }
+
+    fn store(self, memory: &mut [u8], ptr: usize) {
+        memory[ptr] = self;
+    }
+
+    fn wrapping_add(self, delta: u64) -> Self {
+        u8::wrapping_add(self, delta as u8)
+    }
+
+    fn wrapping_sub(self, delta: u64) -> Self {
+        u8::wrapping_sub(self, delta as u8)
+    }
+
+    fn wrapping_mul(self, factor: u64) -> Self {
+        u8::wrapping_mul(self, factor as u8)
+    }
+
+    fn from_byte(byte: u8) -> Self {
+        byte
+    }
+
+    fn low_byte(self) -> u8 {
+        self
+    }
+}
+
+macro_rules! wide_cell {
+    ($ty:ty, $width:expr) => {
+        impl Cell for $ty {
+            const WIDTH: CellWidth = $width;
+            const ZERO: Self = 0;
+
+            fn load(memory: &[u8], ptr: usize) -> Self {
+                const SIZE: usize = std::mem::size_of::<$ty>();
+                let bytes = &memory[ptr * SIZE..(ptr + 1) * SIZE];
+                <$ty>::from_le_bytes(bytes.try_into().unwrap())
+            }
+
+            fn store(self, memory: &mut [u8], ptr: usize) {
+                const SIZE: usize = std::mem::size_of::<$ty>();
+                memory[ptr * SIZE..(ptr + 1) * SIZE]
+                    .copy_from_slice(&self.to_le_bytes());
+            }
+
+            fn wrapping_add(self, delta: u64) -> Self {
+                <$ty>::wrapping_add(self, delta as $ty)
+            }
+
+            fn wrapping_sub(self, delta: u64) -> Self {
+                <$ty>::wrapping_sub(self, delta as $ty)
+            }
+
+            fn wrapping_mul(self, factor: u64) -> Self {
+                <$ty>::wrapping_mul(self, factor as $ty)
+            }
+
+            fn from_byte(byte: u8) -> Self {
+                byte as $ty
+            }
+
+            fn low_byte(self) -> u8 {
+                self as u8
+            }
+        }
+    };
+}
+
+wide_cell!(u16, CellWidth::U16);
+wide_cell!(u32, CellWidth::U32);
+wide_cell!(u64, CellWidth::U64);
@@ -0,0 +1,451 @@
+//! Disassemblers for the instructions the `x64` and `a64` encoders emit, and
+//! annotated listings of JIT code. x86-64 is printed in the Intel syntax the
+//! `x64` listing uses, AArch64 in GNU syntax. Anything outside the encoded
+//! subset shows up as raw `.byte` or `.word` data.
+
+use crate::emu::VmExit;
+use crate::ir::{Program, Span};
+use crate::jit::{generate_jit_mapped, CodeRegion, JitOptions, Target};
+use crate::x64::{Mem, Reg, Size};
+
+use std::convert::TryInto;
+use std::fmt::Write;
+
+/// A decoded instruction
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Instruction {
+    /// Position of the instruction in the code
+    pub at: usize,
+
+    /// Length of the encoding in bytes
+    pub len: usize,
+
+    /// The instruction as assembly text, branch targets as code positions
+    pub text: String,
+}
+
+/// Decode every instruction of `code`, which was generated for `target`
+pub fn disassemble(target: Target, code: &[u8]) -> Vec<Instruction> {
+    let mut instructions = Vec::new();
+
+    let mut at = 0;
+    while at < code.len() {
+        let (len, text) = match target {
+            Target::X86_64 => decode_x86_64(code, at)
+                .unwrap_or_else(|| (1, format!(".byte 0x{:02x}", code[at]))),
+            Target::AArch64 => match code.get(at..at + 4) {
+                Some(word) => {
+                    let word = u32::from_le_bytes(word.try_into().unwrap());
+                    (4, decode_aarch64(word, at)
+                        .unwrap_or_else(|| format!(".word 0x{:08x}", word)))
+                },
+                None => (1, format!(".byte 0x{:02x}", code[at])),
+            },
+        };
+        instructions.push(Instruction { at, len, text });
+        at += len;
+    }
+
+    instructions
+}
+
+/// JIT `program` for `target` and disassemble the code. Every operation is
+/// headed by its index, its position in `source` and the operation itself,
+/// and so is the out of line code it branches to.
+pub fn jit_listing(target: Target, program: &Program, source: &str, options: &JitOptions)
+        -> Result<String, VmExit> {
+    let (code, map) = generate_jit_mapped(target, program, options)?;
+    let mut listing = String::new();
+
+    let mut regions = map.iter().peekable();
+    for instruction in disassemble(target, &code) {
+        while let Some(&(_, region)) = regions.next_if(|&&(at, _)| at <= instruction.at) {
+            let describe = |idx: usize| {
+                format!("{} at {}: {}", idx, Span::new(source, program.offsets[idx]),
+                        program.ops[idx])
+            };
+            let header = match region {
+                CodeRegion::Prologue => "prologue".to_string(),
+                CodeRegion::Op(idx) => describe(idx),
+                CodeRegion::Exit => "exit".to_string(),
+                CodeRegion::OutOfLine(idx) => format!("out of line, {}", describe(idx)),
+            };
+            writeln!(listing, "; {}", header).unwrap();
+        }
+
+        let bytes: Vec<String> = code[instruction.at..instruction.at + instruction.len].iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        writeln!(listing, "{:6x}:  {:<30} {}", instruction.at, bytes.join(" "),
+                 instruction.text).unwrap();
+    }
+
+    Ok(listing)
+}
+
+/// General purpose registers by number
+const REGS: [Reg; 16] = [
+    Reg::Rax, Reg::Rcx, Reg::Rdx, Reg::Rbx, Reg::Rsp, Reg::Rbp, Reg::Rsi, Reg::Rdi,
+    Reg::R8, Reg::R9, Reg::R10, Reg::R11, Reg::R12, Reg::R13, Reg::R14, Reg::R15,
+];
+
+/// Mnemonics of the condition codes, by number
+const X86_CONDS: [&str; 16] = [
+    "jo", "jno", "jb", "jae", "je", "jne", "jbe", "ja",
+    "js", "jns", "jp", "jnp", "jl", "jge", "jle", "jg",
+];
+
+/// The register or memory operand of a ModRM byte
+enum Operand {
+    Reg(Reg),
+    Mem(Mem),
+}
+
+impl Operand {
+    fn text(&self, size: Size) -> String {
+        match *self {
+            Operand::Reg(reg) => reg.name(size).to_string(),
+            Operand::Mem(mem) => mem.text(size),
+        }
+    }
+}
+
+/// Reads the bytes of one x86-64 instruction
+struct Reader<'a> {
+    code: &'a [u8],
+    pos: usize,
+
+    /// The REX prefix, zero without one
+    rex: u8,
+}
+
+impl Reader<'_> {
+    fn byte(&mut self) -> Option<u8> {
+        let byte = *self.code.get(self.pos)?;
+        self.pos += 1;
+        Some(byte)
+    }
+
+    fn bytes<const N: usize>(&mut self) -> Option<[u8; N]> {
+        let bytes = self.code.get(self.pos..self.pos + N)?.try_into().unwrap();
+        self.pos += N;
+        Some(bytes)
+    }
+
+    fn imm8(&mut self) -> Option<i64> {
+        Some(self.byte()? as i8 as i64)
+    }
+
+    fn imm16(&mut self) -> Option<i64> {
+        Some(i16::from_le_bytes(self.bytes()?) as i64)
+    }
+
+    fn imm32(&mut self) -> Option<i64> {
+        Some(i32::from_le_bytes(self.bytes()?) as i64)
+    }
+
+    /// A 16-bit immediate for word operations, else a 32-bit one
+    fn imm(&mut self, size: Size) -> Option<i64> {
+        if size == Size::Word { self.imm16() } else { self.imm32() }
+    }
+
+    /// Decode a ModRM byte, returning the reg field and the r/m operand. The
+    /// encoder only uses `[base]`, `[base + disp8]` and `[base + disp32]`.
+    fn modrm(&mut self) -> Option<(u8, Operand)> {
+        let modrm = self.byte()?;
+        let reg = (modrm >> 3 & 7) | (self.rex & 4) << 1;
+        let rm = (modrm & 7) | (self.rex & 1) << 3;
+
+        let disp = match modrm >> 6 {
+            3 => return Some((reg, Operand::Reg(REGS[rm as usize]))),
+            // rip relative
+            0 if modrm & 7 == 5 => return None,
+            mode => {
+                if modrm & 7 == 4 && self.byte()? != 0x24 {
+                    return None;
+                }
+                match mode {
+                    0 => 0,
+                    1 => self.imm8()?,
+                    _ => self.imm32()?,
+                }
+            },
+        };
+        Some((reg, Operand::Mem(Mem::new(REGS[rm as usize], disp as i32))))
+    }
+
+    /// The target of a branch whose displacement was just read
+    fn target(&self, rel: i64) -> String {
+        format!("0x{:x}", self.pos as i64 + rel)
+    }
+}
+
+/// Name of the arithmetic operation with `/digit` encoding `digit`
+fn alu_name(digit: u8) -> Option<&'static str> {
+    match digit & 7 {
+        0 => Some("add"),
+        5 => Some("sub"),
+        7 => Some("cmp"),
+        _ => None,
+    }
+}
+
+/// Decode the x86-64 instruction at `at`, returning its length and text
+fn decode_x86_64(code: &[u8], at: usize) -> Option<(usize, String)> {
+    let mut reader = Reader { code, pos: at, rex: 0 };
+
+    let mut opcode = reader.byte()?;
+    let word = opcode == 0x66;
+    if word {
+        opcode = reader.byte()?;
+    }
+    if opcode & 0xf0 == 0x40 {
+        reader.rex = opcode;
+        opcode = reader.byte()?;
+    }
+    let size = match (reader.rex & 8 != 0, word) {
+        (true, _)      => Size::Qword,
+        (false, true)  => Size::Word,
+        (false, false) => Size::Dword,
+    };
+
+    let text = match opcode {
+        // op r/m8, r8
+        0x00 | 0x28 | 0x38 => {
+            let (reg, rm) = reader.modrm()?;
+            format!("{} {}, {}", alu_name(opcode >> 3)?, rm.text(Size::Byte),
+                    REGS[reg as usize].name(Size::Byte))
+        },
+        // op r/m, r
+        0x01 | 0x29 | 0x39 | 0x31 | 0x85 => {
+            let name = match opcode {
+                0x31 => "xor",
+                0x85 => "test",
+                _ => alu_name(opcode >> 3)?,
+            };
+            let (reg, rm) = reader.modrm()?;
+            format!("{} {}, {}", name, rm.text(size), REGS[reg as usize].name(size))
+        },
+        // op rax, imm32
+        0x05 | 0x2d | 0x3d => {
+            format!("{} {}, 0x{:x}", alu_name(opcode >> 3)?, Reg::Rax.name(size),
+                    reader.imm32()? as i32)
+        },
+        // op r/m, imm
+        0x80 | 0x81 | 0x83 => {
+            let (digit, rm) = reader.modrm()?;
+            let size = if opcode == 0x80 { Size::Byte } else { size };
+            let imm = match opcode {
+                0x81 => reader.imm(size)?,
+                _ => reader.imm8()?,
+            };
+            match rm {
+                Operand::Reg(reg) => format!("{} {}, 0x{:x}", alu_name(digit)?, reg.name(size),
+                                             imm as i32),
+                Operand::Mem(mem) => format!("{} {}, {}", alu_name(digit)?, mem.text(size), imm),
+            }
+        },
+        // mov r/m, r
+        0x88 | 0x89 => {
+            let size = if opcode == 0x88 { Size::Byte } else { size };
+            let (reg, rm) = reader.modrm()?;
+            format!("mov {}, {}", rm.text(size), REGS[reg as usize].name(size))
+        },
+        // mov r, r/m
+        0x8b => {
+            let (reg, rm) = reader.modrm()?;
+            format!("mov {}, {}", REGS[reg as usize].name(size), rm.text(size))
+        },
+        // mov r/m, imm
+        0xc6 | 0xc7 => {
+            let size = if opcode == 0xc6 { Size::Byte } else { size };
+            let (digit, rm) = reader.modrm()?;
+            if digit & 7 != 0 {
+                return None;
+            }
+            let imm = if size == Size::Byte { reader.imm8()? } else { reader.imm(size)? };
+            match rm {
+                Operand::Reg(reg) => format!("mov {}, 0x{:x}", reg.name(size), imm),
+                Operand::Mem(mem) => format!("mov {}, {}", mem.text(size), imm),
+            }
+        },
+        // movabs r64, imm64
+        0xb8..=0xbf if size == Size::Qword => {
+            let reg = REGS[((opcode & 7) | (reader.rex & 1) << 3) as usize];
+            format!("mov {}, 0x{:x}", reg.name(size), u64::from_le_bytes(reader.bytes()?))
+        },
+        // imul r, r/m, imm
+        0x69 | 0x6b => {
+            let (reg, rm) = reader.modrm()?;
+            let imm = if opcode == 0x6b { reader.imm8()? } else { reader.imm32()? };
+            format!("imul {}, {}, {}", REGS[reg as usize].name(size), rm.text(size), imm)
+        },
+        0xff => {
+            let (digit, rm) = reader.modrm()?;
+            match digit & 7 {
+                0 => format!("inc {}", rm.text(size)),
+                1 => format!("dec {}", rm.text(size)),
+                2 => format!("call {}", rm.text(Size::Qword)),
+                _ => return None,
+            }
+        },
+        0xe8 => {
+            let rel = reader.imm32()?;
+            format!("call {}", reader.target(rel))
+        },
+        0xc3 => "ret".to_string(),
+        0xeb | 0xe9 => {
+            let rel = if opcode == 0xeb { reader.imm8()? } else { reader.imm32()? };
+            format!("jmp {}", reader.target(rel))
+        },
+        0x70..=0x7f => {
+            let rel = reader.imm8()?;
+            format!("{} {}", X86_CONDS[(opcode & 0xf) as usize], reader.target(rel))
+        },
+        0x0f => match reader.byte()? {
+            0x05 => "syscall".to_string(),
+            opcode @ (0xb6 | 0xb7) => {
+                let (reg, rm) = reader.modrm()?;
+                let from = if opcode == 0xb6 { Size::Byte } else { Size::Word };
+                format!("movzx {}, {}", REGS[reg as usize].name(size), rm.text(from))
+            },
+            0xaf => {
+                let (reg, rm) = reader.modrm()?;
+                format!("imul {}, {}", REGS[reg as usize].name(size), rm.text(size))
+            },
+            opcode @ 0x80..=0x8f => {
+                let rel = reader.imm32()?;
+                format!("{} {}", X86_CONDS[(opcode & 0xf) as usize], reader.target(rel))
+            },
+            _ => return None,
+        },
+        _ => return None,
+    };
+
+    Some((reader.pos - at, text))
+}
+
+/// Mnemonic suffixes of the condition codes, by number
+const AARCH64_CONDS: [&str; 16] = [
+    "eq", "ne", "hs", "lo", "mi", "pl", "vs", "vc",
+    "hi", "ls", "ge", "lt", "gt", "le", "al", "nv",
+];
+
+/// Name of register `number` of the `sf` width, where 31 is `sp` if
+/// `sp` is set and the zero register otherwise
+fn aarch64_reg(number: u32, sf: bool, sp: bool) -> String {
+    match (number, sf, sp) {
+        (31, true, true)   => "sp".to_string(),
+        (31, false, true)  => "wsp".to_string(),
+        (31, true, false)  => "xzr".to_string(),
+        (31, false, false) => "wzr".to_string(),
+        (number, true, _)  => format!("x{}", number),
+        (number, false, _) => format!("w{}", number),
+    }
+}
+
+/// Decode the AArch64 instruction `word` at `at`
+fn decode_aarch64(word: u32, at: usize) -> Option<String> {
+    let sf = word >> 31 != 0;
+    let rd = word & 0x1f;
+    let rn = word >> 5 & 0x1f;
+    let rm = word >> 16 & 0x1f;
+    let reg = |number, sp| aarch64_reg(number, sf, sp);
+
+    // Branch targets, from signed word offsets
+    let target = |offset: u32, bits: u32| {
+        let offset = ((offset << (32 - bits)) as i32 >> (32 - bits)) as i64 * 4;
+        format!("0x{:x}", at as i64 + offset)
+    };
+
+    let text = if word & 0x1f00_0000 == 0x1100_0000 {
+        // add/sub (immediate)
+        let sub = word & 1 << 30 != 0;
+        let flags = word & 1 << 29 != 0;
+        let imm = word >> 10 & 0xfff;
+        let shift = if word & 1 << 22 != 0 { ", lsl #12" } else { "" };
+        match (sub, flags) {
+            (true, true) if rd == 31 => format!("cmp {}, #0x{:x}{}", reg(rn, true), imm, shift),
+            _ => {
+                let name = match (sub, flags) {
+                    (false, false) => "add",
+                    (false, true)  => "adds",
+                    (true, false)  => "sub",
+                    (true, true)   => "subs",
+                };
+                format!("{} {}, {}, #0x{:x}{}", name, reg(rd, !flags), reg(rn, true), imm, shift)
+            },
+        }
+    } else if word & 0x1f20_fc00 == 0x0b00_0000 && word >> 22 & 3 == 0 {
+        // add/sub (shifted register) without a shift
+        let sub = word & 1 << 30 != 0;
+        let flags = word & 1 << 29 != 0;
+        match (sub, flags) {
+            (true, true) if rd == 31 => format!("cmp {}, {}", reg(rn, false), reg(rm, false)),
+            _ => {
+                let name = match (sub, flags) {
+                    (false, false) => "add",
+                    (false, true)  => "adds",
+                    (true, false)  => "sub",
+                    (true, true)   => "subs",
+                };
+                format!("{} {}, {}, {}", name, reg(rd, false), reg(rn, false), reg(rm, false))
+            },
+        }
+    } else if word & 0x7fe0_8000 == 0x1b00_0000 {
+        let ra = word >> 10 & 0x1f;
+        format!("madd {}, {}, {}, {}", reg(rd, false), reg(rn, false), reg(rm, false),
+                reg(ra, false))
+    } else if word & 0xffe0_ffe0 == 0xaa00_03e0 {
+        format!("mov {}, {}", reg(rd, false), reg(rm, false))
+    } else if word & 0x1f80_0000 == 0x1280_0000 {
+        // movn, movz and movk
+        let name = match word >> 29 & 3 {
+            0 => "movn",
+            2 => "movz",
+            3 => "movk",
+            _ => return None,
+        };
+        let imm = word >> 5 & 0xffff;
+        match word >> 21 & 3 {
+            0 => format!("{} {}, #0x{:x}", name, reg(rd, false), imm),
+            hw => format!("{} {}, #0x{:x}, lsl #{}", name, reg(rd, false), imm, hw * 16),
+        }
+    } else if word & 0x3f80_0000 == 0x3900_0000 {
+        // ldr/str (unsigned offset)
+        let scale = word >> 30;
+        let load = word & 1 << 22 != 0;
+        let name = match (load, scale) {
+            (true, 0)  => "ldrb",
+            (true, 1)  => "ldrh",
+            (true, _)  => "ldr",
+            (false, 0) => "strb",
+            (false, 1) => "strh",
+            (false, _) => "str",
+        };
+        let rt = aarch64_reg(rd, scale == 3, false);
+        let base = aarch64_reg(rn, true, true);
+        match (word >> 10 & 0xfff) << scale {
+            0 => format!("{} {}, [{}]", name, rt, base),
+            offset => format!("{} {}, [{}, #{}]", name, rt, base, offset),
+        }
+    } else if word & 0xffff_fc1f == 0xd63f_0000 {
+        format!("blr {}", aarch64_reg(rn, true, false))
+    } else if word & 0xffff_fc1f == 0xd65f_0000 {
+        match rn {
+            30 => "ret".to_string(),
+            rn => format!("ret {}", aarch64_reg(rn, true, false)),
+        }
+    } else if word & 0xfc00_0000 == 0x1400_0000 {
+        format!("b {}", target(word & 0x03ff_ffff, 26))
+    } else if word & 0xff00_0010 == 0x5400_0000 {
+        format!("b.{} {}", AARCH64_CONDS[(word & 0xf) as usize], target(word >> 5 & 0x7_ffff, 19))
+    } else if word & 0x7e00_0000 == 0x3400_0000 {
+        let name = if word & 1 << 24 != 0 { "cbnz" } else { "cbz" };
+        format!("{} {}, {}", name, reg(rd, false), target(word >> 5 & 0x7_ffff, 19))
+    } else {
+        return None;
+    };
+
+    Some(text)
+}
@@ -0,0 +1,881 @@
+use crate::bytecode::{Bytecode, Machine};
+use crate::cell::{Cell, CellWidth};
+use crate::io::{BfInput, BfOutput, OutputBuffer};
+use crate::ir::{BfOperation, ParseError, Program};
+use crate::jit::{generate_jit_mapped, CodeRegion, HostCalls, JitOptions, Target};
+use crate::jit::{HOST_ERROR, INPUT_EXHAUSTED};
+use crate::jitcache::JitCache;
+use crate::optimize::Optimizer;
+use crate::tape::{Tape, GUARD_FAULT};
+use crate::DEBUG_ENABLED;
+
+use std::{io, sync::Arc, time::Instant};
+use std::arch::asm;
+
+/// Reasons why the VM exited
+#[derive(Debug)]
+pub enum VmExit {
+    /// The VM exited due to a PTR OOB
+    PtrOob {
+        /// Source offset of the operation which moved the pointer, unknown
+        /// when a guard page stopped JIT code outside of any operation
+        offset: Option<usize>,
+
+        /// The cell index the pointer was moved to
+        ptr: isize,
+    },
+
+    /// A `,` hit the end of the input with `EofPolicy::Stop`
+    InputExhausted {
+        /// Source offset of the `,`
+        offset: usize,
+    },
+
+    /// The VM exited cleanly as requested by the code.
+    Exit(f64),    
+}
+
+impl VmExit {
+    /// Process exit code used by the command line tool for this exit reason
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            VmExit::Exit(_) => 0,
+            VmExit::PtrOob { .. } => 2,
+            VmExit::InputExhausted { .. } => 4,
+        }
+    }
+}
+
+/// What `,` does once the input is exhausted
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EofPolicy {
+    /// Leave the cell unchanged
+    #[default]
+    Unchanged,
+
+    /// Store zero
+    Zero,
+
+    /// Store -1, all bits set at the cell width (255 for 8-bit cells)
+    MinusOne,
+
+    /// Stop the program with `VmExit::InputExhausted`
+    Stop,
+}
+
+impl EofPolicy {
+    /// Look up an EOF policy by its command line name
+    pub fn from_name(name: &str) -> Option<EofPolicy> {
+        match name {
+            "unchanged" => Some(EofPolicy::Unchanged),
+            "zero"      => Some(EofPolicy::Zero),
+            "minus-one" => Some(EofPolicy::MinusOne),
+            "stop"      => Some(EofPolicy::Stop),
+            _           => None,
+        }
+    }
+}
+
+/// Execution engines available to run a program
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Engine {
+    /// Naive interpreter which scans for matching brackets at runtime
+    Vm,
+
+    /// Interpreter with precomputed loop targets
+    Vm2,
+
+    /// Interpreter over optimized operations
+    Vm3,
+
+    /// Interpreter over optimized operations compiled to bytecode, see the
+    /// `bytecode` module
+    Bytecode,
+
+    /// One-to-one JIT translation of every command
+    Jit,
+
+    /// JIT over optimized operations
+    JitOpt,
+}
+
+impl Engine {
+    /// Look up an engine by its command line name
+    pub fn from_name(name: &str) -> Option<Engine> {
+        match name {
+            "vm"       => Some(Engine::Vm),
+            "vm2"      => Some(Engine::Vm2),
+            "vm3"      => Some(Engine::Vm3),
+            "bytecode" => Some(Engine::Bytecode),
+            "jit"      => Some(Engine::Jit),
+            "jitopt"   => Some(Engine::JitOpt),
+            _          => None,
+        }
+    }
+
+    /// Whether the engine requires a JIT cache
+    pub fn is_jit(&self) -> bool {
+        matches!(self, Engine::Jit | Engine::JitOpt)
+    }
+}
+
+/// A Brainfuck machine: the tape, the data pointer and an optional JIT
+pub struct Emu {
+    /// The tape
+    pub memory: Tape,
+
+    /// Index of the current cell in `memory`, in cells of the configured
+    /// width
+    pub ptr: usize,
+
+    jit_cache: Option<Arc<JitCache>>,
+
+    jit_options: JitOptions,
+
+    optimizer: Optimizer,
+
+    /// Whether no program ran on the tape since it was allocated
+    fresh_tape: bool,
+
+    input: Box<dyn BfInput + Send>,
+
+    output: OutputBuffer,
+}
+
+// An `Emu` can be built on one thread and run on another
+const _: fn() = || {
+    fn assert_send<T: Send>() {}
+    assert_send::<Emu>();
+};
+
+impl Emu {
+    pub fn new(size: usize) -> Self {
+        Emu {
+            memory: Tape::new(size),
+            ptr: 0,
+            jit_cache: None,
+            jit_options: JitOptions::default(),
+            optimizer: Optimizer::default(),
+            fresh_tape: true,
+            input: Box::new(io::stdin()),
+            output: OutputBuffer::new(Box::new(io::stdout())),
+        }
+    }
+
+    // Enable the JIT
+    pub fn enable_jit(mut self, jit_cache: Arc<JitCache>) -> Self {
+        self.jit_cache = Some(jit_cache);
+        self
+    }
+
+    // Guard pointer movement in JIT code so it exits with `PtrOob` instead
+    // of touching memory outside the tape. The interpreters always check.
+    pub fn enable_bounds_checks(mut self) -> Self {
+        self.jit_options.bounds_checks = true;
+        self
+    }
+
+    // Move the tape between guard pages, so unchecked JIT code faults and
+    // exits with `PtrOob` instead of touching memory outside the tape. The
+    // tape grows to a whole number of pages. Where guard pages are not
+    // supported the tape stays on the heap and the JIT checks every pointer
+    // movement instead, see `Tape::guarded`.
+    pub fn enable_guard_pages(mut self) -> Self {
+        let mut memory = Tape::guarded(self.memory.len());
+        memory[..self.memory.len()].copy_from_slice(&self.memory);
+        if !memory.is_guarded() {
+            self.jit_options.bounds_checks = true;
+        }
+        self.memory = memory;
+        self
+    }
+
+    // Use cells of `cell_width` instead of bytes. The tape keeps its number
+    // of cells and is cleared.
+    pub fn with_cell_width(mut self, cell_width: CellWidth) -> Self {
+        let size = self.cells() * cell_width.bytes();
+        self.memory = if self.memory.is_guarded() {
+            Tape::guarded(size)
+        } else {
+            Tape::new(size)
+        };
+        self.jit_options.cell_width = cell_width;
+        self.fresh_tape = true;
+        self
+    }
+
+    // Read `,` input from `input` instead of stdin
+    pub fn with_input(mut self, input: impl BfInput + Send + 'static) -> Self {
+        self.input = Box::new(input);
+        self
+    }
+
+    // Write `.` output to `output` instead of stdout
+    pub fn with_output(mut self, output: impl BfOutput + Send + 'static) -> Self {
+        self.output.sink = Box::new(output);
+        self
+    }
+
+    // Flush the output after every `.` instead of on newline, for
+    // interactive programs
+    pub fn enable_unbuffered_output(mut self) -> Self {
+        self.output.unbuffered = true;
+        self
+    }
+
+    // Select what `,` does once the input is exhausted
+    pub fn with_eof_policy(mut self, eof_policy: EofPolicy) -> Self {
+        self.jit_options.eof_policy = eof_policy;
+        self
+    }
+
+    // Optimize programs for `Engine::Vm3`, `Engine::Bytecode` and
+    // `Engine::JitOpt` with `optimizer` instead of at `-O2`
+    pub fn with_optimizer(mut self, optimizer: Optimizer) -> Self {
+        self.optimizer = optimizer;
+        self
+    }
+
+    /// The configured `Optimizer`, assuming an all-zero tape only while the
+    /// tape is fresh and nothing was stored in it
+    fn tape_optimizer(&self) -> Optimizer {
+        let zero_tape = self.fresh_tape && self.memory.iter().all(|&byte| byte == 0);
+        self.optimizer.with_zero_tape(zero_tape)
+    }
+
+    /// Width of the tape cells
+    pub fn cell_width(&self) -> CellWidth {
+        self.jit_options.cell_width
+    }
+
+    /// Number of cells on the tape
+    pub fn cells(&self) -> usize {
+        self.memory.len() / self.cell_width().bytes()
+    }
+
+    /// Read one byte of input, `None` at the end of the input. Pending
+    /// output is flushed first, it may be the prompt for this input.
+    fn receive_input(&mut self) -> Option<u8> {
+        self.flush_output();
+        self.input.read_byte()
+            .unwrap_or_else(|err| panic!("Could not read input: {}", err))
+    }
+
+    /// Write one byte of output
+    fn send_output(&mut self, byte: u8) {
+        self.output.write_byte(byte)
+            .unwrap_or_else(|err| panic!("Could not write output: {}", err))
+    }
+
+    /// Write out the buffered output
+    fn flush_output(&mut self) {
+        self.output.flush()
+            .unwrap_or_else(|err| panic!("Could not write output: {}", err))
+    }
+
+    /// Execute the `,` at operation `idx`, applying the EOF policy when the
+    /// input is exhausted
+    fn read_cell<C: Cell>(&mut self, program: &Program, idx: usize)
+            -> Result<(), VmExit> {
+        let cell = match (self.receive_input(), self.jit_options.eof_policy) {
+            (Some(byte), _) => C::from_byte(byte),
+            (None, EofPolicy::Unchanged) => return Ok(()),
+            (None, EofPolicy::Zero) => C::ZERO,
+            (None, EofPolicy::MinusOne) => C::ZERO.wrapping_sub(1),
+            (None, EofPolicy::Stop) => {
+                return Err(VmExit::InputExhausted { offset: program.offsets[idx] });
+            }
+        };
+        cell.store(&mut self.memory, self.ptr);
+        Ok(())
+    }
+
+    /// Execute the `ScanRight` or `ScanLeft` at operation `idx`, moving the
+    /// pointer by `stride` cells until the current cell is zero
+    fn scan<C: Cell>(&mut self, program: &Program, idx: usize, stride: isize)
+            -> Result<(), VmExit> {
+        while C::load(&self.memory, self.ptr) != C::ZERO {
+            let next = self.ptr as isize + stride;
+            if next < 0 || next as usize >= self.cells() {
+                return Err(self.ptr_oob(program, idx, stride));
+            }
+            self.ptr = next as usize;
+        }
+        Ok(())
+    }
+
+    /// The cell `offset` cells away, which operation `idx` changes
+    fn target(&self, program: &Program, idx: usize, offset: isize)
+            -> Result<usize, VmExit> {
+        let target = self.ptr as isize + offset;
+        if target < 0 || target as usize >= self.cells() {
+            return Err(self.ptr_oob(program, idx, offset));
+        }
+        Ok(target as usize)
+    }
+
+    /// Execute the `MulAdd` at operation `idx`, adding the current cell times
+    /// `factor` to the cell `offset` cells away
+    fn mul_add<C: Cell>(&mut self, program: &Program, idx: usize, offset: isize,
+                        factor: u64) -> Result<(), VmExit> {
+        let target = self.target(program, idx, offset)?;
+        let product = C::load(&self.memory, self.ptr).wrapping_mul(factor);
+        C::load(&self.memory, target).wrapping_add(product.into())
+            .store(&mut self.memory, target);
+        Ok(())
+    }
+
+    /// Execute the `Add` at operation `idx`, adding `delta` to the cell
+    /// `offset` cells away
+    fn add<C: Cell>(&mut self, program: &Program, idx: usize, offset: isize,
+                    delta: u64) -> Result<(), VmExit> {
+        let target = self.target(program, idx, offset)?;
+        C::load(&self.memory, target).wrapping_add(delta)
+            .store(&mut self.memory, target);
+        Ok(())
+    }
+
+    /// Execute the `Set` at operation `idx`, storing `value` in the cell
+    /// `offset` cells away
+    fn set<C: Cell>(&mut self, program: &Program, idx: usize, offset: isize,
+                    value: u64) -> Result<(), VmExit> {
+        let target = self.target(program, idx, offset)?;
+        C::ZERO.wrapping_add(value).store(&mut self.memory, target);
+        Ok(())
+    }
+
+    /// Run the VM using either the emulator or the JIT
+    pub fn run(&mut self, instructions: &str)
+            -> Result<Option<VmExit>, ParseError> {
+        if self.jit_cache.is_some() {
+            self.run_engine(Engine::JitOpt, instructions)
+        } else {
+            self.run_engine(Engine::Vm3, instructions)
+        }
+    }
+
+    /// Parse the source and run it with an explicitly selected engine
+    pub fn run_engine(&mut self, engine: Engine, instructions: &str)
+            -> Result<Option<VmExit>, ParseError> {
+        let program = Program::parse(instructions)?;
+        Ok(self.run_program(engine, &program))
+    }
+
+    /// Run an already parsed program with the selected engine. The engines
+    /// running the optimized program only assume the tape is all zeros
+    /// while nothing ran on it yet, see the `optimize` module.
+    pub fn run_program(&mut self, engine: Engine, program: &Program) -> Option<VmExit> {
+        match engine {
+            Engine::Vm       => self.run_vm(program),
+            Engine::Vm2      => self.run_vm2(program),
+            Engine::Vm3      => self.run_vm3(program),
+            Engine::Bytecode => self.run_bytecode(program),
+            Engine::Jit      => {
+                let start = Instant::now();
+                let exit = self.run_machine_code(program, start);
+                self.flush_output();
+                exit
+            },
+            Engine::JitOpt   => self.run_jit(program),
+        }
+    }
+
+    /// Compile the program, optimized by the configured `Optimizer`, with
+    /// `generate_jit` and run it
+    pub fn run_jit(&mut self, program: &Program) -> Option<VmExit> {
+        let start = Instant::now();
+        let optimized = self.tape_optimizer().run(program);
+        let exit = self.run_machine_code(&optimized, start);
+        self.flush_output();
+        exit
+    }
+
+    fn run_machine_code(&mut self, program: &Program, start: Instant) -> Option<VmExit> {
+        self.fresh_tape = false;
+        let jit_cache = self.jit_cache.as_ref().expect("JIT is not enabled");
+
+        match generate_jit_mapped(Target::native(), program, &self.jit_options) {
+            Ok((machine_code, code_map)) => {
+                let jitted_addr = jit_cache.add_code(&machine_code);
+
+                // The JIT code keeps the data pointer in a register and
+                // calls back into the host for I/O, so treat it as a C call
+                // for clobbering purposes
+                let cells = self.cells();
+                let tape = self.memory.as_mut_ptr() as usize;
+                let tape_end = tape + cells * self.cell_width().bytes();
+                let cell_size = self.cell_width().bytes() as isize;
+                let final_ptr: usize;
+                let status: usize;
+                let oob_ptr: usize;
+                let fault_pc: usize;
+                let mut host_calls = HostCalls::new(&mut *self.input, &mut self.output);
+                let _scope = self.memory.enter_jit();
+                #[cfg(target_arch = "x86_64")]
+                unsafe {
+                    asm!(r#"
+                       call {entry}                       
+                    "#,
+                    entry = in(reg) jitted_addr,
+                    inout("r13") tape + self.ptr * cell_size as usize => final_ptr,
+                    in("r14") tape,
+                    in("r15") tape_end,
+                    in("r12") &mut host_calls as *mut HostCalls,
+                    lateout("rax") status,
+                    lateout("rdx") oob_ptr,
+                    lateout("rcx") fault_pc,
+                    clobber_abi("C"));
+                }
+                #[cfg(target_arch = "aarch64")]
+                unsafe {
+                    asm!(r#"
+                       blr {entry}
+                    "#,
+                    entry = in(reg) jitted_addr,
+                    inout("x20") tape + self.ptr * cell_size as usize => final_ptr,
+                    in("x21") tape,
+                    in("x22") tape_end,
+                    in("x23") &mut host_calls as *mut HostCalls,
+                    lateout("x0") status,
+                    lateout("x1") oob_ptr,
+                    // Only set by the guard fault handler, which is x86-64 only
+                    lateout("x2") fault_pc,
+                    out("x24") _,
+                    clobber_abi("C"));
+                }
+                #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+                panic!("no JIT backend for this architecture");
+                self.ptr = (final_ptr.wrapping_sub(tape) as isize / cell_size) as usize;
+
+                // A guard fault may leave the data pointer in the guard, pull the
+                // pointer back onto the tape
+                if status == GUARD_FAULT {
+                    if self.ptr >= cells {
+                        self.ptr = if (self.ptr as isize) < 0 { 0 } else { cells - 1 };
+                    }
+                    // The faulting instruction belongs to the last region
+                    // starting at or before it
+                    let fault_pc = fault_pc.wrapping_sub(jitted_addr);
+                    let region = code_map.iter().rev()
+                        .find(|&&(region_start, _)| region_start <= fault_pc);
+                    let offset = match region {
+                        Some(&(_, CodeRegion::Op(idx) | CodeRegion::OutOfLine(idx))) => {
+                            Some(program.offsets[idx])
+                        },
+                        _ => None,
+                    };
+                    return Some(VmExit::PtrOob {
+                        offset,
+                        ptr: oob_ptr.wrapping_sub(tape) as isize / cell_size,
+                    });
+                }
+
+                if status == HOST_ERROR {
+                    let err = host_calls.error.take().expect("host call failed without an error");
+                    panic!("Could not read input or write output: {}", err);
+                }
+
+                // The input ran out with `EofPolicy::Stop`
+                if status & INPUT_EXHAUSTED != 0 {
+                    return Some(VmExit::InputExhausted {
+                        offset: (status & !INPUT_EXHAUSTED) - 1,
+                    });
+                }
+
+                // Any other non-zero status is the source offset of the
+                // operation which failed its bounds check, plus one
+                if status != 0 {
+                    return Some(VmExit::PtrOob {
+                        offset: Some(status - 1),
+                        ptr: oob_ptr.wrapping_sub(tape) as isize / cell_size,
+                    });
+                }
+
+            },
+            Err(_) => {
+                panic!("error generating machine code!")
+            }
+        }
+
+        let elapsed = start.elapsed().as_secs_f64();
+        Some(VmExit::Exit(elapsed))
+    }
+
+    /// Build the exit for operation `idx` moving the pointer by `delta` cells
+    /// off the tape
+    fn ptr_oob(&self, program: &Program, idx: usize, delta: isize) -> VmExit {
+        VmExit::PtrOob {
+            offset: Some(program.offsets[idx]),
+            ptr: self.ptr as isize + delta,
+        }
+    }
+
+    /// Naive interpreter which scans for the matching `]` at runtime
+    pub fn run_vm(&mut self, program: &Program) -> Option<VmExit> {
+        self.fresh_tape = false;
+        let exit = match self.cell_width() {
+            CellWidth::U8  => self.run_vm_cells::<u8>(program),
+            CellWidth::U16 => self.run_vm_cells::<u16>(program),
+            CellWidth::U32 => self.run_vm_cells::<u32>(program),
+            CellWidth::U64 => self.run_vm_cells::<u64>(program),
+        };
+        self.flush_output();
+        exit
+    }
+
+    fn run_vm_cells<C: Cell>(&mut self, program: &Program) -> Option<VmExit> {
+        // flag to indicate that we need to scan for the matching `]`
+        let mut scan_loop_end = false;
+        // loop nesting consideration
+        let mut nested_depth = 0u32;
+
+        // used to keep track of [] loops
+        let mut start_loop_positions = Vec::<usize>::new();        
+
+        let mut idx: usize = 0;
+
+        // start a timer
+        let start = Instant::now();
+            
+        while idx < program.ops.len() {
+            let operation = program.ops[idx];
+            // Decode operator
+            match operation {
+                BfOperation::IncPtr(times) => {
+                    // Increment the data pointer to the next cell
+                    if !scan_loop_end {
+                        if (self.ptr + times) >= self.cells() {
+                            return Some(self.ptr_oob(program, idx, times as isize));
+                        }
+                        self.ptr += times;
+                        if DEBUG_ENABLED {
+                            println!("Executed Op: > at pos {} - ptr: {}", program.offsets[idx], self.ptr);                             
+                        }                    
+                    }
+                },
+                BfOperation::DecPtr(times) => {
+                    // Decrement the data pointer to point to the previous cell       
+                    if !scan_loop_end {
+                        if self.ptr < times {
+                            return Some(self.ptr_oob(program, idx, -(times as isize)));
+                        } 
+                        self.ptr -= times;
+                        if DEBUG_ENABLED {
+                            println!("Executed Op: < at pos {} - ptr: {}", program.offsets[idx], self.ptr);                     
+                        }
+                    }
+
+                },
+                BfOperation::IncData(times) => {
+                    // Increment the cell at data pointer
+                    if !scan_loop_end {
+                        C::load(&self.memory, self.ptr).wrapping_add(times)
+                            .store(&mut self.memory, self.ptr);
+                        if DEBUG_ENABLED {
+                            println!("Executed Op: + at pos {} - ptr: {}", program.offsets[idx], self.ptr); 
+                        }   
+                    }             
+                },
+                BfOperation::DecData(times) => {
+                    // Decrement the cell at data pointer.
+                    if !scan_loop_end {
+                        C::load(&self.memory, self.ptr).wrapping_sub(times)
+                            .store(&mut self.memory, self.ptr);
+                        if DEBUG_ENABLED {
+                            println!("Executed Op: - at pos {} - ptr: {}", program.offsets[idx], self.ptr);   
+                        }
+                    }
+                },
+                BfOperation::WriteStdout => {
+                    // Output the low byte of the cell at the data pointer.
+                    if !scan_loop_end {
+                        self.send_output(C::load(&self.memory, self.ptr).low_byte());
+                        if DEBUG_ENABLED {
+                            println!("Executed Op: . at pos {} - ptr: {}", program.offsets[idx], self.ptr); 
+                        }
+                    }                          
+                },
+                BfOperation::ReadStdin => {
+                    // Input one byte and store its value at the data pointer.
+                    if !scan_loop_end {
+                        if let Err(exit) = self.read_cell::<C>(program, idx) {
+                            return Some(exit);
+                        }
+
+                        if DEBUG_ENABLED {
+                            println!("Executed Op: , at pos {} - ptr: {}", program.offsets[idx], self.ptr);  
+                        }
+                    }
+                },
+                BfOperation::SetZero => {
+                    if !scan_loop_end {
+                        C::ZERO.store(&mut self.memory, self.ptr);
+                    }
+                },
+                BfOperation::ScanRight(stride) => {
+                    if !scan_loop_end {
+                        if let Err(exit) = self.scan::<C>(program, idx, stride as isize) {
+                            return Some(exit);
+                        }
+                    }
+                },
+                BfOperation::ScanLeft(stride) => {
+                    if !scan_loop_end {
+                        if let Err(exit) = self.scan::<C>(program, idx, -(stride as isize)) {
+                            return Some(exit);
+                        }
+                    }
+                },
+                BfOperation::MulAdd { offset, factor } => {
+                    if !scan_loop_end {
+                        if let Err(exit) = self.mul_add::<C>(program, idx, offset, factor) {
+                            return Some(exit);
+                        }
+                    }
+                },
+                BfOperation::Add { offset, delta } => {
+                    if !scan_loop_end {
+                        if let Err(exit) = self.add::<C>(program, idx, offset, delta) {
+                            return Some(exit);
+                        }
+                    }
+                },
+                BfOperation::Set { offset, value } => {
+                    if !scan_loop_end {
+                        if let Err(exit) = self.set::<C>(program, idx, offset, value) {
+                            return Some(exit);
+                        }
+                    }
+                },
+                BfOperation::WriteConst(byte) => {
+                    if !scan_loop_end {
+                        self.send_output(byte);
+                    }
+                },
+                BfOperation::LoopStart(_) => {
+                    // If the cell at the data pointer is zero,
+                    // jump to the instruction following the matching ] bracket.
+                    // Otherwise, continue execution.            
+                    if C::load(&self.memory, self.ptr) == C::ZERO {                        
+                        scan_loop_end = true;
+                    }
+
+                    if !scan_loop_end {
+                        start_loop_positions.push(idx);
+                    } else {
+                        nested_depth += 1;
+                    }
+                },
+                BfOperation::LoopEnd(_) => {
+                    // Unconditionally jump back to the matching [ bracket.
+                    if scan_loop_end {
+                        nested_depth -= 1;
+                        if nested_depth == 0 {
+                            scan_loop_end = false;
+                        }
+                    } else {                       
+                        idx = start_loop_positions.pop().unwrap();                        
+                        continue;
+                    }
+                },
+            }
+
+            idx += 1;
+        }        
+
+        let elapsed = start.elapsed().as_secs_f64();
+        Some(VmExit::Exit(elapsed))
+    }
+
+
+    /// Same as before but it uses the precomputed loop targets `[` `]`
+    pub fn run_vm2(&mut self, program: &Program) -> Option<VmExit> {
+        self.fresh_tape = false;
+        let exit = match self.cell_width() {
+            CellWidth::U8  => self.run_vm2_cells::<u8>(program),
+            CellWidth::U16 => self.run_vm2_cells::<u16>(program),
+            CellWidth::U32 => self.run_vm2_cells::<u32>(program),
+            CellWidth::U64 => self.run_vm2_cells::<u64>(program),
+        };
+        self.flush_output();
+        exit
+    }
+
+    fn run_vm2_cells<C: Cell>(&mut self, program: &Program) -> Option<VmExit> {
+        let mut idx: usize = 0;
+
+        // start a timer
+        let start = Instant::now();
+            
+        while idx < program.ops.len() {
+            let operation = program.ops[idx];
+            // Decode operator
+            match operation {
+                BfOperation::IncPtr(times) => {
+                    // Increment the data pointer to the next cell
+                    if (self.ptr + times) >= self.cells() {
+                        return Some(self.ptr_oob(program, idx, times as isize));
+                    }
+                    self.ptr += times;
+                    if DEBUG_ENABLED {
+                        println!("Executed Op: > at pos {} - ptr: {}", program.offsets[idx], self.ptr);                             
+                    }                    
+                },
+                BfOperation::DecPtr(times) => {
+                    // Decrement the data pointer to point to the previous cell      
+                    if self.ptr < times {
+                        return Some(self.ptr_oob(program, idx, -(times as isize)));
+                    } 
+                    self.ptr -= times;
+                    if DEBUG_ENABLED {
+                        println!("Executed Op: < at pos {} - ptr: {}", program.offsets[idx], self.ptr);                     
+                    }                  
+                },
+                BfOperation::IncData(times) => {
+                    // Increment the cell at data pointer                    
+                    C::load(&self.memory, self.ptr).wrapping_add(times)
+                        .store(&mut self.memory, self.ptr);
+                    if DEBUG_ENABLED {
+                        println!("Executed Op: + at pos {} - ptr: {}", program.offsets[idx], self.ptr); 
+                    }               
+                },
+                BfOperation::DecData(times) => {
+                    // Decrement the cell at data pointer.
+                    C::load(&self.memory, self.ptr).wrapping_sub(times)
+                        .store(&mut self.memory, self.ptr);
+                    if DEBUG_ENABLED {
+                        println!("Executed Op: - at pos {} - ptr: {}", program.offsets[idx], self.ptr);   
+                    }
+                },
+                BfOperation::WriteStdout => {
+                    // Output the low byte of the cell at the data pointer.
+                    self.send_output(C::load(&self.memory, self.ptr).low_byte());
+                    if DEBUG_ENABLED {
+                        println!("Executed Op: . at pos {} - ptr: {}", program.offsets[idx], self.ptr); 
+                    }                        
+                },
+                BfOperation::ReadStdin => {
+                    // Input one byte and store its value at the data pointer.
+                    if let Err(exit) = self.read_cell::<C>(program, idx) {
+                        return Some(exit);
+                    }
+
+                    if DEBUG_ENABLED {
+                        println!("Executed Op: , at pos {} - ptr: {}", program.offsets[idx], self.ptr);  
+                    }                
+                },
+                BfOperation::SetZero => {
+                    // `[-]`, clear the current cell
+                    C::ZERO.store(&mut self.memory, self.ptr);
+                },
+                BfOperation::ScanRight(stride) => {
+                    // `[>]`, find the next zero cell to the right
+                    if let Err(exit) = self.scan::<C>(program, idx, stride as isize) {
+                        return Some(exit);
+                    }
+                },
+                BfOperation::ScanLeft(stride) => {
+                    if let Err(exit) = self.scan::<C>(program, idx, -(stride as isize)) {
+                        return Some(exit);
+                    }
+                },
+                BfOperation::MulAdd { offset, factor } => {
+                    // One cell of a multiply loop such as `[->++<]`
+                    if let Err(exit) = self.mul_add::<C>(program, idx, offset, factor) {
+                        return Some(exit);
+                    }
+                },
+                BfOperation::Add { offset, delta } => {
+                    // A cell changed by a run such as `>+>+<<-`, relative to
+                    // the pointer at its start
+                    if let Err(exit) = self.add::<C>(program, idx, offset, delta) {
+                        return Some(exit);
+                    }
+                },
+                BfOperation::Set { offset, value } => {
+                    // A cell whose value is known, such as `[-]+++`
+                    if let Err(exit) = self.set::<C>(program, idx, offset, value) {
+                        return Some(exit);
+                    }
+                },
+                BfOperation::WriteConst(byte) => {
+                    // A `.` whose output is known
+                    self.send_output(byte);
+                },
+                BfOperation::LoopStart(end) => {
+                    // If the cell at the data pointer is zero,
+                    // jump to the instruction following the matching ] bracket.
+                    // Otherwise, continue execution.          
+                    if C::load(&self.memory, self.ptr) == C::ZERO {                        
+                        idx = end;
+                        continue;
+                    }
+                },
+                BfOperation::LoopEnd(start) => {
+                    // Jump back to the matching [ bracket if the cell is
+                    // non-zero.
+                    if C::load(&self.memory, self.ptr) != C::ZERO {                        
+                        idx = start;
+                        continue;
+                    }
+                },
+            }
+
+            idx += 1;
+        }        
+
+        let elapsed = start.elapsed().as_secs_f64();
+        Some(VmExit::Exit(elapsed))
+    }
+
+
+    /// Same as run_vm2 but over the program optimized by the configured
+    /// `Optimizer`, which consolidates sequences of operations and replaces
+    /// clear, scan and multiply loops at the default `-O2`
+    pub fn run_vm3(&mut self, program: &Program) -> Option<VmExit> {
+        self.run_vm2(&self.tape_optimizer().run(program))
+    }
+
+    /// Compile the program, optimized by the configured `Optimizer`, to
+    /// bytecode and run it
+    pub fn run_bytecode(&mut self, program: &Program) -> Option<VmExit> {
+        self.run_compiled(&Bytecode::compile(&self.tape_optimizer().run(program)))
+    }
+
+    /// Run bytecode compiled earlier, e.g. loaded with `Bytecode::from_bytes`.
+    /// It was optimized when it was compiled, the tape must be all zeros.
+    pub fn run_compiled(&mut self, bytecode: &Bytecode) -> Option<VmExit> {
+        self.fresh_tape = false;
+        let exit = match self.cell_width() {
+            CellWidth::U8  => self.run_bytecode_cells::<u8>(bytecode),
+            CellWidth::U16 => self.run_bytecode_cells::<u16>(bytecode),
+            CellWidth::U32 => self.run_bytecode_cells::<u32>(bytecode),
+            CellWidth::U64 => self.run_bytecode_cells::<u64>(bytecode),
+        };
+        self.flush_output();
+        exit
+    }
+
+    fn run_bytecode_cells<C: Cell>(&mut self, bytecode: &Bytecode) -> Option<VmExit> {
+        let cells = self.cells();
+        let mut machine = Machine {
+            tape: &mut self.memory,
+            ptr: self.ptr,
+            input: &mut *self.input,
+            output: &mut self.output,
+            eof_policy: self.jit_options.eof_policy,
+        };
+
+        // start a timer
+        let start = Instant::now();
+
+        let result = bytecode.run::<C>(&mut machine, cells);
+        self.ptr = machine.ptr;
+        if let Err(exit) = result {
+            return Some(exit);
+        }
+
+        let elapsed = start.elapsed().as_secs_f64();
+        Some(VmExit::Exit(elapsed))
+    }
+}
@@ -0,0 +1,132 @@
+//! Program I/O. `,` reads from a `BfInput` and `.` writes to a `BfOutput`,
+//! in the interpreters directly and in JIT code through host calls. Any
+//! `Read` is an input and any `Write` is an output, so stdin/stdout, files,
+//! sockets and in-memory buffers all work with every engine.
+//!
+//! Output is buffered in front of the `BfOutput` by every engine alike, see
+//! `OutputBuffer`.
+
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex};
+
+/// Source of the bytes read by `,`
+pub trait BfInput {
+    /// Read one byte, `None` at the end of the input
+    fn read_byte(&mut self) -> io::Result<Option<u8>>;
+}
+
+/// Sink of the bytes written by `.`
+pub trait BfOutput {
+    /// Write one byte
+    fn write_byte(&mut self, byte: u8) -> io::Result<()>;
+
+    /// Write a run of bytes
+    fn write_bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+        bytes.iter().try_for_each(|&byte| self.write_byte(byte))
+    }
+
+    /// Push written bytes to their destination
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<R: Read> BfInput for R {
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        let mut buffer = [0; 1];  // read exactly one byte
+        match self.read_exact(&mut buffer) {
+            Ok(()) => Ok(Some(buffer[0])),
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+impl<W: Write> BfOutput for W {
+    fn write_byte(&mut self, byte: u8) -> io::Result<()> {
+        self.write_all(&[byte])
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.write_all(bytes)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Write::flush(self)
+    }
+}
+
+/// Capacity of the output buffer
+pub const OUTPUT_BUFFER_SIZE: usize = 8 * 1024;
+
+/// Output buffer shared by all engines. It is flushed on newline, when it is
+/// full, before `,` reads input and when the program exits, or after every
+/// byte when unbuffered.
+pub(crate) struct OutputBuffer {
+    /// Where flushed bytes go
+    pub sink: Box<dyn BfOutput + Send>,
+
+    /// Flush after every byte, for interactive programs
+    pub unbuffered: bool,
+
+    buffer: Vec<u8>,
+}
+
+impl OutputBuffer {
+    pub fn new(sink: Box<dyn BfOutput + Send>) -> Self {
+        OutputBuffer {
+            sink,
+            unbuffered: false,
+            buffer: Vec::with_capacity(OUTPUT_BUFFER_SIZE),
+        }
+    }
+
+    /// Buffer one byte, flushing if required
+    pub fn write_byte(&mut self, byte: u8) -> io::Result<()> {
+        self.buffer.push(byte);
+        if self.unbuffered || byte == b'\n' || self.buffer.len() >= OUTPUT_BUFFER_SIZE {
+            self.flush()
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Write out the buffered bytes and flush the sink
+    pub fn flush(&mut self) -> io::Result<()> {
+        let result = self.sink.write_bytes(&self.buffer)
+            .and_then(|()| self.sink.flush());
+        self.buffer.clear();
+        result
+    }
+}
+
+impl Drop for OutputBuffer {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+/// In-memory output which stays readable after it was handed to an `Emu`
+#[derive(Clone, Debug, Default)]
+pub struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl SharedBuffer {
+    pub fn new() -> Self {
+        SharedBuffer::default()
+    }
+
+    /// Everything written so far
+    pub fn contents(&self) -> Vec<u8> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,852 @@
+//! The intermediate representation shared by every engine. The parser turns
+//! Brainfuck source into one `BfOperation` per command with the loop targets
+//! already resolved, the passes of the `optimize` module rewrite the
+//! program, and the interpreters and JIT backends execute whatever program
+//! they are handed.
+
+use crate::optimize::Optimizer;
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// A single operation of the intermediate representation
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BfOperation {
+    /// Move the data pointer right by N cells
+    IncPtr(usize),
+
+    /// Move the data pointer left by N cells
+    DecPtr(usize),
+
+    /// Add N to the current cell, wrapping at the cell width
+    IncData(u64),
+
+    /// Subtract N from the current cell, wrapping at the cell width
+    DecData(u64),
+
+    /// Read one byte of input into the current cell
+    ReadStdin,
+
+    /// Write the current cell to the output
+    WriteStdout,
+
+    /// Jump past the matching `LoopEnd` (at the given index) if the current
+    /// cell is zero
+    LoopStart(usize),
+
+    /// Jump back to the matching `LoopStart` (at the given index) if the
+    /// current cell is non-zero
+    LoopEnd(usize),
+
+    /// Set the current cell to zero, `[-]`
+    SetZero,
+
+    /// Move the data pointer right by N cells until the current cell is
+    /// zero, `[>]`
+    ScanRight(usize),
+
+    /// Move the data pointer left by N cells until the current cell is zero,
+    /// `[<]`
+    ScanLeft(usize),
+
+    /// Add the current cell times `factor` to the cell `offset` cells away,
+    /// wrapping at the cell width. The data pointer does not move.
+    MulAdd { offset: isize, factor: u64 },
+
+    /// Add `delta` to the cell `offset` cells away, wrapping at the cell
+    /// width. The data pointer does not move.
+    Add { offset: isize, delta: u64 },
+
+    /// Store `value`, truncated to the cell width, in the cell `offset`
+    /// cells away. The data pointer does not move.
+    Set { offset: isize, value: u64 },
+
+    /// Write the byte N to the output, a `.` of a cell whose value is known
+    WriteConst(u8),
+}
+
+/// The cell `offset` cells away from the data pointer, as `[ptr+offset]`
+struct CellRef(isize);
+
+impl fmt::Display for CellRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            0 => write!(f, "[ptr]"),
+            offset => write!(f, "[ptr{:+}]", offset),
+        }
+    }
+}
+
+/// `+= delta`, or `-= delta` for deltas which are negative as an `i64`
+struct Delta(u64);
+
+impl fmt::Display for Delta {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 as i64 {
+            delta if delta < 0 => write!(f, "-= {}", delta.unsigned_abs()),
+            delta => write!(f, "+= {}", delta),
+        }
+    }
+}
+
+impl fmt::Display for BfOperation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            BfOperation::IncPtr(times)  => write!(f, "ptr += {}", times),
+            BfOperation::DecPtr(times)  => write!(f, "ptr -= {}", times),
+            BfOperation::IncData(times) => write!(f, "[ptr] {}", Delta(times)),
+            BfOperation::DecData(times) => write!(f, "[ptr] {}", Delta(times.wrapping_neg())),
+            BfOperation::ReadStdin      => write!(f, "read [ptr]"),
+            BfOperation::WriteStdout    => write!(f, "write [ptr]"),
+            BfOperation::LoopStart(end) => write!(f, "loop -> {}", end),
+            BfOperation::LoopEnd(start) => write!(f, "end -> {}", start),
+            BfOperation::SetZero        => write!(f, "[ptr] = 0"),
+            BfOperation::ScanRight(stride) => write!(f, "scan ptr += {}", stride),
+            BfOperation::ScanLeft(stride)  => write!(f, "scan ptr -= {}", stride),
+            BfOperation::MulAdd { offset, factor } => match factor as i64 {
+                factor if factor < 0 => {
+                    write!(f, "{} -= [ptr] * {}", CellRef(offset), factor.unsigned_abs())
+                },
+                factor => write!(f, "{} += [ptr] * {}", CellRef(offset), factor),
+            },
+            BfOperation::Add { offset, delta } => write!(f, "{} {}", CellRef(offset), Delta(delta)),
+            BfOperation::Set { offset, value } => write!(f, "{} = {}", CellRef(offset), value),
+            BfOperation::WriteConst(byte) => match byte {
+                b' ' | b'\t' | b'\n' | b'\r' | b'!'..=b'~' => {
+                    write!(f, "write {} {:?}", byte, byte as char)
+                },
+                _ => write!(f, "write {}", byte),
+            },
+        }
+    }
+}
+
+/// A parsed Brainfuck program
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Program {
+    /// The operations, with loop targets resolved
+    pub ops: Vec<BfOperation>,
+
+    /// Source byte offset of the first command that produced each operation
+    pub offsets: Vec<usize>,
+}
+
+impl Program {
+    /// Parse Brainfuck source into one operation per command. Characters
+    /// other than the eight commands are comments and are skipped; use
+    /// `lint` to report them.
+    pub fn parse(source: &str) -> Result<Program, ParseError> {
+        let mut program = Program::default();
+
+        for (offset, byte) in source.bytes().enumerate() {
+            let operation = match byte {
+                b'>' => BfOperation::IncPtr(1),
+                b'<' => BfOperation::DecPtr(1),
+                b'+' => BfOperation::IncData(1),
+                b'-' => BfOperation::DecData(1),
+                b',' => BfOperation::ReadStdin,
+                b'.' => BfOperation::WriteStdout,
+                b'[' => BfOperation::LoopStart(0),
+                b']' => BfOperation::LoopEnd(0),
+                _ => continue,
+            };
+            program.ops.push(operation);
+            program.offsets.push(offset);
+        }
+
+        if let Err(idx) = program.resolve_loops() {
+            let span = Span::new(source, program.offsets[idx]);
+            return Err(match program.ops[idx] {
+                BfOperation::LoopStart(_) => ParseError::UnmatchedLoopStart(span),
+                _ => ParseError::UnmatchedLoopEnd(span),
+            });
+        }
+        Ok(program)
+    }
+
+    /// Strict mode: report every character which is neither one of the eight
+    /// commands nor whitespace. Such characters are valid comments, but in
+    /// code without prose they are usually typos.
+    pub fn lint(source: &str) -> Vec<ParseWarning> {
+        let mut warnings = Vec::new();
+        let mut span = Span { offset: 0, line: 1, column: 1 };
+
+        for (offset, c) in source.char_indices() {
+            span.offset = offset;
+            if !c.is_whitespace() && !"><+-,.[]".contains(c) {
+                warnings.push(ParseWarning::StrayCharacter(c, span));
+            }
+
+            if c == '\n' {
+                span.line += 1;
+                span.column = 1;
+            } else {
+                span.column += 1;
+            }
+        }
+
+        warnings
+    }
+
+    /// Number of operations in the program
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    /// Render the program one operation per line, with its index and the
+    /// line and column in `source` of the command it came from. Loop bodies
+    /// are indented.
+    pub fn listing(&self, source: &str) -> String {
+        let mut listing = String::new();
+        let mut depth = 0;
+
+        for (idx, (&operation, &offset)) in self.ops.iter().zip(self.offsets.iter()).enumerate() {
+            if let BfOperation::LoopEnd(_) = operation {
+                depth -= 1;
+            }
+            let position = Span::new(source, offset).to_string();
+            listing += &format!("{:>6}  {:<8}  {:indent$}{}\n", idx, position, "", operation,
+                                indent = 4 * depth);
+            if let BfOperation::LoopStart(_) = operation {
+                depth += 1;
+            }
+        }
+
+        listing
+    }
+
+    /// Whether the program has no operations
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    /// Fold runs of `+`, `-`, `>` and `<` into a single operation each
+    pub fn fold(&self) -> Program {
+        let mut folded = Program::default();
+
+        for (&operation, &offset) in self.ops.iter().zip(self.offsets.iter()) {
+            let merged = match (folded.ops.last(), operation) {
+                (Some(BfOperation::IncPtr(a)), BfOperation::IncPtr(b)) => {
+                    Some(BfOperation::IncPtr(a + b))
+                },
+                (Some(BfOperation::DecPtr(a)), BfOperation::DecPtr(b)) => {
+                    Some(BfOperation::DecPtr(a + b))
+                },
+                (Some(BfOperation::IncData(a)), BfOperation::IncData(b)) => {
+                    Some(BfOperation::IncData(a.wrapping_add(b)))
+                },
+                (Some(BfOperation::DecData(a)), BfOperation::DecData(b)) => {
+                    Some(BfOperation::DecData(a.wrapping_add(b)))
+                },
+                _ => None,
+            };
+
+            match merged {
+                Some(merged) => *folded.ops.last_mut().unwrap() = merged,
+                None => {
+                    folded.ops.push(operation);
+                    folded.offsets.push(offset);
+                }
+            }
+        }
+
+        folded.resolve_loops().expect("folding keeps loops balanced");
+        folded
+    }
+
+    /// Optimize the program with the default `Optimizer`, running every pass
+    /// of `-O2`. The result expects the tape to be all zeros when it starts.
+    pub fn optimize(&self) -> Program {
+        Optimizer::default().run(self)
+    }
+
+    /// Replace the loop idioms real programs spend most of their time in,
+    /// see `Pass::Idioms`. Also returns the number of loops replaced.
+    pub(crate) fn replace_idioms(&self) -> (Program, usize) {
+        let mut replaced = Program::default();
+        let mut loops = 0;
+
+        let mut idx = 0;
+        while idx < self.ops.len() {
+            if let BfOperation::LoopStart(end) = self.ops[idx] {
+                if let Some(idiom) = self.loop_idiom(idx, end) {
+                    for (operation, offset) in idiom {
+                        replaced.push(operation, offset);
+                    }
+                    loops += 1;
+                    idx = end + 1;
+                    continue;
+                }
+            }
+            replaced.push(self.ops[idx], self.offsets[idx]);
+            idx += 1;
+        }
+
+        replaced.resolve_loops().expect("replacing idioms keeps loops balanced");
+        (replaced, loops)
+    }
+
+    /// Rewrite every run of two or more `IncPtr`, `DecPtr`, `IncData`,
+    /// `DecData` and `Add` into an `Add` per cell with a net change, in the
+    /// order the run first changes them, followed by the net pointer
+    /// movement. When the run reaches further than these cells and the
+    /// final position, an `Add` of zero to the furthest cell it reaches on
+    /// that side, placed where the run first got there, keeps the excursion
+    /// checked against the tape.
+    pub(crate) fn address_cells(&self) -> Program {
+        let mut addressed = Program::default();
+
+        let mut idx = 0;
+        while idx < self.ops.len() {
+            let run = self.ops[idx..].iter()
+                .take_while(|operation| matches!(operation,
+                    BfOperation::IncPtr(_) | BfOperation::DecPtr(_) |
+                    BfOperation::IncData(_) | BfOperation::DecData(_) |
+                    BfOperation::Add { .. }))
+                .count();
+            if run < 2 {
+                addressed.ops.push(self.ops[idx]);
+                addressed.offsets.push(self.offsets[idx]);
+                idx += 1;
+                continue;
+            }
+
+            let ops = &self.ops[idx..idx + run];
+            let offsets = &self.offsets[idx..idx + run];
+            let (ptr, deltas) = cell_deltas(ops, offsets)
+                .expect("runs only hold pointer and data operations");
+
+            let mut adds: Vec<CellDelta> = deltas.into_iter()
+                .filter(|&(_, delta, _)| delta != 0)
+                .collect();
+            for (offset, source) in reach(ops, offsets) {
+                if offset != 0 && offset != ptr && adds.iter().all(|&(cell, _, _)| cell != offset) {
+                    adds.push((offset, 0, source));
+                }
+            }
+            // The excursions are checked where the run got there
+            adds.sort_by_key(|&(_, _, source)| source);
+            for (offset, delta, source) in adds {
+                addressed.ops.push(BfOperation::Add { offset, delta });
+                addressed.offsets.push(source);
+            }
+            if ptr != 0 {
+                addressed.ops.push(match ptr {
+                    ptr if ptr > 0 => BfOperation::IncPtr(ptr as usize),
+                    ptr => BfOperation::DecPtr(ptr.unsigned_abs()),
+                });
+                // Reported if the movement leaves the tape
+                let last_move = ops.iter()
+                    .rposition(|operation| matches!(operation,
+                        BfOperation::IncPtr(_) | BfOperation::DecPtr(_)))
+                    .unwrap();
+                addressed.offsets.push(offsets[last_move]);
+            }
+            idx += run;
+        }
+
+        addressed.resolve_loops().expect("addressing keeps loops balanced");
+        addressed
+    }
+
+    /// Track the cell values known at every point of the program, see
+    /// `KnownCells`, removing loops which never run and replacing
+    /// operations on known cells by stores of their result. Known values
+    /// are only stored right before an operation which could observe the
+    /// tape or stop the program. Also returns the number of loops removed
+    /// or run at compile time. Unless `zero_tape` says the tape starts all
+    /// zeros only the values the program stores itself are known.
+    pub(crate) fn propagate_constants(&self, zero_tape: bool) -> (Program, usize) {
+        let mut propagated = Program::default();
+        let mut known = KnownCells::new(zero_tape);
+        let mut loops = 0;
+
+        let mut idx = 0;
+        while idx < self.ops.len() {
+            let operation = self.ops[idx];
+            let source = self.offsets[idx];
+
+            let addition = match operation {
+                BfOperation::IncData(times) => Some((0, times)),
+                BfOperation::DecData(times) => Some((0, times.wrapping_neg())),
+                BfOperation::Add { offset, delta } => Some((offset, delta)),
+                _ => None,
+            };
+            if let Some((offset, delta)) = addition {
+                // An `Add` of zero only checks a cell against the tape
+                if delta == 0 && offset != 0 {
+                    known.flush(&mut propagated);
+                    propagated.push(operation, source);
+                    idx += 1;
+                    continue;
+                }
+                let cell = known.ptr + offset;
+                match known.value(cell) {
+                    Some(value) => known.set(cell, value.wrapping_add(delta), source),
+                    None => {
+                        // Only a neighbouring cell can be off the tape
+                        if offset != 0 {
+                            known.flush(&mut propagated);
+                        }
+                        propagated.push(operation, source);
+                    },
+                }
+                idx += 1;
+                continue;
+            }
+
+            match operation {
+                BfOperation::Set { offset, value } => known.set(known.ptr + offset, value, source),
+                BfOperation::SetZero => known.set(known.ptr, 0, source),
+                BfOperation::WriteStdout => match known.value(known.ptr) {
+                    Some(value) => propagated.push(BfOperation::WriteConst(value as u8), source),
+                    None => propagated.push(operation, source),
+                },
+                BfOperation::WriteConst(_) => propagated.push(operation, source),
+                BfOperation::LoopStart(end) => {
+                    let counter = known.value(known.ptr);
+                    if counter == Some(0) {
+                        loops += 1;
+                        idx = end + 1;
+                        continue;
+                    }
+                    if let Some(counter) = counter {
+                        if self.fold_multiply_loop(idx, end, counter, &mut known, &mut propagated) {
+                            loops += 1;
+                            idx = end + 1;
+                            continue;
+                        }
+                    }
+                    known.flush(&mut propagated);
+                    propagated.push(operation, source);
+                    known.forget();
+                },
+                BfOperation::IncPtr(times) => {
+                    known.flush(&mut propagated);
+                    propagated.push(operation, source);
+                    known.ptr += times as isize;
+                },
+                BfOperation::DecPtr(times) => {
+                    known.flush(&mut propagated);
+                    propagated.push(operation, source);
+                    known.ptr -= times as isize;
+                },
+                BfOperation::ReadStdin => {
+                    known.flush(&mut propagated);
+                    propagated.push(operation, source);
+                    known.stored.insert(known.ptr, None);
+                },
+                BfOperation::MulAdd { offset, .. } => {
+                    known.flush(&mut propagated);
+                    propagated.push(operation, source);
+                    known.stored.insert(known.ptr + offset, None);
+                },
+                // All of them stop on a zero cell somewhere
+                BfOperation::ScanRight(_) | BfOperation::ScanLeft(_) | BfOperation::LoopEnd(_) => {
+                    known.flush(&mut propagated);
+                    propagated.push(operation, source);
+                    known.forget();
+                    known.stored.insert(known.ptr, Some(0));
+                },
+                BfOperation::IncData(_) | BfOperation::DecData(_) | BfOperation::Add { .. } => {
+                    unreachable!()
+                },
+            }
+            idx += 1;
+        }
+
+        known.flush(&mut propagated);
+        propagated.resolve_loops().expect("propagating keeps loops balanced");
+        (propagated, loops)
+    }
+
+    /// Run the multiply loop from `start` to `end` at compile time if it is
+    /// one and its `counter` is non-zero at every cell width, returning
+    /// whether it was
+    fn fold_multiply_loop(&self, start: usize, end: usize, counter: u64,
+                          known: &mut KnownCells, folded: &mut Program) -> bool {
+        let body = &self.ops[start + 1..end];
+        let is_multiply = body.last() == Some(&BfOperation::SetZero)
+            && body[..body.len() - 1].iter().all(|operation| matches!(operation, BfOperation::MulAdd { .. }));
+        if !is_multiply || counter as u8 == 0 {
+            return false;
+        }
+
+        for (idx, &operation) in body.iter().enumerate() {
+            let source = self.offsets[start + 1 + idx];
+            match operation {
+                BfOperation::MulAdd { offset, factor } => {
+                    let cell = known.ptr + offset;
+                    let product = counter.wrapping_mul(factor);
+                    match known.value(cell) {
+                        Some(value) => known.set(cell, value.wrapping_add(product), source),
+                        None => {
+                            known.flush(folded);
+                            folded.push(BfOperation::Add { offset, delta: product }, source);
+                        },
+                    }
+                },
+                _ => known.set(known.ptr, 0, source),
+            }
+        }
+        true
+    }
+
+    /// Append an operation
+    fn push(&mut self, operation: BfOperation, offset: usize) {
+        self.ops.push(operation);
+        self.offsets.push(offset);
+    }
+
+    /// The operations replacing the loop from `start` to `end` and their
+    /// source offsets, if the loop is one of the idioms `replace_idioms`
+    /// recognizes
+    fn loop_idiom(&self, start: usize, end: usize) -> Option<Vec<(BfOperation, usize)>> {
+        let body = &self.ops[start + 1..end];
+        let offsets = &self.offsets[start + 1..end];
+
+        // An odd step reaches zero from any value at every cell width
+        match *body {
+            [BfOperation::IncData(step)] | [BfOperation::DecData(step)] if step % 2 == 1 => {
+                return Some(vec![(BfOperation::SetZero, self.offsets[start])]);
+            },
+            [BfOperation::IncPtr(stride)] => {
+                return Some(vec![(BfOperation::ScanRight(stride), self.offsets[start])]);
+            },
+            [BfOperation::DecPtr(stride)] => {
+                return Some(vec![(BfOperation::ScanLeft(stride), self.offsets[start])]);
+            },
+            _ => {},
+        }
+
+        let (ptr, mut deltas) = cell_deltas(body, offsets)?;
+        if ptr != 0 {
+            return None;
+        }
+
+        // Counting down the body runs once per unit of the counter, counting
+        // up once per unit it is short of wrapping around
+        let counter = deltas.iter().position(|&(cell, _, _)| cell == 0)?;
+        let (_, step, counter_offset) = deltas.remove(counter);
+        let sign = match step {
+            u64::MAX => 1,
+            1 => u64::MAX,
+            _ => return None,
+        };
+
+        // The replacement only checks the cells it changes, so the body
+        // must not reach any further
+        let reaches_further = reach(body, offsets).iter().any(|&(cell, _)| {
+            cell != 0 && deltas.iter().all(|&(changed, delta, _)| changed != cell || delta == 0)
+        });
+        if reaches_further {
+            return None;
+        }
+
+        let targets: Vec<(BfOperation, usize)> = deltas.into_iter()
+            .filter(|&(_, delta, _)| delta != 0)
+            .map(|(offset, delta, source)| {
+                (BfOperation::MulAdd { offset, factor: delta.wrapping_mul(sign) }, source)
+            })
+            .collect();
+        if targets.is_empty() {
+            return Some(vec![(BfOperation::SetZero, counter_offset)]);
+        }
+
+        let mut idiom = vec![(BfOperation::LoopStart(0), self.offsets[start])];
+        idiom.extend(targets);
+        idiom.push((BfOperation::SetZero, counter_offset));
+        idiom.push((BfOperation::LoopEnd(0), self.offsets[end]));
+        Some(idiom)
+    }
+
+    /// Point every `LoopStart` and `LoopEnd` at its matching bracket. On
+    /// failure returns the index of the first unmatched bracket.
+    fn resolve_loops(&mut self) -> Result<(), usize> {
+        let mut open_loops = Vec::<usize>::new();
+
+        for idx in 0..self.ops.len() {
+            match self.ops[idx] {
+                BfOperation::LoopStart(_) => open_loops.push(idx),
+                BfOperation::LoopEnd(_) => {
+                    let start = open_loops.pop().ok_or(idx)?;
+                    self.ops[start] = BfOperation::LoopStart(idx);
+                    self.ops[idx] = BfOperation::LoopEnd(start);
+                },
+                _ => {}
+            }
+        }
+
+        match open_loops.first() {
+            Some(&start) => Err(start),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Cell values known to `Program::propagate_constants`, by cell position
+/// relative to where the pointer was when it last lost track of it
+struct KnownCells {
+    /// Position of the data pointer
+    ptr: isize,
+
+    /// Cells whose value on the tape is known, or known to be unknown
+    stored: BTreeMap<isize, Option<u64>>,
+
+    /// Whether every cell missing from `stored` is zero, true on an all-zero
+    /// tape until the pointer moves by an unknown distance
+    rest_zero: bool,
+
+    /// Values not stored on the tape yet, in the order they were first
+    /// changed, with the source offset of the command doing so
+    pending: Vec<(isize, u64, usize)>,
+}
+
+impl KnownCells {
+    /// The state at the start of a program, knowing every cell is zero if
+    /// the tape starts all zeros
+    fn new(zero_tape: bool) -> Self {
+        KnownCells { ptr: 0, stored: BTreeMap::new(), rest_zero: zero_tape, pending: Vec::new() }
+    }
+
+    /// The value of `cell` if it is known
+    fn value(&self, cell: isize) -> Option<u64> {
+        match self.pending.iter().find(|&&(pending, _, _)| pending == cell) {
+            Some(&(_, value, _)) => Some(value),
+            None => self.stored_value(cell),
+        }
+    }
+
+    /// The value of `cell` on the tape if it is known
+    fn stored_value(&self, cell: isize) -> Option<u64> {
+        match self.stored.get(&cell) {
+            Some(&value) => value,
+            None if self.rest_zero => Some(0),
+            None => None,
+        }
+    }
+
+    /// Change `cell` to `value` without storing it yet
+    fn set(&mut self, cell: isize, value: u64, source: usize) {
+        match self.pending.iter_mut().find(|(pending, _, _)| *pending == cell) {
+            Some((_, pending, _)) => *pending = value,
+            None => self.pending.push((cell, value, source)),
+        }
+    }
+
+    /// Store the pending values which differ from the tape
+    fn flush(&mut self, program: &mut Program) {
+        for (cell, value, source) in std::mem::take(&mut self.pending) {
+            if self.stored_value(cell) == Some(value) {
+                continue;
+            }
+            let operation = match (cell - self.ptr, value) {
+                (0, 0) => BfOperation::SetZero,
+                (offset, value) => BfOperation::Set { offset, value },
+            };
+            program.push(operation, source);
+            self.stored.insert(cell, Some(value));
+        }
+    }
+
+    /// Lose track of the pointer, nothing is pending
+    fn forget(&mut self) {
+        debug_assert!(self.pending.is_empty());
+        self.ptr = 0;
+        self.stored.clear();
+        self.rest_zero = false;
+    }
+}
+
+/// Net change of a cell: its offset from the data pointer, the delta and
+/// the source offset of the first command changing it
+type CellDelta = (isize, u64, usize);
+
+/// The net pointer movement of straight-line `ops` and the net change of
+/// every cell they touch, relative to the pointer at their start. `None` if
+/// `ops` do anything but move the pointer and add to cells.
+fn cell_deltas(ops: &[BfOperation], offsets: &[usize]) -> Option<(isize, Vec<CellDelta>)> {
+    let mut ptr = 0isize;
+    let mut deltas: Vec<CellDelta> = Vec::new();
+
+    for (&operation, &offset) in ops.iter().zip(offsets) {
+        let (target, delta) = match operation {
+            BfOperation::IncPtr(times) => {
+                ptr += times as isize;
+                continue;
+            },
+            BfOperation::DecPtr(times) => {
+                ptr -= times as isize;
+                continue;
+            },
+            BfOperation::IncData(times) => (ptr, times),
+            BfOperation::DecData(times) => (ptr, times.wrapping_neg()),
+            BfOperation::Add { offset, delta } => (ptr + offset, delta),
+            _ => return None,
+        };
+        match deltas.iter_mut().find(|(cell, _, _)| *cell == target) {
+            Some((_, total, _)) => *total = total.wrapping_add(delta),
+            None => deltas.push((target, delta, offset)),
+        }
+    }
+
+    Some((ptr, deltas))
+}
+
+/// The lowest and the highest cell straight-line `ops` move the pointer to
+/// or add to, relative to the pointer at their start, each with the source
+/// offset of the first command reaching it
+fn reach(ops: &[BfOperation], offsets: &[usize]) -> [(isize, usize); 2] {
+    let mut ptr = 0isize;
+    let mut lowest = (0, offsets[0]);
+    let mut highest = (0, offsets[0]);
+
+    for (&operation, &offset) in ops.iter().zip(offsets) {
+        let cell = match operation {
+            BfOperation::IncPtr(times) => {
+                ptr += times as isize;
+                ptr
+            },
+            BfOperation::DecPtr(times) => {
+                ptr -= times as isize;
+                ptr
+            },
+            BfOperation::Add { offset, .. } => ptr + offset,
+            _ => ptr,
+        };
+        if cell < lowest.0 {
+            lowest = (cell, offset);
+        }
+        if cell > highest.0 {
+            highest = (cell, offset);
+        }
+    }
+
+    [lowest, highest]
+}
+
+/// A position in the Brainfuck source
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Span {
+    /// Byte offset into the source
+    pub offset: usize,
+
+    /// 1-based line number
+    pub line: usize,
+
+    /// 1-based column, counted in characters
+    pub column: usize,
+}
+
+impl Span {
+    /// Compute the line and column of byte `offset` in `source`
+    pub fn new(source: &str, offset: usize) -> Span {
+        let before = &source[..offset];
+        let line_start = before.rfind('\n').map_or(0, |idx| idx + 1);
+
+        Span {
+            offset,
+            line: before.matches('\n').count() + 1,
+            column: before[line_start..].chars().count() + 1,
+        }
+    }
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+/// Reasons why a program could not be parsed
+#[derive(Clone, Debug, PartialEq)]
+pub enum ParseError {
+    /// A `[` without a matching `]`
+    UnmatchedLoopStart(Span),
+
+    /// A `]` without a matching `[`
+    UnmatchedLoopEnd(Span),
+}
+
+impl ParseError {
+    /// Location of the offending character
+    pub fn span(&self) -> Span {
+        match self {
+            ParseError::UnmatchedLoopStart(span) => *span,
+            ParseError::UnmatchedLoopEnd(span) => *span,
+        }
+    }
+
+    /// Render a diagnostic showing the offending source line, e.g.
+    ///
+    /// ```text
+    /// error: unmatched `[`
+    ///  --> hello.bf:3:3
+    ///   |
+    /// 3 | ++[->+<
+    ///   |   ^
+    /// ```
+    pub fn render(&self, name: &str, source: &str) -> String {
+        render_diagnostic("error", &self.to_string(), self.span(), name, source)
+    }
+}
+
+/// Questionable but valid source reported by `Program::lint`
+#[derive(Clone, Debug, PartialEq)]
+pub enum ParseWarning {
+    /// A character which is neither a command nor whitespace. Standard
+    /// Brainfuck treats it as a comment.
+    StrayCharacter(char, Span),
+}
+
+impl ParseWarning {
+    /// Location of the offending character
+    pub fn span(&self) -> Span {
+        match self {
+            ParseWarning::StrayCharacter(_, span) => *span,
+        }
+    }
+
+    /// Render a diagnostic showing the offending source line
+    pub fn render(&self, name: &str, source: &str) -> String {
+        render_diagnostic("warning", &self.to_string(), self.span(), name,
+                          source)
+    }
+}
+
+impl fmt::Display for ParseWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseWarning::StrayCharacter(c, _) => {
+                write!(f, "stray character {:?} is ignored", c)
+            }
+        }
+    }
+}
+
+/// Render a rustc-style diagnostic pointing at `span` in `source`
+fn render_diagnostic(level: &str, message: &str, span: Span, name: &str,
+                     source: &str) -> String {
+    let line = source.lines().nth(span.line - 1).unwrap_or("");
+    let gutter = " ".repeat(span.line.to_string().len());
+
+    // Keep tabs so the caret lines up with the source line
+    let padding: String = line.chars().take(span.column - 1)
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+
+    format!("{}: {}\n{}--> {}:{}\n{} |\n{} | {}\n{} | {}^\n",
+            level, message, gutter, name, span, gutter, span.line, line,
+            gutter, padding)
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::UnmatchedLoopStart(_) => write!(f, "unmatched `[`"),
+            ParseError::UnmatchedLoopEnd(_) => write!(f, "unmatched `]`"),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
@@ -0,0 +1,331 @@
+//! JIT code generators for x86-64 and AArch64, encoding with the built-in
+//! `x64` and `a64` assemblers. The register conventions are described in the
+//! backend modules.
+//!
+//! The code keeps the data pointer, the first cell of the tape and one past
+//! the last cell in registers. With bounds checks enabled every pointer
+//! movement is checked against the tape; without, only movements which
+//! could jump over a guard region of `GUARD_SIZE` bytes without touching
+//! memory are. When a check fails the code returns early with the source
+//! offset of the faulting operation plus one as its status, along with the
+//! out of bounds pointer or the cell a `MulAdd`, `Add` or `Set` targets; the data
+//! pointer is moved back to the last valid cell. When a `,` hits the end of
+//! the input with `EofPolicy::Stop` the code returns its source offset plus
+//! one, tagged with `INPUT_EXHAUSTED`. A normal exit returns zero.
+//!
+//! Cells are `JitOptions::cell_width` wide, pointer movements are scaled
+//! accordingly and the data pointer always points at the first byte of a
+//! cell.
+//!
+//! I/O goes through the `HostCalls` table. When a host call fails the code
+//! returns `HOST_ERROR` and the error is left in the table.
+
+use crate::cell::CellWidth;
+use crate::emu::{EofPolicy, VmExit};
+use crate::io::{BfInput, OutputBuffer};
+use crate::ir::{BfOperation, Program};
+use crate::tape::GUARD_SIZE;
+
+use std::io;
+
+mod aarch64;
+mod x86_64;
+
+/// Tag on the status returned when the input ran out with
+/// `EofPolicy::Stop`
+pub(crate) const INPUT_EXHAUSTED: usize = 1 << (usize::BITS - 1);
+
+/// Status returned when a host call failed
+pub(crate) const HOST_ERROR: usize = usize::MAX - 1;
+
+/// Returned by the read host call at the end of the input
+pub(crate) const HOST_EOF: usize = 0x100;
+
+/// Host functions called by JIT code, passed in a register. Each function gets
+/// the table itself as its first argument.
+#[repr(C)]
+pub(crate) struct HostCalls<'a> {
+    /// Returns the byte read, `HOST_EOF` or `HOST_ERROR`
+    pub read: extern "C" fn(*mut HostCalls<'a>) -> usize,
+
+    /// Returns zero or `HOST_ERROR`
+    pub write: extern "C" fn(*mut HostCalls<'a>, u8) -> usize,
+
+    input: &'a mut dyn BfInput,
+
+    output: &'a mut OutputBuffer,
+
+    /// The error behind a `HOST_ERROR` exit
+    pub error: Option<io::Error>,
+}
+
+impl<'a> HostCalls<'a> {
+    pub fn new(input: &'a mut dyn BfInput, output: &'a mut OutputBuffer) -> Self {
+        HostCalls { read: host_read, write: host_write, input, output, error: None }
+    }
+}
+
+extern "C" fn host_read(calls: *mut HostCalls<'_>) -> usize {
+    let calls = unsafe { &mut *calls };
+    match calls.output.flush().and_then(|()| calls.input.read_byte()) {
+        Ok(Some(byte)) => byte as usize,
+        Ok(None) => HOST_EOF,
+        Err(err) => {
+            calls.error = Some(err);
+            HOST_ERROR
+        }
+    }
+}
+
+extern "C" fn host_write(calls: *mut HostCalls<'_>, byte: u8) -> usize {
+    let calls = unsafe { &mut *calls };
+    match calls.output.write_byte(byte) {
+        Ok(()) => 0,
+        Err(err) => {
+            calls.error = Some(err);
+            HOST_ERROR
+        }
+    }
+}
+
+/// Code generation options shared by the JIT backends
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct JitOptions {
+    /// Guard every pointer movement and exit with `VmExit::PtrOob` instead
+    /// of touching memory outside the tape
+    pub bounds_checks: bool,
+
+    /// Width of the tape cells
+    pub cell_width: CellWidth,
+
+    /// What `,` does once the input is exhausted
+    pub eof_policy: EofPolicy,
+}
+
+/// Decides which operations the backends guard with an explicit bounds
+/// check. With `JitOptions::bounds_checks` that is every operation which can
+/// leave the tape, without it only those which could get past a guard region
+/// without accessing memory, and pointer movements after which the pointer
+/// could turn back or the program end before the cell it points at is
+/// accessed.
+pub(crate) struct BoundsChecks {
+    enabled: bool,
+
+    cell_size: usize,
+
+    eof_policy: EofPolicy,
+
+    /// Distance in bytes the pointer moved since memory was last accessed or
+    /// checked
+    unchecked_distance: usize,
+
+    /// Index of the first operation after the last run of pointer movements
+    /// in one direction, and whether it accesses the cell they lead to
+    run_end: Option<(usize, bool)>,
+}
+
+impl BoundsChecks {
+    pub fn new(options: &JitOptions) -> Self {
+        BoundsChecks {
+            enabled: options.bounds_checks,
+            cell_size: options.cell_width.bytes(),
+            eof_policy: options.eof_policy,
+            unchecked_distance: 0,
+            run_end: None,
+        }
+    }
+
+    /// Whether the operation at `idx` needs a check, called for every
+    /// operation in program order
+    pub fn check(&mut self, program: &Program, idx: usize) -> bool {
+        let cell_size = self.cell_size;
+        match program.ops[idx] {
+            BfOperation::IncPtr(times) | BfOperation::DecPtr(times) => {
+                self.unchecked_distance += times * cell_size;
+                let check = self.enabled || self.unchecked_distance >= GUARD_SIZE
+                    || !self.accessed_after_move(program, idx);
+                if check {
+                    self.unchecked_distance = 0;
+                }
+                check
+            },
+            // Both access the current cell first, and every step of a scan
+            // accesses the cell it moved to
+            BfOperation::ScanRight(stride) | BfOperation::ScanLeft(stride) => {
+                self.unchecked_distance = 0;
+                self.enabled || stride * cell_size >= GUARD_SIZE
+            },
+            BfOperation::MulAdd { offset, .. } => {
+                self.unchecked_distance = 0;
+                self.enabled || offset.unsigned_abs() * cell_size >= GUARD_SIZE
+            },
+            // Only the target is accessed, which can be as far from the tape
+            // as the pointer plus the offset
+            BfOperation::Add { offset, .. } | BfOperation::Set { offset, .. } => {
+                let bytes = offset.unsigned_abs() * cell_size;
+                let check = self.enabled || self.unchecked_distance + bytes >= GUARD_SIZE;
+                self.unchecked_distance = self.unchecked_distance.min(bytes);
+                check
+            },
+            // Access the current cell. The code after a `]` is only reached
+            // from the test of its `[`.
+            BfOperation::IncData(_) | BfOperation::DecData(_) | BfOperation::WriteStdout |
+            BfOperation::SetZero | BfOperation::LoopStart(_) | BfOperation::LoopEnd(_) => {
+                self.unchecked_distance = 0;
+                false
+            },
+            // Writes a known value without loading the cell
+            BfOperation::WriteConst(_) => false,
+            // Only stores to the cell at the end of the input if the policy
+            // gives it a value, `Stop` returns without touching it
+            BfOperation::ReadStdin => {
+                if matches!(self.eof_policy, EofPolicy::Zero | EofPolicy::MinusOne) {
+                    self.unchecked_distance = 0;
+                }
+                false
+            },
+        }
+    }
+
+    /// Whether the cell the pointer movement at `idx` leads to, or one further
+    /// out in the same direction, is accessed before the pointer moves back
+    fn accessed_after_move(&mut self, program: &Program, idx: usize) -> bool {
+        if let Some((end, accessed)) = self.run_end {
+            if idx < end {
+                return accessed;
+            }
+        }
+
+        let outwards = |operation: &BfOperation| matches!((program.ops[idx], operation),
+            (BfOperation::IncPtr(_), BfOperation::IncPtr(_)) |
+            (BfOperation::DecPtr(_), BfOperation::DecPtr(_)));
+        let end = idx + 1 + program.ops[idx + 1..].iter()
+            .take_while(|operation| outwards(operation))
+            .count();
+        let accessed = match program.ops.get(end) {
+            Some(BfOperation::Add { offset: 0, .. } | BfOperation::Set { offset: 0, .. }) => true,
+            Some(BfOperation::ReadStdin) => {
+                matches!(self.eof_policy, EofPolicy::Zero | EofPolicy::MinusOne)
+            },
+            Some(operation) => matches!(operation,
+                BfOperation::IncData(_) | BfOperation::DecData(_) | BfOperation::WriteStdout |
+                BfOperation::SetZero | BfOperation::LoopStart(_) | BfOperation::LoopEnd(_) |
+                BfOperation::ScanRight(_) | BfOperation::ScanLeft(_) | BfOperation::MulAdd { .. }),
+            None => false,
+        };
+        self.run_end = Some((end, accessed));
+        accessed
+    }
+}
+
+/// `operation` with its pointer movements and cell offsets scaled from cells
+/// to bytes
+pub(crate) fn scale_to_bytes(operation: BfOperation, cell_size: usize) -> BfOperation {
+    match operation {
+        BfOperation::IncPtr(times) => BfOperation::IncPtr(times * cell_size),
+        BfOperation::DecPtr(times) => BfOperation::DecPtr(times * cell_size),
+        BfOperation::ScanRight(stride) => BfOperation::ScanRight(stride * cell_size),
+        BfOperation::ScanLeft(stride) => BfOperation::ScanLeft(stride * cell_size),
+        BfOperation::MulAdd { offset, factor } => {
+            BfOperation::MulAdd { offset: offset * cell_size as isize, factor }
+        },
+        BfOperation::Add { offset, delta } => {
+            BfOperation::Add { offset: offset * cell_size as isize, delta }
+        },
+        BfOperation::Set { offset, value } => {
+            BfOperation::Set { offset: offset * cell_size as isize, value }
+        },
+        operation => operation,
+    }
+}
+
+/// What a stretch of JIT code was generated for
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CodeRegion {
+    /// Setup before the first operation
+    Prologue,
+
+    /// The operation at this index of the program
+    Op(usize),
+
+    /// The returns after the last operation
+    Exit,
+
+    /// Code taken when the operation at this index fails a bounds check or
+    /// hits the end of the input
+    OutOfLine(usize),
+}
+
+/// Where every region of JIT code starts, in code order
+pub type CodeMap = Vec<(usize, CodeRegion)>;
+
+/// Architectures the JIT generates code for
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Target {
+    X86_64,
+    AArch64,
+}
+
+impl Target {
+    /// Look up an architecture by its command line name
+    pub fn from_name(name: &str) -> Option<Target> {
+        match name {
+            "x86_64" | "x86-64" => Some(Target::X86_64),
+            "aarch64"           => Some(Target::AArch64),
+            _                   => None,
+        }
+    }
+
+    /// The architecture we are running on. Elsewhere this is x86-64, whose
+    /// code can be generated but not run.
+    pub fn native() -> Target {
+        if cfg!(target_arch = "aarch64") {
+            Target::AArch64
+        } else {
+            Target::X86_64
+        }
+    }
+}
+
+/// JIT the program optimized at the default `-O2`, see `Program::optimize`
+pub fn generate_jit_opt(program: &Program, options: &JitOptions)
+        -> Result<Vec<u8>, VmExit> {
+    generate_jit(&program.optimize(), options)
+}
+
+/// JIT The stuff up, translating every operation one to one
+pub fn generate_jit(program: &Program, options: &JitOptions)
+        -> Result<Vec<u8>, VmExit> {
+    generate_jit_for(Target::native(), program, options)
+}
+
+/// Translate every operation one to one into code for `target`, which need
+/// not be the architecture we run on
+pub fn generate_jit_for(target: Target, program: &Program, options: &JitOptions)
+        -> Result<Vec<u8>, VmExit> {
+    Ok(generate_jit_mapped(target, program, options)?.0)
+}
+
+/// Same as `generate_jit_for`, also returning where the code of every
+/// operation starts
+pub fn generate_jit_mapped(target: Target, program: &Program, options: &JitOptions)
+        -> Result<(Vec<u8>, CodeMap), VmExit> {
+    Ok(match target {
+        Target::X86_64 => {
+            let (asm, map) = x86_64::assemble(program, options);
+            (asm.finish(), map)
+        },
+        Target::AArch64 => {
+            let (asm, map) = aarch64::assemble(program, options);
+            (asm.finish(), map)
+        },
+    })
+}
+
+/// Generate x86-64 code for `program` with the built-in encoder and compare
+/// every instruction against Keystone
+#[cfg(feature = "keystone")]
+pub fn cross_check(program: &Program, options: &JitOptions) -> Result<Vec<u8>, String> {
+    x86_64::assemble(program, options).0.cross_check()
+}
+
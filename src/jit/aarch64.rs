@@ -0,0 +1,353 @@
+//! AArch64 code generator. The generated code expects the data pointer in
+//! `x20`, the first cell of the tape in `x21`, one past the last cell in
+//! `x22` and the `HostCalls` table in `x23`. It returns the status in `x0`
+//! and, on a failed bounds check, the out of bounds pointer in `x1`.
+//!
+//! Host calls clobber the link register, so it is kept in `x24` for the
+//! whole run and every exit returns through it. `x19` is left alone as the
+//! compiler reserves it around inline assembly.
+
+use super::{scale_to_bytes, BoundsChecks, CodeMap, CodeRegion, HostCalls, JitOptions};
+use super::{HOST_EOF, HOST_ERROR, INPUT_EXHAUSTED};
+use crate::a64::{AddSub, Assembler, Cond, Label, Reg, Size, Width};
+use crate::a64::{X0, X1, X9, X10, X11, X12, X16, X20, X21, X22, X23, X24, X30, XZR};
+use crate::cell::CellWidth;
+use crate::emu::EofPolicy;
+use crate::ir::{BfOperation, Program};
+
+use std::mem::offset_of;
+
+/// Data pointer
+const PTR: Reg = X20;
+
+/// First cell of the tape
+const TAPE_START: Reg = X21;
+
+/// One past the last cell of the tape
+const TAPE_END: Reg = X22;
+
+/// The `HostCalls` table
+const CALLS: Reg = X23;
+
+/// Return address of the generated code
+const LINK: Reg = X24;
+
+/// Largest immediate `add` and `sub` take unshifted
+const IMM12: u64 = 1 << 12;
+
+/// Add `delta` to the cell `addr` points at, or subtract it. Small deltas
+/// and deltas which are small once negated are immediates, others go
+/// through `x10`.
+fn data_op(asm: &mut Assembler, op: AddSub, addr: Reg, delta: u64, width: CellWidth) {
+    let size = Size::from(width);
+    let reg_width = size.width();
+    let delta = width.truncate(delta);
+    let negated = width.truncate(delta.wrapping_neg());
+
+    asm.load(size, X9, addr);
+    if delta < IMM12 {
+        asm.add_sub_imm(op, reg_width, X9, X9, delta as u32);
+    } else if negated < IMM12 {
+        let op = if op == AddSub::Add { AddSub::Sub } else { AddSub::Add };
+        asm.add_sub_imm(op, reg_width, X9, X9, negated as u32);
+    } else {
+        asm.mov_imm(X10, delta);
+        asm.add_sub_reg(op, reg_width, X9, X9, X10);
+    }
+    asm.store(size, X9, addr);
+}
+
+/// Move the data pointer by `bytes`, through `x9` when the movement does not
+/// fit an immediate
+fn move_ptr(asm: &mut Assembler, op: AddSub, bytes: usize) {
+    if (bytes as u64) < IMM12 {
+        asm.add_sub_imm(op, Width::X, PTR, PTR, bytes as u32);
+    } else {
+        asm.mov_imm(X9, bytes as u64);
+        asm.add_sub_reg(op, Width::X, PTR, PTR, X9);
+    }
+}
+
+/// Point `x11` at the cell `disp` bytes away from the current one
+fn target_address(asm: &mut Assembler, disp: isize) {
+    let op = if disp < 0 { AddSub::Sub } else { AddSub::Add };
+    let bytes = disp.unsigned_abs() as u64;
+    if bytes < IMM12 {
+        asm.add_sub_imm(op, Width::X, X11, PTR, bytes as u32);
+    } else {
+        asm.mov_imm(X11, bytes);
+        asm.add_sub_reg(op, Width::X, X11, PTR, X11);
+    }
+}
+
+/// Point `x11` at the cell `disp` bytes away and jump to `label` if it is
+/// off the tape
+fn check_target(asm: &mut Assembler, disp: isize, label: Label) {
+    target_address(asm, disp);
+    if disp < 0 {
+        asm.cmp_reg(Width::X, X11, TAPE_START);
+        asm.b_cond(Cond::Lo, label);
+    } else {
+        asm.cmp_reg(Width::X, X11, TAPE_END);
+        asm.b_cond(Cond::Hs, label);
+    }
+}
+
+/// Add the current cell times `factor` to the cell `x11` points at. Loads
+/// zero extend and stores truncate, so the product wraps at the cell width.
+fn mul_add(asm: &mut Assembler, factor: u64, width: CellWidth) {
+    let size = Size::from(width);
+    let reg_width = size.width();
+    let factor = width.truncate(factor);
+
+    asm.load(size, X9, PTR);
+    asm.load(size, X10, X11);
+    if factor == 1 {
+        asm.add_sub_reg(AddSub::Add, reg_width, X10, X10, X9);
+    } else if factor == width.truncate(u64::MAX) {
+        asm.add_sub_reg(AddSub::Sub, reg_width, X10, X10, X9);
+    } else {
+        asm.mov_imm(X12, factor);
+        asm.madd(reg_width, X10, X9, X12, X10);
+    }
+    asm.store(size, X10, X11);
+}
+
+/// Call the host function at `offset` in the `HostCalls` table
+fn host_call(asm: &mut Assembler, offset: usize) {
+    asm.mov_reg(X0, CALLS);
+    asm.load_offset(X16, CALLS, offset);
+    asm.blr(X16);
+}
+
+/// Code placed after the program, reached only on early exits
+enum OutOfLine {
+    /// Undo a pointer movement which failed its bounds check and return its
+    /// source offset plus one
+    PtrOob { label: Label, undo: AddSub, bytes: usize, status: usize },
+
+    /// Return the status of a `MulAdd`, `Add` or `Set` whose target, in
+    /// `x11`, failed its bounds check
+    TargetOob { label: Label, status: usize },
+
+    /// Store `value` at EOF and continue after the `,`
+    EofStore { label: Label, value: u64, resume: Label },
+
+    /// Return `status` at EOF
+    EofStop { label: Label, status: usize },
+}
+
+pub(super) fn assemble(program: &Program, options: &JitOptions) -> (Assembler, CodeMap) {
+    let mut asm = Assembler::new();
+    let mut map = CodeMap::new();
+
+    // Code taken when a bounds check fails or the input is exhausted, with
+    // the index of the operation it belongs to
+    let mut out_of_line = Vec::new();
+    let host_error = asm.new_label();
+
+    let cell_size = options.cell_width.bytes();
+    let size = Size::from(options.cell_width);
+
+    // Labels are named after the index of the operation they precede, so
+    // the resolved loop targets double as labels
+    let labels: Vec<Label> = (0..program.len()).map(|_| asm.new_label()).collect();
+
+    let mut bounds_checks = BoundsChecks::new(options);
+
+    map.push((asm.position(), CodeRegion::Prologue));
+    asm.mov_reg(LINK, X30);
+
+    for (idx, operation) in program.ops.iter().enumerate() {
+        map.push((asm.position(), CodeRegion::Op(idx)));
+        let check = bounds_checks.check(program, idx);
+
+        // Pointer movements are in bytes from here on
+        let operation = scale_to_bytes(*operation, cell_size);
+        match operation {
+            BfOperation::IncPtr(bytes) if check => {
+                let label = asm.new_label();
+                move_ptr(&mut asm, AddSub::Add, bytes);
+                asm.cmp_reg(Width::X, PTR, TAPE_END);
+                asm.b_cond(Cond::Hs, label);
+                out_of_line.push((idx, OutOfLine::PtrOob {
+                    label, undo: AddSub::Sub, bytes, status: program.offsets[idx] + 1,
+                }));
+            },
+            BfOperation::DecPtr(bytes) if check => {
+                let label = asm.new_label();
+                move_ptr(&mut asm, AddSub::Sub, bytes);
+                asm.cmp_reg(Width::X, PTR, TAPE_START);
+                asm.b_cond(Cond::Lo, label);
+                out_of_line.push((idx, OutOfLine::PtrOob {
+                    label, undo: AddSub::Add, bytes, status: program.offsets[idx] + 1,
+                }));
+            },
+            BfOperation::IncPtr(bytes) => move_ptr(&mut asm, AddSub::Add, bytes),
+            BfOperation::DecPtr(bytes) => move_ptr(&mut asm, AddSub::Sub, bytes),
+            BfOperation::IncData(times) => {
+                // Loads zero extend and stores truncate, so the arithmetic
+                // wraps at the cell width
+                data_op(&mut asm, AddSub::Add, PTR, times, options.cell_width);
+            },
+            BfOperation::DecData(times) => {
+                data_op(&mut asm, AddSub::Sub, PTR, times, options.cell_width);
+            },
+            BfOperation::WriteStdout => {
+                // Output the low byte of the cell, the first one as cells
+                // are little-endian
+                asm.load(Size::Byte, X1, PTR);
+                host_call(&mut asm, offset_of!(HostCalls, write));
+                asm.cbnz(Width::X, X0, host_error);
+            },
+            BfOperation::WriteConst(byte) => {
+                asm.mov_imm(X1, byte.into());
+                host_call(&mut asm, offset_of!(HostCalls, write));
+                asm.cbnz(Width::X, X0, host_error);
+            },
+            BfOperation::ReadStdin => {
+                host_call(&mut asm, offset_of!(HostCalls, read));
+
+                let resume = asm.new_label();
+                let eof_target = match options.eof_policy {
+                    EofPolicy::Unchanged => resume,
+                    EofPolicy::Zero | EofPolicy::MinusOne => {
+                        let label = asm.new_label();
+                        let value = if options.eof_policy == EofPolicy::Zero { 0 } else { u64::MAX };
+                        out_of_line.push((idx, OutOfLine::EofStore { label, value, resume }));
+                        label
+                    },
+                    EofPolicy::Stop => {
+                        let label = asm.new_label();
+                        let status = INPUT_EXHAUSTED | (program.offsets[idx] + 1);
+                        out_of_line.push((idx, OutOfLine::EofStop { label, status }));
+                        label
+                    },
+                };
+
+                // The byte comes back zero extended, storing the whole
+                // register clears the rest of a wide cell
+                asm.cmp_imm(Width::X, X0, HOST_EOF as u32);
+                asm.b_cond(Cond::Eq, eof_target);
+                asm.b_cond(Cond::Hi, host_error);
+                asm.store(size, X0, PTR);
+                asm.bind(resume);
+            },
+            BfOperation::SetZero => asm.store(size, XZR, PTR),
+            BfOperation::ScanRight(bytes) | BfOperation::ScanLeft(bytes) => {
+                // Test at the bottom, the loop only branches once per step
+                let (op, undo, bound, cond) = match operation {
+                    BfOperation::ScanRight(_) => (AddSub::Add, AddSub::Sub, TAPE_END, Cond::Hs),
+                    _ => (AddSub::Sub, AddSub::Add, TAPE_START, Cond::Lo),
+                };
+                let step = asm.new_label();
+                let test = asm.new_label();
+                asm.b(test);
+                asm.bind(step);
+                move_ptr(&mut asm, op, bytes);
+                if check {
+                    let label = asm.new_label();
+                    asm.cmp_reg(Width::X, PTR, bound);
+                    asm.b_cond(cond, label);
+                    out_of_line.push((idx, OutOfLine::PtrOob {
+                        label, undo, bytes, status: program.offsets[idx] + 1,
+                    }));
+                }
+                asm.bind(test);
+                asm.load(size, X9, PTR);
+                asm.cbnz(size.width(), X9, step);
+            },
+            BfOperation::MulAdd { offset, factor } => {
+                if check {
+                    let label = asm.new_label();
+                    check_target(&mut asm, offset, label);
+                    out_of_line.push((idx, OutOfLine::TargetOob {
+                        label, status: program.offsets[idx] + 1,
+                    }));
+                } else {
+                    target_address(&mut asm, offset);
+                }
+                mul_add(&mut asm, factor, options.cell_width);
+            },
+            BfOperation::Add { offset, delta } => {
+                if check {
+                    let label = asm.new_label();
+                    check_target(&mut asm, offset, label);
+                    out_of_line.push((idx, OutOfLine::TargetOob {
+                        label, status: program.offsets[idx] + 1,
+                    }));
+                } else {
+                    target_address(&mut asm, offset);
+                }
+                data_op(&mut asm, AddSub::Add, X11, delta, options.cell_width);
+            },
+            BfOperation::Set { offset, value } => {
+                if check {
+                    let label = asm.new_label();
+                    check_target(&mut asm, offset, label);
+                    out_of_line.push((idx, OutOfLine::TargetOob {
+                        label, status: program.offsets[idx] + 1,
+                    }));
+                } else {
+                    target_address(&mut asm, offset);
+                }
+                asm.mov_imm(X9, options.cell_width.truncate(value));
+                asm.store(size, X9, X11);
+            },
+            BfOperation::LoopStart(end) => {
+                asm.bind(labels[idx]);
+                asm.load(size, X9, PTR);
+                asm.cbz(size.width(), X9, labels[end]);
+            },
+            BfOperation::LoopEnd(start) => {
+                // Unconditionally jump back to the matching [ bracket.
+                asm.b(labels[start]);
+                asm.bind(labels[idx]);
+            },
+        }
+    }
+
+    map.push((asm.position(), CodeRegion::Exit));
+    asm.mov_imm(X0, 0);
+    asm.ret(LINK);
+
+    asm.bind(host_error);
+    asm.mov_imm(X0, HOST_ERROR as u64);
+    asm.ret(LINK);
+
+    for (idx, stub) in out_of_line {
+        map.push((asm.position(), CodeRegion::OutOfLine(idx)));
+        match stub {
+            OutOfLine::PtrOob { label, undo, bytes, status } => {
+                asm.bind(label);
+                asm.mov_reg(X1, PTR);
+                move_ptr(&mut asm, undo, bytes);
+                asm.mov_imm(X0, status as u64);
+                asm.ret(LINK);
+            },
+            OutOfLine::TargetOob { label, status } => {
+                asm.bind(label);
+                asm.mov_reg(X1, X11);
+                asm.mov_imm(X0, status as u64);
+                asm.ret(LINK);
+            },
+            OutOfLine::EofStore { label, value, resume } => {
+                asm.bind(label);
+                if value == 0 {
+                    asm.store(size, XZR, PTR);
+                } else {
+                    asm.mov_imm(X9, value);
+                    asm.store(size, X9, PTR);
+                }
+                asm.b(resume);
+            },
+            OutOfLine::EofStop { label, status } => {
+                asm.bind(label);
+                asm.mov_imm(X0, status as u64);
+                asm.ret(LINK);
+            },
+        }
+    }
+
+    (asm, map)
+}
@@ -0,0 +1,337 @@
+//! x86-64 code generator. The generated code expects the data pointer in
+//! `r13`, the first cell of the tape in `r14`, one past the last cell in
+//! `r15` and the `HostCalls` table in `r12`. It returns the status in `rax`
+//! and, on a failed bounds check, the out of bounds pointer in `rdx`.
+//!
+//! The code only moves `rsp` to align the stack around host calls, the guard
+//! page fault handler relies on `rsp` pointing at the return address
+//! whenever the tape is accessed.
+
+use super::{scale_to_bytes, BoundsChecks, CodeMap, CodeRegion, HostCalls, JitOptions};
+use super::{HOST_EOF, HOST_ERROR, INPUT_EXHAUSTED};
+use crate::cell::CellWidth;
+use crate::emu::EofPolicy;
+use crate::ir::{BfOperation, Program};
+use crate::x64::{Alu, Assembler, Cond, Label, Mem, Reg, Size};
+
+use std::convert::TryFrom;
+use std::mem::offset_of;
+
+/// The current cell, `[r13]`
+const CELL: Mem = Mem { base: Reg::R13, disp: 0 };
+
+/// Add or subtract `delta` to the cell at `mem`. Instructions only take a
+/// 32-bit immediate, so larger 64-bit deltas go through `rax`.
+fn data_op(asm: &mut Assembler, op: Alu, mem: Mem, delta: u64, width: CellWidth) {
+    let delta = width.truncate(delta);
+
+    if width == CellWidth::U64 && i32::try_from(delta as i64).is_err() {
+        asm.mov_reg_imm(Reg::Rax, delta);
+        asm.alu_mem_reg(op, Size::Qword, mem, Reg::Rax);
+    } else {
+        asm.alu_mem_imm(op, width.into(), mem, delta as i64);
+    }
+}
+
+/// Move the data pointer by `bytes`, which the encoder takes as a 32-bit
+/// immediate
+fn move_ptr(asm: &mut Assembler, op: Alu, bytes: usize) {
+    let bytes = i32::try_from(bytes).expect("pointer movement too large");
+    asm.alu_reg_imm(op, Reg::R13, bytes);
+}
+
+/// Compute the address of the cell `disp` bytes away in `rax` and jump to
+/// `label` if it is off the tape
+fn check_target(asm: &mut Assembler, disp: i32, label: Label) {
+    asm.mov_reg_reg(Reg::Rax, Reg::R13);
+    if disp < 0 {
+        asm.alu_reg_imm(Alu::Sub, Reg::Rax, -disp);
+        asm.cmp_reg_reg(Reg::Rax, Reg::R14);
+        asm.jcc(Cond::Below, label);
+    } else {
+        asm.alu_reg_imm(Alu::Add, Reg::Rax, disp);
+        asm.cmp_reg_reg(Reg::Rax, Reg::R15);
+        asm.jcc(Cond::AboveEqual, label);
+    }
+}
+
+/// Add the current cell times `factor` to the cell `disp` bytes away. The
+/// cell is loaded zero extended into `rax`, multiplied there and only its low
+/// `size` bits are added, so the product wraps at the cell width.
+fn mul_add(asm: &mut Assembler, disp: i32, factor: u64, width: CellWidth) {
+    let size = Size::from(width);
+    let target = Mem::new(Reg::R13, disp);
+    let factor = width.truncate(factor);
+
+    asm.movzx_reg_mem(size, Reg::Rax, CELL);
+    if factor == width.truncate(u64::MAX) {
+        asm.alu_mem_reg(Alu::Sub, size, target, Reg::Rax);
+        return;
+    }
+    if factor != 1 {
+        // Narrow cells only keep the low bits, which the sign extended
+        // immediate gets right
+        match i32::try_from(factor as i64) {
+            Ok(imm) => asm.imul_reg_imm(Reg::Rax, Reg::Rax, imm),
+            Err(_) if width != CellWidth::U64 => {
+                asm.imul_reg_imm(Reg::Rax, Reg::Rax, factor as u32 as i32);
+            },
+            Err(_) => {
+                asm.mov_reg_imm(Reg::Rcx, factor);
+                asm.imul_reg_reg(Reg::Rax, Reg::Rcx);
+            },
+        }
+    }
+    asm.alu_mem_reg(Alu::Add, size, target, Reg::Rax);
+}
+
+/// Call the host function at `offset` in the `HostCalls` table. The stack is
+/// 16-byte aligned around the call as the C ABI requires.
+fn host_call(asm: &mut Assembler, offset: usize) {
+    asm.mov_reg_reg(Reg::Rdi, Reg::R12);
+    asm.alu_reg_imm(Alu::Sub, Reg::Rsp, 8);
+    asm.call_mem(Mem::new(Reg::R12, offset as i32));
+    asm.alu_reg_imm(Alu::Add, Reg::Rsp, 8);
+}
+
+/// Code placed after the program, reached only on early exits
+enum OutOfLine {
+    /// Undo a pointer movement which failed its bounds check and return its
+    /// source offset plus one
+    PtrOob { label: Label, undo: Alu, bytes: usize, status: usize },
+
+    /// Return the status of a `MulAdd`, `Add` or `Set` whose target, in
+    /// `rax`, failed its bounds check
+    TargetOob { label: Label, status: usize },
+
+    /// Store `value` at EOF and continue after the `,`
+    EofStore { label: Label, value: i64, resume: Label },
+
+    /// Return `status` at EOF
+    EofStop { label: Label, status: usize },
+}
+
+pub(super) fn assemble(program: &Program, options: &JitOptions) -> (Assembler, CodeMap) {
+    let mut asm = Assembler::new();
+    let mut map = CodeMap::new();
+
+    // Code taken when a bounds check fails or the input is exhausted, with
+    // the index of the operation it belongs to
+    let mut out_of_line = Vec::new();
+    let host_error = asm.new_label();
+
+    let cell_size = options.cell_width.bytes();
+    let size = Size::from(options.cell_width);
+
+    // Labels are named after the index of the operation they precede, so
+    // the resolved loop targets double as labels
+    let labels: Vec<Label> = (0..program.len()).map(|_| asm.new_label()).collect();
+
+    let mut bounds_checks = BoundsChecks::new(options);
+
+    for (idx, operation) in program.ops.iter().enumerate() {
+        map.push((asm.position(), CodeRegion::Op(idx)));
+        let check = bounds_checks.check(program, idx);
+
+        // Pointer movements are in bytes from here on
+        let operation = scale_to_bytes(*operation, cell_size);
+        match operation {
+            BfOperation::IncPtr(bytes) if check => {
+                let label = asm.new_label();
+                move_ptr(&mut asm, Alu::Add, bytes);
+                asm.cmp_reg_reg(Reg::R13, Reg::R15);
+                asm.jcc(Cond::AboveEqual, label);
+                out_of_line.push((idx, OutOfLine::PtrOob {
+                    label, undo: Alu::Sub, bytes, status: program.offsets[idx] + 1,
+                }));
+            },
+            BfOperation::DecPtr(bytes) if check => {
+                let label = asm.new_label();
+                move_ptr(&mut asm, Alu::Sub, bytes);
+                asm.cmp_reg_reg(Reg::R13, Reg::R14);
+                asm.jcc(Cond::Below, label);
+                out_of_line.push((idx, OutOfLine::PtrOob {
+                    label, undo: Alu::Add, bytes, status: program.offsets[idx] + 1,
+                }));
+            },
+            BfOperation::IncPtr(1) => asm.inc(Reg::R13),
+            BfOperation::IncPtr(bytes) => {
+                // Increment the data pointer to the next cell
+                move_ptr(&mut asm, Alu::Add, bytes);
+            },
+            BfOperation::DecPtr(1) => asm.dec(Reg::R13),
+            BfOperation::DecPtr(bytes) => {
+                // Decrement the data pointer to point to the previous cell
+                move_ptr(&mut asm, Alu::Sub, bytes);
+            },
+            BfOperation::IncData(times) => {
+                // The operand must match the cell width, a wider one would
+                // carry into the neighbouring cells
+                data_op(&mut asm, Alu::Add, CELL, times, options.cell_width);
+            },
+            BfOperation::DecData(times) => {
+                // Decrement the value at data pointer.
+                data_op(&mut asm, Alu::Sub, CELL, times, options.cell_width);
+            },
+            BfOperation::WriteStdout => {
+                // Output the low byte of the cell at the data pointer, cells
+                // are little-endian so it is the first one.
+                asm.movzx_reg_mem8(Reg::Rsi, CELL);
+                host_call(&mut asm, offset_of!(HostCalls, write));
+                asm.test_reg_reg(Reg::Rax, Reg::Rax);
+                asm.jcc(Cond::NotEqual, host_error);
+            },
+            BfOperation::WriteConst(byte) => {
+                asm.mov_reg_imm(Reg::Rsi, byte.into());
+                host_call(&mut asm, offset_of!(HostCalls, write));
+                asm.test_reg_reg(Reg::Rax, Reg::Rax);
+                asm.jcc(Cond::NotEqual, host_error);
+            },
+            BfOperation::ReadStdin => {
+                // Input one byte and store its value at the data pointer.
+                host_call(&mut asm, offset_of!(HostCalls, read));
+
+                let resume = asm.new_label();
+                let eof_target = match options.eof_policy {
+                    EofPolicy::Unchanged => resume,
+                    EofPolicy::Zero | EofPolicy::MinusOne => {
+                        let label = asm.new_label();
+                        let value = if options.eof_policy == EofPolicy::Zero { 0 } else { -1 };
+                        out_of_line.push((idx, OutOfLine::EofStore { label, value, resume }));
+                        label
+                    },
+                    EofPolicy::Stop => {
+                        let label = asm.new_label();
+                        let status = INPUT_EXHAUSTED | (program.offsets[idx] + 1);
+                        out_of_line.push((idx, OutOfLine::EofStop { label, status }));
+                        label
+                    },
+                };
+
+                // The byte comes back zero extended, storing the whole
+                // accumulator clears the rest of a wide cell
+                asm.alu_reg_imm(Alu::Cmp, Reg::Rax, HOST_EOF as i32);
+                asm.jcc(Cond::Equal, eof_target);
+                asm.jcc(Cond::Above, host_error);
+                asm.mov_mem_reg(size, CELL, Reg::Rax);
+                asm.bind(resume);
+            },
+            BfOperation::SetZero => asm.mov_mem_imm(size, CELL, 0),
+            BfOperation::ScanRight(bytes) | BfOperation::ScanLeft(bytes) => {
+                // Test at the bottom, the loop only branches once per step
+                let (op, undo, bound, cond) = match operation {
+                    BfOperation::ScanRight(_) => (Alu::Add, Alu::Sub, Reg::R15, Cond::AboveEqual),
+                    _ => (Alu::Sub, Alu::Add, Reg::R14, Cond::Below),
+                };
+                let step = asm.new_label();
+                let test = asm.new_label();
+                asm.jmp(test);
+                asm.bind(step);
+                move_ptr(&mut asm, op, bytes);
+                if check {
+                    let label = asm.new_label();
+                    asm.cmp_reg_reg(Reg::R13, bound);
+                    asm.jcc(cond, label);
+                    out_of_line.push((idx, OutOfLine::PtrOob {
+                        label, undo, bytes, status: program.offsets[idx] + 1,
+                    }));
+                }
+                asm.bind(test);
+                asm.alu_mem_imm(Alu::Cmp, size, CELL, 0);
+                asm.jcc(Cond::NotEqual, step);
+            },
+            BfOperation::MulAdd { offset, factor } => {
+                let disp = i32::try_from(offset).expect("multiply offset too large");
+                if check {
+                    let label = asm.new_label();
+                    check_target(&mut asm, disp, label);
+                    out_of_line.push((idx, OutOfLine::TargetOob {
+                        label, status: program.offsets[idx] + 1,
+                    }));
+                }
+                mul_add(&mut asm, disp, factor, options.cell_width);
+            },
+            BfOperation::Add { offset, delta } => {
+                let disp = i32::try_from(offset).expect("cell offset too large");
+                if check {
+                    let label = asm.new_label();
+                    check_target(&mut asm, disp, label);
+                    out_of_line.push((idx, OutOfLine::TargetOob {
+                        label, status: program.offsets[idx] + 1,
+                    }));
+                }
+                // `[r13+disp]`, the pointer itself only moves at the end of
+                // the run
+                let target = Mem::new(Reg::R13, disp);
+                data_op(&mut asm, Alu::Add, target, delta, options.cell_width);
+            },
+            BfOperation::Set { offset, value } => {
+                let disp = i32::try_from(offset).expect("cell offset too large");
+                if check {
+                    let label = asm.new_label();
+                    check_target(&mut asm, disp, label);
+                    out_of_line.push((idx, OutOfLine::TargetOob {
+                        label, status: program.offsets[idx] + 1,
+                    }));
+                }
+                let target = Mem::new(Reg::R13, disp);
+                let value = options.cell_width.truncate(value);
+                if options.cell_width != CellWidth::U64 || i32::try_from(value as i64).is_ok() {
+                    asm.mov_mem_imm(size, target, value as i64);
+                } else {
+                    asm.mov_reg_imm(Reg::Rax, value);
+                    asm.mov_mem_reg(size, target, Reg::Rax);
+                }
+            },
+            BfOperation::LoopStart(end) => {
+                asm.bind(labels[idx]);
+                asm.alu_mem_imm(Alu::Cmp, size, CELL, 0);
+                asm.jcc(Cond::Equal, labels[end]);
+            },
+            BfOperation::LoopEnd(start) => {
+                // Unconditionally jump back to the matching [ bracket.
+                asm.jmp(labels[start]);
+                asm.bind(labels[idx]);
+            },
+        }
+    }
+
+    map.push((asm.position(), CodeRegion::Exit));
+    asm.zero(Reg::Rax);
+    asm.ret();
+
+    asm.bind(host_error);
+    asm.mov_reg_imm(Reg::Rax, HOST_ERROR as u64);
+    asm.ret();
+
+    for (idx, stub) in out_of_line {
+        map.push((asm.position(), CodeRegion::OutOfLine(idx)));
+        match stub {
+            OutOfLine::PtrOob { label, undo, bytes, status } => {
+                asm.bind(label);
+                asm.mov_reg_reg(Reg::Rdx, Reg::R13);
+                move_ptr(&mut asm, undo, bytes);
+                asm.mov_reg_imm(Reg::Rax, status as u64);
+                asm.ret();
+            },
+            OutOfLine::TargetOob { label, status } => {
+                asm.bind(label);
+                asm.mov_reg_reg(Reg::Rdx, Reg::Rax);
+                asm.mov_reg_imm(Reg::Rax, status as u64);
+                asm.ret();
+            },
+            OutOfLine::EofStore { label, value, resume } => {
+                asm.bind(label);
+                asm.mov_mem_imm(size, CELL, value);
+                asm.jmp(resume);
+            },
+            OutOfLine::EofStop { label, status } => {
+                asm.bind(label);
+                asm.mov_reg_imm(Reg::Rax, status as u64);
+                asm.ret();
+            },
+        }
+    }
+
+    (asm, map)
+}
@@ -0,0 +1,77 @@
+//! BrainfuckRVM: a Brainfuck interpreter with a JIT
+//!
+//! The crate exposes the whole engine so it can be embedded in other
+//! programs. The three entry points are [`parse`], [`compile`] and [`run`]:
+//!
+//! ```no_run
+//! use brainfuck_rvm::{Engine, VmExit};
+//!
+//! let program = brainfuck_rvm::parse("++[->+<]").unwrap();
+//! assert_eq!(program.len(), 8);
+//! assert_eq!(program.fold().len(), 7);
+//!
+//! let source = "++++++++[>++++++<-]>.";
+//! match brainfuck_rvm::run(source, Engine::Vm3, 30000) {
+//!     Ok(Some(VmExit::Exit(elapsed))) => println!("done in {}s", elapsed),
+//!     Ok(Some(VmExit::PtrOob { ptr, .. })) => println!("pointer {} out of bounds", ptr),
+//!     Ok(Some(VmExit::InputExhausted { .. })) | Ok(None) => {}
+//!     Err(err) => eprint!("{}", err.render("<inline>", source)),
+//! }
+//! ```
+//!
+//! For finer control create an [`Emu`] directly and optionally attach a
+//! shared [`JitCache`] with [`Emu::enable_jit`]. Program I/O defaults to
+//! stdin and stdout, [`Emu::with_input`] and [`Emu::with_output`] take any
+//! [`BfInput`] and [`BfOutput`], e.g. a byte slice and a [`SharedBuffer`].
+
+pub mod a64;
+pub mod aot;
+pub mod bytecode;
+pub mod cell;
+pub mod disasm;
+pub mod emu;
+pub mod io;
+pub mod ir;
+pub mod jit;
+pub mod jitcache;
+pub mod optimize;
+pub mod tape;
+pub mod transpile;
+pub mod wasm;
+pub mod x64;
+
+pub use crate::cell::CellWidth;
+pub use crate::emu::{Emu, Engine, EofPolicy, VmExit};
+pub use crate::io::{BfInput, BfOutput, SharedBuffer};
+pub use crate::ir::{BfOperation, ParseError, ParseWarning, Program, Span};
+pub use crate::jitcache::JitCache;
+pub use crate::optimize::{Optimizer, Pass};
+pub use crate::tape::Tape;
+
+use std::sync::Arc;
+
+/// Print every executed operation of the interpreters
+pub const DEBUG_ENABLED: bool = false;
+
+/// Parse Brainfuck source into the shared intermediate representation
+pub fn parse(source: &str) -> Result<Program, ParseError> {
+    Program::parse(source)
+}
+
+/// Compile Brainfuck source into machine code for the host architecture,
+/// x86-64 or AArch64. See the `jit` module for the register convention.
+pub fn compile(source: &str) -> Result<Vec<u8>, ParseError> {
+    let program = Program::parse(source)?;
+    Ok(jit::generate_jit_opt(&program, &jit::JitOptions::default())
+        .expect("could not generate machine code"))
+}
+
+/// Run Brainfuck source on a fresh tape of `tape_size` cells with `engine`
+pub fn run(source: &str, engine: Engine, tape_size: usize)
+        -> Result<Option<VmExit>, ParseError> {
+    let mut emu = Emu::new(tape_size);
+    if engine.is_jit() {
+        emu = emu.enable_jit(Arc::new(JitCache::new(1024 * 1024)));
+    }
+    emu.run_engine(engine, source)
+}
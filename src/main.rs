@@ -1,976 +1,493 @@
-pub mod jitcache;
-use crate::jitcache::JitCache;
-
-use std::{collections::HashMap, io, sync::{Arc}, time::Instant};
-use io::{Write, Read};
-extern crate regex;
-use regex::Regex;
-use std::arch::asm;
-
-extern crate keystone;
-use keystone::{Arch, Keystone, MODE_64, OPT_SYNTAX_INTEL, OptionType};
-
-pub const DEBUG_ENABLED: bool = false;
-
-fn key_to_continue() {
-    let mut reader = io::stdin();
-    let mut buffer = [0;1];  // read exactly one byte
-    reader.read_exact(&mut buffer).unwrap();
-}
-
-extern "win64" fn foo() -> u32 { 1 }
-
-/// Reasons why the VM exited
-pub enum VmExit {
-    /// The VM exited due to a PTR OOB
-    PtrOob,
-
-    /// The VM exited cleanly as requested by the code.
-    Exit(f64),    
+use brainfuck_rvm::aot::{generate_elf, AotOptions};
+use brainfuck_rvm::bytecode::Bytecode;
+use brainfuck_rvm::disasm::jit_listing;
+use brainfuck_rvm::jit::{JitOptions, Target};
+use brainfuck_rvm::transpile::{generate_c, generate_rust};
+use brainfuck_rvm::wasm::generate_wasm;
+use brainfuck_rvm::{CellWidth, Emu, Engine, EofPolicy, JitCache, Optimizer, Pass, Program, Span, VmExit};
+
+use std::{env, fs, fs::File, io, process, sync::Arc};
+use io::{BufReader, Write, Read};
+
+fn usage() -> ! {
+    eprintln!(r#"BrainfuckRVM: a Brainfuck Interpreter.
+
+Usage: BrainfuckRVm [options] <program.bf | program.bfc | ->
+
+Options:
+    -e, --engine <name>   vm, vm2, vm3, bytecode, jit or jitopt (default:
+                          jitopt, bytecode on hosts without a JIT)
+    -O<level>             optimization level of vm3, bytecode, jitopt and
+                          compiled programs, 0 to 3 (default: 2)
+        --no-pass <name>  skip an optimization pass: fold, idioms, offsets
+                          or constants
+        --pass-stats      print what every optimization pass changed to
+                          stderr
+    -t, --tape <cells>    number of tape cells (default: 30000)
+    -c, --cell-width <bits>
+                          8, 16, 32 or 64-bit cells (default: 8)
+    -i, --input <path>    file used for `,` input, `-` for stdin (default: -)
+    -u, --unbuffered      flush the output after every `.`
+        --eof <policy>    what `,` does at the end of the input: unchanged,
+                          zero, minus-one or stop (default: unchanged)
+        --checked         bounds check every pointer movement in JIT code
+                          and compiled programs (default for the JIT where
+                          there are no guard pages)
+        --guard-pages     put the tape between guard pages which stop
+                          unchecked JIT code, x86-64 Linux only; elsewhere
+                          the JIT checks every movement instead (default
+                          for the JIT)
+        --unchecked       run JIT code without bounds checks or guard
+                          pages, a program leaving the tape touches host
+                          memory
+        --time            print the execution time to stderr
+        --strict          warn about characters that are not commands
+        --dump-tape <path>
+                          write the final tape to a file
+    -o, --output <path>   compile the program instead of running it, `-` for
+                          stdout
+        --emit <kind>     what to compile to: elf, a standalone x86-64 Linux
+                          executable, c or rust source, a wasm module, or
+                          bytecode which runs without parsing and
+                          optimizing; ir lists the optimized IR and asm the
+                          disassembled JIT code, both with source positions
+                          (default: elf)
+        --target <arch>   architecture of `--emit asm`: x86_64 or aarch64
+                          (default: the host's)
+    -h, --help            show this message
+
+Exit codes:
+    0   the program ran to completion
+    1   usage or I/O error
+    2   the data pointer left the tape (PtrOob)
+    3   the program could not be parsed or loaded
+    4   `,` stopped the program at the end of the input (--eof stop)"#);
+    process::exit(1)
 }
 
-fn rdtsc() -> u64 {
-    unsafe { std::arch::x86_64::_rdtsc() }
+/// Compilation targets of `--emit`
+#[derive(Clone, Copy, PartialEq)]
+enum Emit {
+    Elf,
+    C,
+    Rust,
+    Wasm,
+    Ir,
+    Asm,
+    Bytecode,
 }
 
-pub struct Emu {
-    pub memory: Vec<u8>,
-    pub ptr: usize,
-
-    jit_cache: Option<Arc<JitCache>>,
-}
-
-enum BfOperation {
-    INVALID_OP,
-    INC_PTR(usize),
-    DEC_PTR(usize),
-    INC_DATA(u8),
-    DEC_DATA(u8),
-    READ_STDIN,
-    WRITE_STDOUT,
-    LOOP_START,
-    LOOP_END,
-}
-
-impl Emu {
-    pub fn new(size: usize) -> Self {
-        Emu {
-            memory: vec![0u8; size],
-            ptr: 0,
-            jit_cache: None,
-        }
-    }
-
-    // Enable the JIT
-    pub fn enable_jit(mut self, jit_cache: Arc<JitCache>) -> Self {
-        self.jit_cache = Some(jit_cache);
-        self
-    }
-
-    fn receive_input(&self) -> u8 {
-        let mut reader = io::stdin();
-        let mut buffer = [0;1];  // read exactly one byte
-        reader.read_exact(&mut buffer).unwrap();
-        //return char::from(buffer[0]);
-        return buffer[0];
-    }
-
-    // Run the VM using either the emulator or the JIT
-    pub fn run(&mut self, instructions: String) -> Option<VmExit> {
-        if let Some(jit_cache) = &self.jit_cache {
-            self.run_jit(instructions)
-        } else {
-            self.run_vm3(instructions)
+impl Emit {
+    fn from_name(name: &str) -> Option<Emit> {
+        match name {
+            "elf"      => Some(Emit::Elf),
+            "c"        => Some(Emit::C),
+            "rust"     => Some(Emit::Rust),
+            "wasm"     => Some(Emit::Wasm),
+            "ir"       => Some(Emit::Ir),
+            "asm"      => Some(Emit::Asm),
+            "bytecode" => Some(Emit::Bytecode),
+            _          => None,
         }
     }
+}
 
-    pub fn run_jit(&mut self, instructions: String) -> Option<VmExit> {
-        let jit_cache = self.jit_cache.as_ref().unwrap();
-        println!("{:?}", self.jit_cache);
-
-        let start = Instant::now();
-
-        //match self.generate_jit(instructions) {
-        match self.generate_jit_opt(instructions) {
-            Ok(machine_code) => {
-                let jitted_addr = jit_cache.add_mapping(0, &machine_code);
-
-                unsafe {
-                    asm!(r#"
-                       call {entry}                       
-                    "#,
-                    entry = in(reg) jitted_addr,
-                    in("r13") self.memory.as_ptr() as usize);            
-                }
+/// Command line options
+struct Options {
+    program: String,
+    /// `None` picks the default engine for the program
+    engine: Option<Engine>,
+    optimizer: Optimizer,
+    pass_stats: bool,
+    tape_size: usize,
+    cell_width: CellWidth,
+    input: Option<String>,
+    eof_policy: EofPolicy,
+    unbuffered: bool,
+    time: bool,
+    strict: bool,
+    dump_tape: Option<String>,
+    checked: bool,
+    guard_pages: bool,
+    unchecked: bool,
+    output: Option<String>,
+    emit: Option<Emit>,
+    target: Target,
+}
 
+fn parse_args() -> Options {
+    let mut args = env::args().skip(1);
+    let mut program = None;
+    let mut engine = None;
+    let mut optimizer = Optimizer::default();
+    let mut skipped = Vec::new();
+    let mut pass_stats = false;
+    let mut tape_size = 30000;
+    let mut cell_width = CellWidth::U8;
+    let mut input = None;
+    let mut eof_policy = EofPolicy::Unchanged;
+    let mut unbuffered = false;
+    let mut time = false;
+    let mut strict = false;
+    let mut dump_tape = None;
+    let mut checked = false;
+    let mut guard_pages = false;
+    let mut unchecked = false;
+    let mut output = None;
+    let mut emit = None;
+    let mut target = Target::native();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-e" | "--engine" => {
+                let name = args.next().unwrap_or_else(|| usage());
+                engine = match Engine::from_name(&name) {
+                    Some(engine) => Some(engine),
+                    None => {
+                        eprintln!("unknown engine `{}`", name);
+                        usage()
+                    }
+                };
             },
-            Err(_) => {
-                panic!("error generating machine code!")
-            }
-        }
-
-        let elapsed = start.elapsed().as_secs_f64();
-        Some(VmExit::Exit(elapsed))
-    }
-
-    pub fn generate_jit_opt(&self,instructions: String) -> Result<Vec<u8>, VmExit> {
-        let mut idx: usize = 0;
-
-        let mut bfInstructions = Vec::<BfOperation>::new();
-        let re = Regex::new(r#"[\+]+|[-]+|[>]+|[<]+|[\[]|[\]]|[\.]|[,]"#).unwrap();
-        for cap in re.captures_iter(&instructions) {
-            match &cap[0].chars().nth(0).unwrap() {
-                '>' => bfInstructions.push(BfOperation::INC_PTR(*&cap[0].len())),
-                '<' => bfInstructions.push(BfOperation::DEC_PTR(*&cap[0].len())),
-                '+' => bfInstructions.push(BfOperation::INC_DATA(*&cap[0].len() as u8)),
-                '-' => bfInstructions.push(BfOperation::DEC_DATA(*&cap[0].len() as u8)),
-                '.' => bfInstructions.push(BfOperation::WRITE_STDOUT),
-                ',' => bfInstructions.push(BfOperation::READ_STDIN),
-                '[' => bfInstructions.push(BfOperation::LOOP_START),
-                ']' => bfInstructions.push(BfOperation::LOOP_END),
-                _ => {
-                    unreachable!("xxx");
-                }
-            }            
-        }
-
-        let mut asm = String::new();
-
-        let engine = Keystone::new(Arch::X86, keystone::MODE_64)
-            .expect("Could not initialize keystone engine");
-        engine.option(OptionType::SYNTAX, keystone::OPT_SYNTAX_INTEL)
-            .expect("Could not set option to intel syntax");
-
-        idx = 0;       
-
-        let mut labels: u64 = 0;
-        let mut forward_labels = Vec::<u64>::new();
-        let mut backward_labels = Vec::<u64>::new();
-            
-        while idx < bfInstructions.len() {
-            let operation = bfInstructions.get(idx).unwrap();
-            // Decode operator
-            match operation {
-                BfOperation::INC_PTR(times) => {
-                    // Increment the data pointer to the next cell
-                    asm += &format!(r#"
-                        add r13, 0x{:x};
-                    "#, times);                   
-                },
-                BfOperation::DEC_PTR(times) => {
-                    // Decrement the data pointer to point to the previous cell      
-                    asm += &format!(r#"
-                        sub r13, 0x{:x};
-                    "#, times);                  
-                },
-                BfOperation::INC_DATA(times) => {
-                    asm += &format!(r#"
-                        add qword ptr ds:[r13], 0x{:x};
-                    "#, times);              
-                },
-                BfOperation::DEC_DATA(times) => {
-                    // Decrement the byte value at data pointer.
-                    asm += &format!(r#"
-                        sub qword ptr ds:[r13], 0x{:x};
-                    "#, times);  
-                },
-                BfOperation::WRITE_STDOUT => {
-                    // Output the byte value at the data pointer.
-                    asm += &format!(r#"
-                        mov rax, 1;
-                        mov rdi, 1;
-                        mov rsi, r13;
-                        mov rdx, 1;                       
-                        syscall;
-                    "#);                         
-                },
-                BfOperation::READ_STDIN => {
-                    // Input one byte and store its value at the data pointer.
-                    asm += &format!(r#"
-                        mov rax, 0;
-                        mov rdi, 0;
-                        mov rsi, r13;
-                        mov rdx, 1;
-                        syscall;
-                    "#);                
-                },
-                BfOperation::LOOP_START => {
-                    asm += &format!(r#"
-                        label{}:
-                        "#, labels);
-
-                    backward_labels.push(labels);
-                    labels += 1;
-
-                    asm += &format!(r#"
-                        cmp byte ptr ds:[r13], 0;                        
-                    "#);
-                    
-                    asm += &format!(r#"
-                        jz label{};                        
-                    "#, labels);
-                    forward_labels.push(labels);
-                    labels += 1;
-                },
-                BfOperation::LOOP_END => {
-                    // Unconditionally jump back to the matching [ bracket.
-                    asm += &format!(r#"
-                        jmp label{};
-                        label{}:
-                    "#, backward_labels.pop().unwrap(),
-                        forward_labels.pop().unwrap()
-                    ); 
-                },
-                _ => { panic!("unrecognized token at position {}", idx) }
-            }
-
-            idx += 1;
-        }   
-        
-        asm += &format!(r#"            
-            ret;
-        "#);
-   
-        let result = engine.asm(asm.to_string(), 0)
-        .expect(&format!("could not assemble:\n{}", asm)); 
-
-        Ok(result.bytes)
-    }
-
-
-    /// JIT The stuff up
-    pub fn generate_jit(&self,instructions: String) -> Result<Vec<u8>, VmExit> {
-        let mut asm = String::new();
-
-        let engine = Keystone::new(Arch::X86, keystone::MODE_64)
-            .expect("Could not initialize keystone engine");
-        engine.option(OptionType::SYNTAX, keystone::OPT_SYNTAX_INTEL)
-            .expect("Could not set option to intel syntax");
-
-        let mut idx: usize = 0;
-
-        let mut labels: u64 = 0;
-        let mut forward_labels = Vec::<u64>::new();
-        let mut backward_labels = Vec::<u64>::new();
-        let instructions = instructions.as_bytes();
-        while idx < instructions.len() {
-            let operation = instructions.get(idx).unwrap();
-            // Decode operator
-            match operation {
-                b'>' => {                    
-                    // inc %r13
-                    asm += &format!(r#"
-                        inc r13;
-                    "#);
-                    // 0x49, 0xFF, 0xC5  
-                },
-                b'<' => {                    
-                    asm += &format!(r#"
-                        dec r13;
-                    "#);                    
-                },
-                b'+' => {                    
-                    // addb $1, 0(%r13)
-                    asm += &format!(r#"
-                        add qword ptr ds:[r13], 1;
-                    "#);      
-                },
-                b'-' => {
-                    // Decrement the byte value at data pointer.
-                    asm += &format!(r#"
-                        sub qword ptr ds:[r13], 1;
-                    "#);                    
-                },
-                b'.' => {
-                    // Output the byte value at the data pointer.
-                   
-                    asm += &format!(r#"
-                        mov rax, 1;
-                        mov rdi, 1;
-                        mov rsi, r13;
-                        mov rdx, 1;
-                        syscall;
-                    "#);                     
-                },
-                b',' => {
-                    asm += &format!(r#"
-                        mov rax, 0;
-                        mov rdi, 0;
-                        mov rsi, r13;
-                        mov rdx, 1;
-                        syscall;
-                    "#);      
-                },
-                b'[' => {
-                    asm += &format!(r#"
-                        label{}:
-                        "#, labels);
-
-                    backward_labels.push(labels);
-                    labels += 1;
-
-                    asm += &format!(r#"
-                        cmp byte ptr ds:[r13], 0;                        
-                    "#);
-                    
-                    asm += &format!(r#"
-                        jz label{};                        
-                    "#, labels);
-                    forward_labels.push(labels);
-                    labels += 1;
-
-                },
-                b']' => {
-                    // Unconditionally jump back to the matching [ bracket.
-                    asm += &format!(r#"
-                        jmp label{};
-                        label{}:
-                    "#, backward_labels.pop().unwrap(),
-                        forward_labels.pop().unwrap()
-                    );                    
-                  
-                },
-                _ => { panic!("unrecognized token at position {}", idx) }
-            }
-
-                idx += 1;
-            }
-
-            asm += &format!(r#"
-               ret;
-            "#);
-            
-            let result = engine.asm(asm.to_string(), 0)
-            .expect(&format!("could not assemble:\n{}", asm));            
-
-            Ok(result.bytes)
-    }
-    
-
-    pub fn run_vm(&mut self, instructions: String) -> Option<VmExit> {
-        // flag to indicate that we need to scan for the matching `]`
-        let mut scan_loop_end = false;
-        /// loop nesting consideration
-        let mut nested_depth = 0u32;
-
-        /// used to keep track of [] loops
-        //let mut loop_positions = Vec::<usize>::new();
-        let mut start_loop_positions = Vec::<usize>::new();        
-
-        let mut idx: usize = 0;
-
-        // start a timer
-        let start = Instant::now();
-            
-        let instructions = instructions.as_bytes();
-        while idx < instructions.len() {
-            let operation = instructions.get(idx).unwrap();
-            // Decode operator
-            match operation {
-                b'>' => {
-                    // Increment the data pointer to the next cell
-                    if scan_loop_end == false {
-                        if (self.ptr + 1) >= self.memory.len() {
-                            return Some(VmExit::PtrOob);
-                        }
-                        self.ptr += 1;
-                        if DEBUG_ENABLED {
-                            println!("Executed Op: > at pos {} - ptr: {}", idx, self.ptr);                             
-                        }                    
+            "--no-pass" => {
+                let name = args.next().unwrap_or_else(|| usage());
+                skipped.push(match Pass::from_name(&name) {
+                    Some(pass) => pass,
+                    None => {
+                        eprintln!("unknown optimization pass `{}`", name);
+                        usage()
                     }
-                },
-                b'<' => {
-                    // Decrement the data pointer to point to the previous cell       
-                    if scan_loop_end == false {
-                        if self.ptr == 0 {
-                            return Some(VmExit::PtrOob);
-                        } 
-                        self.ptr -= 1;
-                        if DEBUG_ENABLED {
-                            println!("Executed Op: < at pos {} - ptr: {}", idx, self.ptr);                     
-                        }
+                });
+            },
+            "--pass-stats" => pass_stats = true,
+            "-t" | "--tape" => {
+                let cells = args.next().unwrap_or_else(|| usage());
+                tape_size = match cells.parse::<usize>() {
+                    Ok(cells) if cells > 0 => cells,
+                    _ => {
+                        eprintln!("invalid tape size `{}`", cells);
+                        usage()
                     }
-
-                },
-                b'+' => {
-                    // Increment the byte value at data pointer
-                    if scan_loop_end == false {
-                        self.memory[self.ptr] += 1;
-                        if DEBUG_ENABLED {
-                            println!("Executed Op: + at pos {} - ptr: {}", idx, self.ptr); 
-                        
-                        }   
-                    }             
-                },
-                b'-' => {
-                    // Decrement the byte value at data pointer.
-                    if scan_loop_end == false {
-                        self.memory[self.ptr] -= 1;
-                        if DEBUG_ENABLED {
-                            println!("Executed Op: - at pos {} - ptr: {}", idx, self.ptr);   
-                        }
+                };
+            },
+            "-c" | "--cell-width" => {
+                let bits = args.next().unwrap_or_else(|| usage());
+                cell_width = match bits.parse().ok().and_then(CellWidth::from_bits) {
+                    Some(cell_width) => cell_width,
+                    None => {
+                        eprintln!("invalid cell width `{}`", bits);
+                        usage()
                     }
-                },
-                b'.' => {
-                    // Output the byte value at the data pointer.
-                   
-                    if scan_loop_end == false {
-                        print!("{}", char::from(self.memory[self.ptr]));
-                        io::stdout().flush().ok().expect("Could not flush stdout");
-                        if DEBUG_ENABLED {
-                            println!("Executed Op: . at pos {} - ptr: {}", idx, self.ptr); 
-                        }
-                    }                          
-                },
-                b',' => {
-                    // Input one byte and store its value at the data pointer.
-                    if scan_loop_end == false {
-                        self.memory[self.ptr] = self.receive_input();
-
-                        if DEBUG_ENABLED {
-                            println!("Executed Op: , at pos {} - ptr: {}", idx, self.ptr);  
-                        }
+                };
+            },
+            "-i" | "--input" => {
+                input = Some(args.next().unwrap_or_else(|| usage()));
+            },
+            "--eof" => {
+                let name = args.next().unwrap_or_else(|| usage());
+                eof_policy = match EofPolicy::from_name(&name) {
+                    Some(eof_policy) => eof_policy,
+                    None => {
+                        eprintln!("unknown EOF policy `{}`", name);
+                        usage()
                     }
-               },
-                b'[' => {
-                    // If the byte value at the data pointer is zero,
-                    // jump to the instruction following the matching ] bracket.
-                    // Otherwise, continue execution.            
-
-                    if self.memory[self.ptr] == 0  {                        
-                        /*if let Some(pos) = end_loop_positions.pop() {                          
-                            idx = pos + 1;
-                            if idx >= instructions.len() {
-                                break;
-                            }
-                            continue;
-                        }
-                        else {
-                        */
-                        scan_loop_end = true;
-
-                        //}
+                };
+            },
+            "-u" | "--unbuffered" => unbuffered = true,
+            "--time" => time = true,
+            "--strict" => strict = true,
+            "--checked" => checked = true,
+            "--guard-pages" => guard_pages = true,
+            "--unchecked" => unchecked = true,
+            "--dump-tape" => {
+                dump_tape = Some(args.next().unwrap_or_else(|| usage()));
+            },
+            "-o" | "--output" => {
+                output = Some(args.next().unwrap_or_else(|| usage()));
+            },
+            "--emit" => {
+                let name = args.next().unwrap_or_else(|| usage());
+                emit = match Emit::from_name(&name) {
+                    Some(emit) => Some(emit),
+                    None => {
+                        eprintln!("unknown output kind `{}`", name);
+                        usage()
                     }
-
-                    if scan_loop_end == false {
-                        start_loop_positions.push(idx);
-                    } else {
-                        nested_depth += 1;
+                };
+            },
+            "--target" => {
+                let name = args.next().unwrap_or_else(|| usage());
+                target = match Target::from_name(&name) {
+                    Some(target) => target,
+                    None => {
+                        eprintln!("unknown architecture `{}`", name);
+                        usage()
                     }
-
-                },
-                b']' => {
-                    // Unconditionally jump back to the matching [ bracket.
-                
-                    
-                    if scan_loop_end == true {
-                        nested_depth -= 1;
-                        if nested_depth == 0 {
-                            scan_loop_end = false;
-                        }
-                    } else {                       
-                        idx = start_loop_positions.pop().unwrap();                        
-                        continue;
+                };
+            },
+            "-h" | "--help" => usage(),
+            _ if arg.starts_with("-O") => {
+                optimizer = match arg[2..].parse().ok().and_then(Optimizer::from_level) {
+                    Some(optimizer) => optimizer,
+                    None => {
+                        eprintln!("invalid optimization level `{}`", &arg[2..]);
+                        usage()
                     }
-                  
-                },
-                _ => { panic!("unrecognized token at position {}", idx) }
+                };
+            },
+            _ if program.is_none() && (arg == "-" || !arg.starts_with('-')) => {
+                program = Some(arg);
+            },
+            _ => {
+                eprintln!("unexpected argument `{}`", arg);
+                usage()
             }
+        }
+    }
 
-            idx += 1;
-        }        
+    if unchecked && (checked || guard_pages) {
+        eprintln!("`--unchecked` cannot be combined with `--checked` or `--guard-pages`");
+        usage()
+    }
 
-        let elapsed = start.elapsed().as_secs_f64();
-        Some(VmExit::Exit(elapsed))
+    // Skipped passes stay skipped whatever the level
+    let optimizer = skipped.into_iter().fold(optimizer, Optimizer::disable);
+
+    Options {
+        program: program.unwrap_or_else(|| usage()),
+        engine,
+        optimizer,
+        pass_stats,
+        tape_size,
+        cell_width,
+        input,
+        eof_policy,
+        unbuffered,
+        time,
+        strict,
+        dump_tape,
+        checked,
+        guard_pages,
+        unchecked,
+        output,
+        emit,
+        target,
     }
+}
 
+/// A program as read from the command line
+enum Code {
+    /// Brainfuck source and its IR
+    Source(String, Program),
 
-    /// Same as before but it precomputes the loops `[` `]`
-    pub fn run_vm2(&mut self, instructions: String) -> Option<VmExit> {
-    
-        let mut idx: usize = 0;
-
-        //let mut loop_map = HashMap::<usize, usize>::new();
-        let mut loop_map = vec![0usize; instructions.len()];
-
-        let instructions = instructions.as_bytes();
-        while idx < instructions.len() {
-            let operation = instructions.get(idx).unwrap();
-            if *operation == b'[' {
-                let mut bracket_nesting = 1u32;
-                let mut seek = idx + 1;                
-                while seek < instructions.len() { 
-                    let cur_ins = *instructions.get(seek).unwrap();                   
-                    if cur_ins == b']' {
-                        bracket_nesting -= 1;
-                    } else if cur_ins == b'[' {
-                        bracket_nesting += 1;
-                    }
+    /// Bytecode written by `--emit bytecode`
+    Bytecode(Bytecode),
+}
 
-                    if bracket_nesting == 0 {
-                        break;
-                    }
-                    seek += 1;
-                }
-
-                if bracket_nesting == 0 {
-                    //loop_map.insert(idx, seek);
-                    //loop_map.insert(seek, idx);
-                    loop_map[idx] = seek;
-                    loop_map[seek] = idx;
-                } else {
-                    panic!("unmatched `[` at pos: {}", idx);
-                }
-            }
+fn read_program(path: &str) -> io::Result<Vec<u8>> {
+    let mut contents = Vec::new();
+    if path == "-" {
+        io::stdin().read_to_end(&mut contents)?;
+    } else {
+        File::open(path)?.read_to_end(&mut contents)?;
+    }
+    Ok(contents)
+}
 
-            idx += 1;
+/// Parse the source or load the bytecode in `contents`, exiting on errors
+fn load_program(options: &Options, name: &str, contents: Vec<u8>) -> Code {
+    if Bytecode::is_bytecode(&contents) {
+        let bytecode = match Bytecode::from_bytes(&contents) {
+            Ok(bytecode) => bytecode,
+            Err(err) => {
+                eprintln!("could not load `{}`: {}", name, err);
+                process::exit(3);
+            }
+        };
+        if options.engine.is_some_and(|engine| engine != Engine::Bytecode) {
+            eprintln!("`{}` is bytecode, it only runs with `--engine bytecode`", name);
+            process::exit(1);
+        }
+        if options.output.is_some() || options.emit.is_some() {
+            eprintln!("`{}` is bytecode, it cannot be compiled any further", name);
+            process::exit(1);
         }
+        return Code::Bytecode(bytecode);
+    }
 
-        idx = 0;
-        // start a timer
-        let start = Instant::now();
-            
-        while idx < instructions.len() {
-            let operation = instructions.get(idx).unwrap();
-            // Decode operator
-            match operation {
-                b'>' => {
-                    // Increment the data pointer to the next cell
-                    if (self.ptr + 1) >= self.memory.len() {
-                        return Some(VmExit::PtrOob);
-                    }
-                    self.ptr += 1;
-                    if DEBUG_ENABLED {
-                        println!("Executed Op: > at pos {} - ptr: {}", idx, self.ptr);                             
-                    }                    
-                },
-                b'<' => {
-                    // Decrement the data pointer to point to the previous cell      
-                    if self.ptr == 0 {
-                        return Some(VmExit::PtrOob);
-                    } 
-                    self.ptr -= 1;
-                    if DEBUG_ENABLED {
-                        println!("Executed Op: < at pos {} - ptr: {}", idx, self.ptr);                     
-                    }                  
-                },
-                b'+' => {
-                    // Increment the byte value at data pointer                    
-                    self.memory[self.ptr] = self.memory[self.ptr].wrapping_add(1);
-                    if DEBUG_ENABLED {
-                        println!("Executed Op: + at pos {} - ptr: {}", idx, self.ptr); 
-                    
-                    }               
-                },
-                b'-' => {
-                    // Decrement the byte value at data pointer.
-                    self.memory[self.ptr] = self.memory[self.ptr].wrapping_sub(1);
-                    if DEBUG_ENABLED {
-                        println!("Executed Op: - at pos {} - ptr: {}", idx, self.ptr);   
-                    }
-                },
-                b'.' => {
-                    // Output the byte value at the data pointer.
-                    print!("{}", char::from(self.memory[self.ptr]));
-                    io::stdout().flush().ok().expect("Could not flush stdout");
-                    if DEBUG_ENABLED {
-                        println!("Executed Op: . at pos {} - ptr: {}", idx, self.ptr); 
-                    }                        
-                },
-                b',' => {
-                    // Input one byte and store its value at the data pointer.
-                    self.memory[self.ptr] = self.receive_input();
-
-                    if DEBUG_ENABLED {
-                        println!("Executed Op: , at pos {} - ptr: {}", idx, self.ptr);  
-                    }                
-                },
-                b'[' => {
-                    // If the byte value at the data pointer is zero,
-                    // jump to the instruction following the matching ] bracket.
-                    // Otherwise, continue execution.          
-                    if self.memory[self.ptr] == 0  {                        
-                        //idx = *loop_map.get(&idx).unwrap();
-                        idx = loop_map[idx];
-                        continue;
-                    }
-                },
-                b']' => {
-                    // Unconditionally jump back to the matching [ bracket.
-                    if self.memory[self.ptr] != 0  {                        
-                        //idx = *loop_map.get(&idx).unwrap();
-                        idx = loop_map[idx];
-                        continue;
-                    }
-                },
-                _ => { panic!("unrecognized token at position {}", idx) }
-            }
+    let source = match String::from_utf8(contents) {
+        Ok(source) => source,
+        Err(err) => {
+            eprintln!("could not read `{}`: {}", name, err);
+            process::exit(1);
+        }
+    };
+    if options.strict {
+        for warning in Program::lint(&source) {
+            eprint!("{}", warning.render(name, &source));
+        }
+    }
 
-            idx += 1;
-        }        
+    match Program::parse(&source) {
+        Ok(program) => Code::Source(source, program),
+        Err(err) => {
+            eprint!("{}", err.render(name, &source));
+            process::exit(3);
+        }
+    }
+}
 
-        let elapsed = start.elapsed().as_secs_f64();
-        Some(VmExit::Exit(elapsed))
+/// Compile `program` to the `--emit` kind and write it to the `--output`
+/// path, stdout by default
+fn compile(options: &Options, program: &Program, source: &str) {
+    let aot_options = AotOptions {
+        jit: JitOptions {
+            bounds_checks: options.checked,
+            cell_width: options.cell_width,
+            eof_policy: options.eof_policy,
+        },
+        tape_cells: options.tape_size,
+        unbuffered: options.unbuffered,
+        optimizer: options.optimizer,
+    };
+    let emit = options.emit.unwrap_or(Emit::Elf);
+    let output = match emit {
+        Emit::Elf  => generate_elf(program, &aot_options).expect("could not generate machine code"),
+        Emit::C    => generate_c(program, &aot_options).into_bytes(),
+        Emit::Rust => generate_rust(program, &aot_options).into_bytes(),
+        Emit::Wasm => generate_wasm(program, &aot_options),
+        Emit::Ir   => options.optimizer.run(program).listing(source).into_bytes(),
+        Emit::Asm  => {
+            jit_listing(options.target, &options.optimizer.run(program), source, &aot_options.jit)
+                .expect("could not generate machine code")
+                .into_bytes()
+        },
+        Emit::Bytecode => Bytecode::compile(&options.optimizer.run(program)).to_bytes(),
+    };
+
+    let path = options.output.as_deref().unwrap_or("-");
+    let result = if path == "-" {
+        io::stdout().write_all(&output)
+    } else {
+        let mut open_options = fs::OpenOptions::new();
+        open_options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        if emit == Emit::Elf {
+            std::os::unix::fs::OpenOptionsExt::mode(&mut open_options, 0o755);
+        }
+        open_options.open(path).and_then(|mut file| file.write_all(&output))
+    };
+    if let Err(err) = result {
+        eprintln!("could not write output to `{}`: {}", path, err);
+        process::exit(1);
     }
+}
 
+fn main() {
+    let options = parse_args();
 
-    /// Same as run_vm2 but it consolidates sequences of operations
-    pub fn run_vm3(&mut self, instructions: String) -> Option<VmExit> {
-    
-        let mut idx: usize = 0;
-
-        let mut bfInstructions = Vec::<BfOperation>::new();
-        let re = Regex::new(r#"[\+]+|[-]+|[>]+|[<]+|[\[]|[\]]|[\.]|[,]"#).unwrap();
-        for cap in re.captures_iter(&instructions) {
-            match &cap[0].chars().nth(0).unwrap() {
-                '>' => bfInstructions.push(BfOperation::INC_PTR(*&cap[0].len())),
-                '<' => bfInstructions.push(BfOperation::DEC_PTR(*&cap[0].len())),
-                '+' => bfInstructions.push(BfOperation::INC_DATA(*&cap[0].len() as u8)),
-                '-' => bfInstructions.push(BfOperation::DEC_DATA(*&cap[0].len() as u8)),
-                '.' => bfInstructions.push(BfOperation::WRITE_STDOUT),
-                ',' => bfInstructions.push(BfOperation::READ_STDIN),
-                '[' => bfInstructions.push(BfOperation::LOOP_START),
-                ']' => bfInstructions.push(BfOperation::LOOP_END),
-                _ => {
-                    unreachable!("xxx");
-                }
-            }            
+    let contents = match read_program(&options.program) {
+        Ok(contents) => contents,
+        Err(err) => {
+            eprintln!("could not read `{}`: {}", options.program, err);
+            process::exit(1);
         }
+    };
 
-        // Precompute loops
-        let mut loop_map = vec![0usize; bfInstructions.len()];
-        idx = 0;
-        while idx < bfInstructions.len() {
-            let operation = bfInstructions.get(idx).unwrap();
-            match operation {
-                BfOperation::LOOP_START => {
-                    let mut bracket_nesting = 1u32;
-                    let mut seek = idx + 1;                
-                    while seek < instructions.len() { 
-                        let cur_ins = bfInstructions.get(seek).unwrap();
-                        match cur_ins {
-                            BfOperation::LOOP_START => {
-                                bracket_nesting += 1;
-                            },
-                            BfOperation::LOOP_END => {
-                                bracket_nesting -= 1;
-                            }
-                            _ => {}
-                        }  
-
-                        if bracket_nesting == 0 {
-                            break;
-                        }
-                        seek += 1;
-                    }
+    let name = if options.program == "-" { "<stdin>" } else { &options.program };
+    let code = load_program(&options, name, contents);
 
-                    if bracket_nesting == 0 {
-                        //loop_map.insert(idx, seek);
-                        //loop_map.insert(seek, idx);
-                        loop_map[idx] = seek;
-                        loop_map[seek] = idx;
-                    } else {
-                        panic!("unmatched `[` at pos: {}", idx);
-                    }
-                }
-                _ => {}
+    if let Code::Source(source, program) = &code {
+        if options.pass_stats {
+            for stats in options.optimizer.run_with_stats(program).1 {
+                eprintln!("{}", stats);
             }
+        }
 
-            idx += 1;
+        if options.output.is_some() || options.emit.is_some() {
+            compile(&options, program, source);
+            return;
         }
+    }
 
-        
-
-        idx = 0;
-        // start a timer
-        let start = Instant::now();
-            
-        while idx < bfInstructions.len() {
-            let operation = bfInstructions.get(idx).unwrap();
-            // Decode operator
-            match operation {
-                BfOperation::INC_PTR(times) => {
-                    // Increment the data pointer to the next cell
-                    if (self.ptr + times) >= self.memory.len() {
-                        return Some(VmExit::PtrOob);
-                    }
-                    self.ptr += times;
-                    if DEBUG_ENABLED {
-                        println!("Executed Op: > at pos {} - ptr: {}", idx, self.ptr);                             
-                    }                    
-                },
-                BfOperation::DEC_PTR(times) => {
-                    // Decrement the data pointer to point to the previous cell      
-                    if self.ptr == 0 {
-                        return Some(VmExit::PtrOob);
-                    } 
-                    self.ptr -= times;
-                    if DEBUG_ENABLED {
-                        println!("Executed Op: < at pos {} - ptr: {}", idx, self.ptr);                     
-                    }                  
-                },
-                BfOperation::INC_DATA(times) => {
-                    // Increment the byte value at data pointer                    
-                    self.memory[self.ptr] = self.memory[self.ptr].wrapping_add(*times);
-                    if DEBUG_ENABLED {
-                        println!("Executed Op: + at pos {} - ptr: {}", idx, self.ptr); 
-                    
-                    }               
-                },
-                BfOperation::DEC_DATA(times) => {
-                    // Decrement the byte value at data pointer.
-                    self.memory[self.ptr] = self.memory[self.ptr].wrapping_sub(*times);
-                    if DEBUG_ENABLED {
-                        println!("Executed Op: - at pos {} - ptr: {}", idx, self.ptr);   
-                    }
-                },
-                BfOperation::WRITE_STDOUT => {
-                    // Output the byte value at the data pointer.
-                    print!("{}", char::from(self.memory[self.ptr]));
-                    io::stdout().flush().ok().expect("Could not flush stdout");
-                    if DEBUG_ENABLED {
-                        println!("Executed Op: . at pos {} - ptr: {}", idx, self.ptr); 
-                    }                        
-                },
-                BfOperation::READ_STDIN => {
-                    // Input one byte and store its value at the data pointer.
-                    self.memory[self.ptr] = self.receive_input();
-
-                    if DEBUG_ENABLED {
-                        println!("Executed Op: , at pos {} - ptr: {}", idx, self.ptr);  
-                    }                
-                },
-                BfOperation::LOOP_START => {
-                    // If the byte value at the data pointer is zero,
-                    // jump to the instruction following the matching ] bracket.
-                    // Otherwise, continue execution.          
-                    if self.memory[self.ptr] == 0  {                        
-                        //idx = *loop_map.get(&idx).unwrap();
-                        idx = loop_map[idx];
-                        continue;
-                    }
-                },
-                BfOperation::LOOP_END => {
-                    // Unconditionally jump back to the matching [ bracket.
-                    if self.memory[self.ptr] != 0  {                        
-                        //idx = *loop_map.get(&idx).unwrap();
-                        idx = loop_map[idx];
-                        continue;
-                    }
-                },
-                _ => { panic!("unrecognized token at position {}", idx) }
+    let engine = match (&code, options.engine) {
+        (_, Some(engine)) => engine,
+        (Code::Bytecode(_), None) => Engine::Bytecode,
+        (Code::Source(..), None) => {
+            if cfg!(any(target_arch = "x86_64", target_arch = "aarch64")) {
+                Engine::JitOpt
+            } else {
+                Engine::Bytecode
             }
-
-            idx += 1;
-        }        
-
-        let elapsed = start.elapsed().as_secs_f64();
-        Some(VmExit::Exit(elapsed))
+        },
+    };
+
+    let mut emu = Emu::new(options.tape_size)
+        .with_cell_width(options.cell_width)
+        .with_eof_policy(options.eof_policy)
+        .with_optimizer(options.optimizer);
+    if let Some(path) = options.input.as_ref().filter(|path| *path != "-") {
+        match File::open(path) {
+            Ok(file) => emu = emu.with_input(BufReader::new(file)),
+            Err(err) => {
+                eprintln!("could not open input `{}`: {}", path, err);
+                process::exit(1);
+            }
+        }
+    }
+    if engine.is_jit() {
+        // Create a JIT cache
+        let jit_cache = Arc::new(JitCache::new(1024 * 1024));
+        emu = emu.enable_jit(jit_cache);
+    }
+    if options.unbuffered {
+        emu = emu.enable_unbuffered_output();
+    }
+    if options.checked {
+        emu = emu.enable_bounds_checks();
+    }
+    // The JIT stops at the edges of the tape unless told not to, with
+    // guard pages where they are supported and bounds checks elsewhere
+    let guard_by_default = engine.is_jit() && !options.checked && !options.unchecked;
+    if options.guard_pages || guard_by_default {
+        emu = emu.enable_guard_pages();
     }
-}
-
 
-fn remove_whitespace(s: &mut String) {
-    s.retain(|c| !c.is_whitespace());
-}
+    let exit = match &code {
+        Code::Source(_, program) => emu.run_program(engine, program),
+        Code::Bytecode(bytecode) => emu.run_compiled(bytecode),
+    };
+    io::stdout().flush().expect("Could not flush stdout");
 
-fn plot_mandelbrot() {
-    let mut bfcode = r#"
-    +++++++++++++[->++>>>+++++>++>+<<<<<<]>>>>>++++++>--->>>>>>>>>>+++++++++++++++[[
->>>>>>>>>]+[<<<<<<<<<]>>>>>>>>>-]+[>>>>>>>>[-]>]<<<<<<<<<[<<<<<<<<<]>>>>>>>>[-]+
-<<<<<<<+++++[-[->>>>>>>>>+<<<<<<<<<]>>>>>>>>>]>>>>>>>+>>>>>>>>>>>>>>>>>>>>>>>>>>
->+<<<<<<<<<<<<<<<<<[<<<<<<<<<]>>>[-]+[>>>>>>[>>>>>>>[-]>>]<<<<<<<<<[<<<<<<<<<]>>
->>>>>[-]+<<<<<<++++[-[->>>>>>>>>+<<<<<<<<<]>>>>>>>>>]>>>>>>+<<<<<<+++++++[-[->>>
->>>>>>+<<<<<<<<<]>>>>>>>>>]>>>>>>+<<<<<<<<<<<<<<<<[<<<<<<<<<]>>>[[-]>>>>>>[>>>>>
->>[-<<<<<<+>>>>>>]<<<<<<[->>>>>>+<<+<<<+<]>>>>>>>>]<<<<<<<<<[<<<<<<<<<]>>>>>>>>>
-[>>>>>>>>[-<<<<<<<+>>>>>>>]<<<<<<<[->>>>>>>+<<+<<<+<<]>>>>>>>>]<<<<<<<<<[<<<<<<<
-<<]>>>>>>>[-<<<<<<<+>>>>>>>]<<<<<<<[->>>>>>>+<<+<<<<<]>>>>>>>>>+++++++++++++++[[
->>>>>>>>>]+>[-]>[-]>[-]>[-]>[-]>[-]>[-]>[-]>[-]<<<<<<<<<[<<<<<<<<<]>>>>>>>>>-]+[
->+>>>>>>>>]<<<<<<<<<[<<<<<<<<<]>>>>>>>>>[>->>>>[-<<<<+>>>>]<<<<[->>>>+<<<<<[->>[
--<<+>>]<<[->>+>>+<<<<]+>>>>>>>>>]<<<<<<<<[<<<<<<<<<]]>>>>>>>>>[>>>>>>>>>]<<<<<<<
-<<[>[->>>>>>>>>+<<<<<<<<<]<<<<<<<<<<]>[->>>>>>>>>+<<<<<<<<<]<+>>>>>>>>]<<<<<<<<<
-[>[-]<->>>>[-<<<<+>[<->-<<<<<<+>>>>>>]<[->+<]>>>>]<<<[->>>+<<<]<+<<<<<<<<<]>>>>>
->>>>[>+>>>>>>>>]<<<<<<<<<[<<<<<<<<<]>>>>>>>>>[>->>>>>[-<<<<<+>>>>>]<<<<<[->>>>>+
-<<<<<<[->>>[-<<<+>>>]<<<[->>>+>+<<<<]+>>>>>>>>>]<<<<<<<<[<<<<<<<<<]]>>>>>>>>>[>>
->>>>>>>]<<<<<<<<<[>>[->>>>>>>>>+<<<<<<<<<]<<<<<<<<<<<]>>[->>>>>>>>>+<<<<<<<<<]<<
-+>>>>>>>>]<<<<<<<<<[>[-]<->>>>[-<<<<+>[<->-<<<<<<+>>>>>>]<[->+<]>>>>]<<<[->>>+<<
-<]<+<<<<<<<<<]>>>>>>>>>[>>>>[-<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<+>>>>>>>>>>>>>
->>>>>>>>>>>>>>>>>>>>>>>]>>>>>]<<<<<<<<<[<<<<<<<<<]>>>>>>>>>+++++++++++++++[[>>>>
->>>>>]<<<<<<<<<-<<<<<<<<<[<<<<<<<<<]>>>>>>>>>-]+>>>>>>>>>>>>>>>>>>>>>+<<<[<<<<<<
-<<<]>>>>>>>>>[>>>[-<<<->>>]+<<<[->>>->[-<<<<+>>>>]<<<<[->>>>+<<<<<<<<<<<<<[<<<<<
-<<<<]>>>>[-]+>>>>>[>>>>>>>>>]>+<]]+>>>>[-<<<<->>>>]+<<<<[->>>>-<[-<<<+>>>]<<<[->
->>+<<<<<<<<<<<<[<<<<<<<<<]>>>[-]+>>>>>>[>>>>>>>>>]>[-]+<]]+>[-<[>>>>>>>>>]<<<<<<
-<<]>>>>>>>>]<<<<<<<<<[<<<<<<<<<]<<<<<<<[->+>>>-<<<<]>>>>>>>>>+++++++++++++++++++
-+++++++>>[-<<<<+>>>>]<<<<[->>>>+<<[-]<<]>>[<<<<<<<+<[-<+>>>>+<<[-]]>[-<<[->+>>>-
-<<<<]>>>]>>>>>>>>>>>>>[>>[-]>[-]>[-]>>>>>]<<<<<<<<<[<<<<<<<<<]>>>[-]>>>>>>[>>>>>
-[-<<<<+>>>>]<<<<[->>>>+<<<+<]>>>>>>>>]<<<<<<<<<[<<<<<<<<<]>>>>>>>>>[>>[-<<<<<<<<
-<+>>>>>>>>>]>>>>>>>]<<<<<<<<<[<<<<<<<<<]>>>>>>>>>+++++++++++++++[[>>>>>>>>>]+>[-
-]>[-]>[-]>[-]>[-]>[-]>[-]>[-]>[-]<<<<<<<<<[<<<<<<<<<]>>>>>>>>>-]+[>+>>>>>>>>]<<<
-<<<<<<[<<<<<<<<<]>>>>>>>>>[>->>>>>[-<<<<<+>>>>>]<<<<<[->>>>>+<<<<<<[->>[-<<+>>]<
-<[->>+>+<<<]+>>>>>>>>>]<<<<<<<<[<<<<<<<<<]]>>>>>>>>>[>>>>>>>>>]<<<<<<<<<[>[->>>>
->>>>>+<<<<<<<<<]<<<<<<<<<<]>[->>>>>>>>>+<<<<<<<<<]<+>>>>>>>>]<<<<<<<<<[>[-]<->>>
-[-<<<+>[<->-<<<<<<<+>>>>>>>]<[->+<]>>>]<<[->>+<<]<+<<<<<<<<<]>>>>>>>>>[>>>>>>[-<
-<<<<+>>>>>]<<<<<[->>>>>+<<<<+<]>>>>>>>>]<<<<<<<<<[<<<<<<<<<]>>>>>>>>>[>+>>>>>>>>
-]<<<<<<<<<[<<<<<<<<<]>>>>>>>>>[>->>>>>[-<<<<<+>>>>>]<<<<<[->>>>>+<<<<<<[->>[-<<+
->>]<<[->>+>>+<<<<]+>>>>>>>>>]<<<<<<<<[<<<<<<<<<]]>>>>>>>>>[>>>>>>>>>]<<<<<<<<<[>
-[->>>>>>>>>+<<<<<<<<<]<<<<<<<<<<]>[->>>>>>>>>+<<<<<<<<<]<+>>>>>>>>]<<<<<<<<<[>[-
-]<->>>>[-<<<<+>[<->-<<<<<<+>>>>>>]<[->+<]>>>>]<<<[->>>+<<<]<+<<<<<<<<<]>>>>>>>>>
-[>>>>[-<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<+>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>
-]>>>>>]<<<<<<<<<[<<<<<<<<<]>>>>>>>>>[>>>[-<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<+>
->>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>]>>>>>>]<<<<<<<<<[<<<<<<<<<]>>>>>>>>>++++++++
-+++++++[[>>>>>>>>>]<<<<<<<<<-<<<<<<<<<[<<<<<<<<<]>>>>>>>>>-]+[>>>>>>>>[-<<<<<<<+
->>>>>>>]<<<<<<<[->>>>>>>+<<<<<<+<]>>>>>>>>]<<<<<<<<<[<<<<<<<<<]>>>>>>>>>[>>>>>>[
--]>>>]<<<<<<<<<[<<<<<<<<<]>>>>+>[-<-<<<<+>>>>>]>[-<<<<<<[->>>>>+<++<<<<]>>>>>[-<
-<<<<+>>>>>]<->+>]<[->+<]<<<<<[->>>>>+<<<<<]>>>>>>[-]<<<<<<+>>>>[-<<<<->>>>]+<<<<
-[->>>>->>>>>[>>[-<<->>]+<<[->>->[-<<<+>>>]<<<[->>>+<<<<<<<<<<<<[<<<<<<<<<]>>>[-]
-+>>>>>>[>>>>>>>>>]>+<]]+>>>[-<<<->>>]+<<<[->>>-<[-<<+>>]<<[->>+<<<<<<<<<<<[<<<<<
-<<<<]>>>>[-]+>>>>>[>>>>>>>>>]>[-]+<]]+>[-<[>>>>>>>>>]<<<<<<<<]>>>>>>>>]<<<<<<<<<
-[<<<<<<<<<]>>>>[-<<<<+>>>>]<<<<[->>>>+>>>>>[>+>>[-<<->>]<<[->>+<<]>>>>>>>>]<<<<<
-<<<+<[>[->>>>>+<<<<[->>>>-<<<<<<<<<<<<<<+>>>>>>>>>>>[->>>+<<<]<]>[->>>-<<<<<<<<<
-<<<<<+>>>>>>>>>>>]<<]>[->>>>+<<<[->>>-<<<<<<<<<<<<<<+>>>>>>>>>>>]<]>[->>>+<<<]<<
-<<<<<<<<<<]>>>>[-]<<<<]>>>[-<<<+>>>]<<<[->>>+>>>>>>[>+>[-<->]<[->+<]>>>>>>>>]<<<
-<<<<<+<[>[->>>>>+<<<[->>>-<<<<<<<<<<<<<<+>>>>>>>>>>[->>>>+<<<<]>]<[->>>>-<<<<<<<
-<<<<<<<+>>>>>>>>>>]<]>>[->>>+<<<<[->>>>-<<<<<<<<<<<<<<+>>>>>>>>>>]>]<[->>>>+<<<<
-]<<<<<<<<<<<]>>>>>>+<<<<<<]]>>>>[-<<<<+>>>>]<<<<[->>>>+>>>>>[>>>>>>>>>]<<<<<<<<<
-[>[->>>>>+<<<<[->>>>-<<<<<<<<<<<<<<+>>>>>>>>>>>[->>>+<<<]<]>[->>>-<<<<<<<<<<<<<<
-+>>>>>>>>>>>]<<]>[->>>>+<<<[->>>-<<<<<<<<<<<<<<+>>>>>>>>>>>]<]>[->>>+<<<]<<<<<<<
-<<<<<]]>[-]>>[-]>[-]>>>>>[>>[-]>[-]>>>>>>]<<<<<<<<<[<<<<<<<<<]>>>>>>>>>[>>>>>[-<
-<<<+>>>>]<<<<[->>>>+<<<+<]>>>>>>>>]<<<<<<<<<[<<<<<<<<<]>>>>>>>>>+++++++++++++++[
-[>>>>>>>>>]+>[-]>[-]>[-]>[-]>[-]>[-]>[-]>[-]>[-]<<<<<<<<<[<<<<<<<<<]>>>>>>>>>-]+
-[>+>>>>>>>>]<<<<<<<<<[<<<<<<<<<]>>>>>>>>>[>->>>>[-<<<<+>>>>]<<<<[->>>>+<<<<<[->>
-[-<<+>>]<<[->>+>+<<<]+>>>>>>>>>]<<<<<<<<[<<<<<<<<<]]>>>>>>>>>[>>>>>>>>>]<<<<<<<<
-<[>[->>>>>>>>>+<<<<<<<<<]<<<<<<<<<<]>[->>>>>>>>>+<<<<<<<<<]<+>>>>>>>>]<<<<<<<<<[
->[-]<->>>[-<<<+>[<->-<<<<<<<+>>>>>>>]<[->+<]>>>]<<[->>+<<]<+<<<<<<<<<]>>>>>>>>>[
->>>[-<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<+>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>]>
->>>>>]<<<<<<<<<[<<<<<<<<<]>>>>>[-]>>>>+++++++++++++++[[>>>>>>>>>]<<<<<<<<<-<<<<<
-<<<<[<<<<<<<<<]>>>>>>>>>-]+[>>>[-<<<->>>]+<<<[->>>->[-<<<<+>>>>]<<<<[->>>>+<<<<<
-<<<<<<<<[<<<<<<<<<]>>>>[-]+>>>>>[>>>>>>>>>]>+<]]+>>>>[-<<<<->>>>]+<<<<[->>>>-<[-
-<<<+>>>]<<<[->>>+<<<<<<<<<<<<[<<<<<<<<<]>>>[-]+>>>>>>[>>>>>>>>>]>[-]+<]]+>[-<[>>
->>>>>>>]<<<<<<<<]>>>>>>>>]<<<<<<<<<[<<<<<<<<<]>>>[-<<<+>>>]<<<[->>>+>>>>>>[>+>>>
-[-<<<->>>]<<<[->>>+<<<]>>>>>>>>]<<<<<<<<+<[>[->+>[-<-<<<<<<<<<<+>>>>>>>>>>>>[-<<
-+>>]<]>[-<<-<<<<<<<<<<+>>>>>>>>>>>>]<<<]>>[-<+>>[-<<-<<<<<<<<<<+>>>>>>>>>>>>]<]>
-[-<<+>>]<<<<<<<<<<<<<]]>>>>[-<<<<+>>>>]<<<<[->>>>+>>>>>[>+>>[-<<->>]<<[->>+<<]>>
->>>>>>]<<<<<<<<+<[>[->+>>[-<<-<<<<<<<<<<+>>>>>>>>>>>[-<+>]>]<[-<-<<<<<<<<<<+>>>>
->>>>>>>]<<]>>>[-<<+>[-<-<<<<<<<<<<+>>>>>>>>>>>]>]<[-<+>]<<<<<<<<<<<<]>>>>>+<<<<<
-]>>>>>>>>>[>>>[-]>[-]>[-]>>>>]<<<<<<<<<[<<<<<<<<<]>>>[-]>[-]>>>>>[>>>>>>>[-<<<<<
-<+>>>>>>]<<<<<<[->>>>>>+<<<<+<<]>>>>>>>>]<<<<<<<<<[<<<<<<<<<]>>>>+>[-<-<<<<+>>>>
->]>>[-<<<<<<<[->>>>>+<++<<<<]>>>>>[-<<<<<+>>>>>]<->+>>]<<[->>+<<]<<<<<[->>>>>+<<
-<<<]+>>>>[-<<<<->>>>]+<<<<[->>>>->>>>>[>>>[-<<<->>>]+<<<[->>>-<[-<<+>>]<<[->>+<<
-<<<<<<<<<[<<<<<<<<<]>>>>[-]+>>>>>[>>>>>>>>>]>+<]]+>>[-<<->>]+<<[->>->[-<<<+>>>]<
-<<[->>>+<<<<<<<<<<<<[<<<<<<<<<]>>>[-]+>>>>>>[>>>>>>>>>]>[-]+<]]+>[-<[>>>>>>>>>]<
-<<<<<<<]>>>>>>>>]<<<<<<<<<[<<<<<<<<<]>>>[-<<<+>>>]<<<[->>>+>>>>>>[>+>[-<->]<[->+
-<]>>>>>>>>]<<<<<<<<+<[>[->>>>+<<[->>-<<<<<<<<<<<<<+>>>>>>>>>>[->>>+<<<]>]<[->>>-
-<<<<<<<<<<<<<+>>>>>>>>>>]<]>>[->>+<<<[->>>-<<<<<<<<<<<<<+>>>>>>>>>>]>]<[->>>+<<<
-]<<<<<<<<<<<]>>>>>[-]>>[-<<<<<<<+>>>>>>>]<<<<<<<[->>>>>>>+<<+<<<<<]]>>>>[-<<<<+>
->>>]<<<<[->>>>+>>>>>[>+>>[-<<->>]<<[->>+<<]>>>>>>>>]<<<<<<<<+<[>[->>>>+<<<[->>>-
-<<<<<<<<<<<<<+>>>>>>>>>>>[->>+<<]<]>[->>-<<<<<<<<<<<<<+>>>>>>>>>>>]<<]>[->>>+<<[
-->>-<<<<<<<<<<<<<+>>>>>>>>>>>]<]>[->>+<<]<<<<<<<<<<<<]]>>>>[-]<<<<]>>>>[-<<<<+>>
->>]<<<<[->>>>+>[-]>>[-<<<<<<<+>>>>>>>]<<<<<<<[->>>>>>>+<<+<<<<<]>>>>>>>>>[>>>>>>
->>>]<<<<<<<<<[>[->>>>+<<<[->>>-<<<<<<<<<<<<<+>>>>>>>>>>>[->>+<<]<]>[->>-<<<<<<<<
-<<<<<+>>>>>>>>>>>]<<]>[->>>+<<[->>-<<<<<<<<<<<<<+>>>>>>>>>>>]<]>[->>+<<]<<<<<<<<
-<<<<]]>>>>>>>>>[>>[-]>[-]>>>>>>]<<<<<<<<<[<<<<<<<<<]>>>[-]>[-]>>>>>[>>>>>[-<<<<+
->>>>]<<<<[->>>>+<<<+<]>>>>>>>>]<<<<<<<<<[<<<<<<<<<]>>>>>>>>>[>>>>>>[-<<<<<+>>>>>
-]<<<<<[->>>>>+<<<+<<]>>>>>>>>]<<<<<<<<<[<<<<<<<<<]>>>>>>>>>+++++++++++++++[[>>>>
->>>>>]+>[-]>[-]>[-]>[-]>[-]>[-]>[-]>[-]>[-]<<<<<<<<<[<<<<<<<<<]>>>>>>>>>-]+[>+>>
->>>>>>]<<<<<<<<<[<<<<<<<<<]>>>>>>>>>[>->>>>[-<<<<+>>>>]<<<<[->>>>+<<<<<[->>[-<<+
->>]<<[->>+>>+<<<<]+>>>>>>>>>]<<<<<<<<[<<<<<<<<<]]>>>>>>>>>[>>>>>>>>>]<<<<<<<<<[>
-[->>>>>>>>>+<<<<<<<<<]<<<<<<<<<<]>[->>>>>>>>>+<<<<<<<<<]<+>>>>>>>>]<<<<<<<<<[>[-
-]<->>>>[-<<<<+>[<->-<<<<<<+>>>>>>]<[->+<]>>>>]<<<[->>>+<<<]<+<<<<<<<<<]>>>>>>>>>
-[>+>>>>>>>>]<<<<<<<<<[<<<<<<<<<]>>>>>>>>>[>->>>>>[-<<<<<+>>>>>]<<<<<[->>>>>+<<<<
-<<[->>>[-<<<+>>>]<<<[->>>+>+<<<<]+>>>>>>>>>]<<<<<<<<[<<<<<<<<<]]>>>>>>>>>[>>>>>>
->>>]<<<<<<<<<[>>[->>>>>>>>>+<<<<<<<<<]<<<<<<<<<<<]>>[->>>>>>>>>+<<<<<<<<<]<<+>>>
->>>>>]<<<<<<<<<[>[-]<->>>>[-<<<<+>[<->-<<<<<<+>>>>>>]<[->+<]>>>>]<<<[->>>+<<<]<+
-<<<<<<<<<]>>>>>>>>>[>>>>[-<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<+>>>>>>>>>>>>>>>>>
->>>>>>>>>>>>>>>>>>>]>>>>>]<<<<<<<<<[<<<<<<<<<]>>>>>>>>>+++++++++++++++[[>>>>>>>>
->]<<<<<<<<<-<<<<<<<<<[<<<<<<<<<]>>>>>>>>>-]+>>>>>>>>>>>>>>>>>>>>>+<<<[<<<<<<<<<]
->>>>>>>>>[>>>[-<<<->>>]+<<<[->>>->[-<<<<+>>>>]<<<<[->>>>+<<<<<<<<<<<<<[<<<<<<<<<
-]>>>>[-]+>>>>>[>>>>>>>>>]>+<]]+>>>>[-<<<<->>>>]+<<<<[->>>>-<[-<<<+>>>]<<<[->>>+<
-<<<<<<<<<<<[<<<<<<<<<]>>>[-]+>>>>>>[>>>>>>>>>]>[-]+<]]+>[-<[>>>>>>>>>]<<<<<<<<]>
->>>>>>>]<<<<<<<<<[<<<<<<<<<]>>->>[-<<<<+>>>>]<<<<[->>>>+<<[-]<<]>>]<<+>>>>[-<<<<
-->>>>]+<<<<[->>>>-<<<<<<.>>]>>>>[-<<<<<<<.>>>>>>>]<<<[-]>[-]>[-]>[-]>[-]>[-]>>>[
->[-]>[-]>[-]>[-]>[-]>[-]>>>]<<<<<<<<<[<<<<<<<<<]>>>>>>>>>[>>>>>[-]>>>>]<<<<<<<<<
-[<<<<<<<<<]>+++++++++++[-[->>>>>>>>>+<<<<<<<<<]>>>>>>>>>]>>>>+>>>>>>>>>+<<<<<<<<
-<<<<<<[<<<<<<<<<]>>>>>>>[-<<<<<<<+>>>>>>>]<<<<<<<[->>>>>>>+[-]>>[>>>>>>>>>]<<<<<
-<<<<[>>>>>>>[-<<<<<<+>>>>>>]<<<<<<[->>>>>>+<<<<<<<[<<<<<<<<<]>>>>>>>[-]+>>>]<<<<
-<<<<<<]]>>>>>>>[-<<<<<<<+>>>>>>>]<<<<<<<[->>>>>>>+>>[>+>>>>[-<<<<->>>>]<<<<[->>>
->+<<<<]>>>>>>>>]<<+<<<<<<<[>>>>>[->>+<<]<<<<<<<<<<<<<<]>>>>>>>>>[>>>>>>>>>]<<<<<
-<<<<[>[-]<->>>>>>>[-<<<<<<<+>[<->-<<<+>>>]<[->+<]>>>>>>>]<<<<<<[->>>>>>+<<<<<<]<
-+<<<<<<<<<]>>>>>>>-<<<<[-]+<<<]+>>>>>>>[-<<<<<<<->>>>>>>]+<<<<<<<[->>>>>>>->>[>>
->>>[->>+<<]>>>>]<<<<<<<<<[>[-]<->>>>>>>[-<<<<<<<+>[<->-<<<+>>>]<[->+<]>>>>>>>]<<
-<<<<[->>>>>>+<<<<<<]<+<<<<<<<<<]>+++++[-[->>>>>>>>>+<<<<<<<<<]>>>>>>>>>]>>>>+<<<
-<<[<<<<<<<<<]>>>>>>>>>[>>>>>[-<<<<<->>>>>]+<<<<<[->>>>>->>[-<<<<<<<+>>>>>>>]<<<<
-<<<[->>>>>>>+<<<<<<<<<<<<<<<<[<<<<<<<<<]>>>>[-]+>>>>>[>>>>>>>>>]>+<]]+>>>>>>>[-<
-<<<<<<->>>>>>>]+<<<<<<<[->>>>>>>-<<[-<<<<<+>>>>>]<<<<<[->>>>>+<<<<<<<<<<<<<<[<<<
-<<<<<<]>>>[-]+>>>>>>[>>>>>>>>>]>[-]+<]]+>[-<[>>>>>>>>>]<<<<<<<<]>>>>>>>>]<<<<<<<
-<<[<<<<<<<<<]>>>>[-]<<<+++++[-[->>>>>>>>>+<<<<<<<<<]>>>>>>>>>]>>>>-<<<<<[<<<<<<<
-<<]]>>>]<<<<.>>>>>>>>>>[>>>>>>[-]>>>]<<<<<<<<<[<<<<<<<<<]>++++++++++[-[->>>>>>>>
->+<<<<<<<<<]>>>>>>>>>]>>>>>+>>>>>>>>>+<<<<<<<<<<<<<<<[<<<<<<<<<]>>>>>>>>[-<<<<<<
-<<+>>>>>>>>]<<<<<<<<[->>>>>>>>+[-]>[>>>>>>>>>]<<<<<<<<<[>>>>>>>>[-<<<<<<<+>>>>>>
->]<<<<<<<[->>>>>>>+<<<<<<<<[<<<<<<<<<]>>>>>>>>[-]+>>]<<<<<<<<<<]]>>>>>>>>[-<<<<<
-<<<+>>>>>>>>]<<<<<<<<[->>>>>>>>+>[>+>>>>>[-<<<<<->>>>>]<<<<<[->>>>>+<<<<<]>>>>>>
->>]<+<<<<<<<<[>>>>>>[->>+<<]<<<<<<<<<<<<<<<]>>>>>>>>>[>>>>>>>>>]<<<<<<<<<[>[-]<-
->>>>>>>>[-<<<<<<<<+>[<->-<<+>>]<[->+<]>>>>>>>>]<<<<<<<[->>>>>>>+<<<<<<<]<+<<<<<<
-<<<]>>>>>>>>-<<<<<[-]+<<<]+>>>>>>>>[-<<<<<<<<->>>>>>>>]+<<<<<<<<[->>>>>>>>->[>>>
->>>[->>+<<]>>>]<<<<<<<<<[>[-]<->>>>>>>>[-<<<<<<<<+>[<->-<<+>>]<[->+<]>>>>>>>>]<<
-<<<<<[->>>>>>>+<<<<<<<]<+<<<<<<<<<]>+++++[-[->>>>>>>>>+<<<<<<<<<]>>>>>>>>>]>>>>>
-+>>>>>>>>>>>>>>>>>>>>>>>>>>>+<<<<<<[<<<<<<<<<]>>>>>>>>>[>>>>>>[-<<<<<<->>>>>>]+<
-<<<<<[->>>>>>->>[-<<<<<<<<+>>>>>>>>]<<<<<<<<[->>>>>>>>+<<<<<<<<<<<<<<<<<[<<<<<<<
-<<]>>>>[-]+>>>>>[>>>>>>>>>]>+<]]+>>>>>>>>[-<<<<<<<<->>>>>>>>]+<<<<<<<<[->>>>>>>>
--<<[-<<<<<<+>>>>>>]<<<<<<[->>>>>>+<<<<<<<<<<<<<<<[<<<<<<<<<]>>>[-]+>>>>>>[>>>>>>
->>>]>[-]+<]]+>[-<[>>>>>>>>>]<<<<<<<<]>>>>>>>>]<<<<<<<<<[<<<<<<<<<]>>>>[-]<<<++++
-+[-[->>>>>>>>>+<<<<<<<<<]>>>>>>>>>]>>>>>->>>>>>>>>>>>>>>>>>>>>>>>>>>-<<<<<<[<<<<
-<<<<<]]>>>]
-    "#.to_string();    
-    remove_whitespace(&mut bfcode);
-
-    println!("BrainfuckRVM: a Brainfuck Interpreter.\n");
-
-    // Create a JIT cache
-    let jit_cache = Arc::new(JitCache::new(1024 * 1024));
-
-    let mut emu = Emu::new(30000).enable_jit(jit_cache);
-    //let mut emu = Emu::new(30000);
-
-    match emu.run(bfcode) {
-        Some(VmExit::PtrOob) => {
-            panic!("OOB")
+    if let Some(path) = &options.dump_tape {
+        if let Err(err) = File::create(path).and_then(|mut file| file.write_all(&emu.memory[..])) {
+            eprintln!("could not write tape to `{}`: {}", path, err);
+            process::exit(1);
         }
-        Some(VmExit::Exit(elapsed)) => {
-            println!("\nExecution time: [{:10.4}]s", elapsed);
-            io::stdout().flush().ok().expect("Could not flush stdout");
-        },
-        _ => { unreachable!("something went wrong") }
     }
 
-    println!("\ndone.");
-    
-}
-
-fn hello_world() {
-    let mut bfcode = r#"
-    >++++++++[<+++++++++>-]<.>++++[<+++++++>-]<+.+++++++..+++.>>++++++[<+++++++>-]<+
-+.------------.>++++++[<+++++++++>-]<+.<.+++.------.--------.>>>++++[<++++++++>-
-]<+.
-    "#.to_string();    
-    remove_whitespace(&mut bfcode);
-
-    println!("BrainfuckRVM: a Brainfuck Interpreter.\n");
-
-    let mut emu = Emu::new(30000);
-    match emu.run_vm3(bfcode) {
-        Some(VmExit::PtrOob) => {
-            panic!("OOB")
+    match exit {
+        Some(VmExit::PtrOob { offset: Some(offset), ptr }) => match &code {
+            Code::Source(source, _) => {
+                eprintln!("data pointer out of bounds at {}:{} (ptr: {})",
+                          name, Span::new(source, offset), ptr);
+            },
+            Code::Bytecode(_) => {
+                eprintln!("data pointer out of bounds at source offset {} of {} (ptr: {})",
+                          offset, name, ptr);
+            },
+        },
+        Some(VmExit::PtrOob { offset: None, ptr }) => {
+            eprintln!("data pointer out of bounds (ptr: {})", ptr);
         }
+        Some(VmExit::InputExhausted { .. }) => {}
         Some(VmExit::Exit(elapsed)) => {
-            println!("\nExecution time: [{:10.4}]s", elapsed);
-            io::stdout().flush().ok().expect("Could not flush stdout");
+            if options.time {
+                eprintln!("Execution time: [{:10.4}]s", elapsed);
+            }
         },
-        _ => { unreachable!("something went wrong") }
+        None => { unreachable!("something went wrong") }
     }
 
-    println!("\ndone.");
+    process::exit(exit.map_or(1, |exit| exit.exit_code()));
 }
-
-fn main() {
-    plot_mandelbrot();
-}
\ No newline at end of file
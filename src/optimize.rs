@@ -0,0 +1,242 @@
+//! The optimization pipeline. An `Optimizer` runs the passes of `Pass::ALL`
+//! in order, each of which can be switched off, and records what every pass
+//! changed. The command line exposes the usual levels:
+//!
+//! - `-O0` runs no pass, every command stays one operation
+//! - `-O1` folds runs of the same command
+//! - `-O2` runs every pass once, this is what `Program::optimize` does
+//! - `-O3` repeats the passes until they stop changing the program
+//!
+//! The constants pass assumes the tape starts out all zeros, unless told
+//! otherwise with `Optimizer::with_zero_tape`. The later passes bend the
+//! bounds checks of runs and multiply loops: instead of every pointer
+//! movement they check the cells they change, where they leave the pointer
+//! and the furthest cells they reach on either side, so a program leaving
+//! the tape still stops. It stops at a `MulAdd`, `Add` or `Set` where the
+//! original code would have stopped at the pointer movement leaving the
+//! tape, possibly after applying net changes of the run which the original
+//! code would only have made partly by then. Loops which reach further than
+//! the cells they change are not replaced by multiply loops.
+
+use crate::ir::Program;
+
+use std::fmt;
+
+/// Upper bound on the rounds of `-O3`
+const MAX_ROUNDS: usize = 8;
+
+/// A rewrite of the IR
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Pass {
+    /// Fold runs of `+`, `-`, `>` and `<` into a single operation each, see
+    /// `Program::fold`
+    Fold,
+
+    /// Replace the loop idioms real programs spend most of their time in:
+    ///
+    /// - `[-]` and `[+]` become `SetZero`
+    /// - `[>]` and `[<]`, with any stride, become `ScanRight` and `ScanLeft`
+    /// - multiply loops such as `[->+>++<<]`, which only add to cells around
+    ///   a counter stepping by one and return to it, become a `MulAdd` per
+    ///   cell followed by `SetZero`. The brackets stay, so the body runs at
+    ///   most once and only when the counter is non-zero.
+    ///
+    /// Works best on folded code, `[>>]` is only a scan once folded.
+    Idioms,
+
+    /// Turn the runs of `+`, `-`, `>` and `<` into an `Add` per cell they
+    /// change, addressed relative to the data pointer at the start of the
+    /// run, and a single pointer movement at its end
+    Offsets,
+
+    /// Track the values of cells through straight-line code, starting from
+    /// the all-zero tape a fresh `Emu` starts on unless the optimizer was told
+    /// otherwise, see `Optimizer::with_zero_tape`. Loops entered on a cell
+    /// known to be zero are removed, changes to known cells are folded into
+    /// a single `Set` and `.` of a known cell becomes `WriteConst`.
+    /// Multiply loops with a known counter run at compile time.
+    Constants,
+}
+
+impl Pass {
+    /// Every pass, in the order an `Optimizer` runs them
+    pub const ALL: [Pass; 4] = [Pass::Fold, Pass::Idioms, Pass::Offsets, Pass::Constants];
+
+    /// Look up a pass by its command line name
+    pub fn from_name(name: &str) -> Option<Pass> {
+        Pass::ALL.iter().copied().find(|pass| pass.name() == name)
+    }
+
+    /// The command line name of the pass
+    pub fn name(self) -> &'static str {
+        match self {
+            Pass::Fold      => "fold",
+            Pass::Idioms    => "idioms",
+            Pass::Offsets   => "offsets",
+            Pass::Constants => "constants",
+        }
+    }
+
+    /// Run the pass over `program`, which starts on an all-zero tape,
+    /// returning the result and the number of loops it rewrote
+    pub fn run(self, program: &Program) -> (Program, usize) {
+        self.run_on(program, true)
+    }
+
+    /// Same as `run`, for a tape which is only all zeros if `zero_tape`
+    fn run_on(self, program: &Program, zero_tape: bool) -> (Program, usize) {
+        match self {
+            Pass::Fold      => (program.fold(), 0),
+            Pass::Idioms    => program.replace_idioms(),
+            Pass::Offsets   => (program.address_cells(), 0),
+            Pass::Constants => program.propagate_constants(zero_tape),
+        }
+    }
+
+    /// Index of the pass in `Pass::ALL`
+    fn index(self) -> usize {
+        Pass::ALL.iter().position(|&pass| pass == self).unwrap()
+    }
+}
+
+impl fmt::Display for Pass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(self.name())
+    }
+}
+
+/// What one run of a pass changed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PassStats {
+    /// The pass
+    pub pass: Pass,
+
+    /// Number of operations going in
+    pub ops_before: usize,
+
+    /// Number of operations coming out
+    pub ops_after: usize,
+
+    /// Number of loops replaced, removed or run at compile time
+    pub loops_rewritten: usize,
+}
+
+impl PassStats {
+    /// Number of operations the pass removed, negative if it added some
+    pub fn ops_removed(&self) -> isize {
+        self.ops_before as isize - self.ops_after as isize
+    }
+}
+
+impl fmt::Display for PassStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:<9} {} -> {} ops ({} removed), {} loops rewritten", self.pass,
+               self.ops_before, self.ops_after, self.ops_removed(), self.loops_rewritten)
+    }
+}
+
+/// An ordered pipeline of passes, the ones of `-O2` by default
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Optimizer {
+    /// Whether each pass of `Pass::ALL` runs
+    enabled: [bool; Pass::ALL.len()],
+
+    /// Repeat the passes until the program stops changing
+    repeat: bool,
+
+    /// Whether programs start on an all-zero tape
+    zero_tape: bool,
+}
+
+impl Default for Optimizer {
+    fn default() -> Self {
+        Optimizer::from_level(2).unwrap()
+    }
+}
+
+impl Optimizer {
+    /// An optimizer which runs no pass at all
+    pub fn new() -> Self {
+        Optimizer { enabled: [false; Pass::ALL.len()], repeat: false, zero_tape: true }
+    }
+
+    /// The optimizer of `-O<level>`, see the module documentation
+    pub fn from_level(level: u8) -> Option<Optimizer> {
+        let optimizer = Optimizer::new();
+        match level {
+            0 => Some(optimizer),
+            1 => Some(optimizer.enable(Pass::Fold)),
+            2 => Some(Pass::ALL.iter().fold(optimizer, |optimizer, &pass| optimizer.enable(pass))),
+            3 => Some(Optimizer::from_level(2)?.enable_repeat()),
+            _ => None,
+        }
+    }
+
+    /// Run `pass`
+    pub fn enable(mut self, pass: Pass) -> Self {
+        self.enabled[pass.index()] = true;
+        self
+    }
+
+    /// Skip `pass`
+    pub fn disable(mut self, pass: Pass) -> Self {
+        self.enabled[pass.index()] = false;
+        self
+    }
+
+    /// Repeat the enabled passes until a round leaves the program unchanged
+    pub fn enable_repeat(mut self) -> Self {
+        self.repeat = true;
+        self
+    }
+
+    /// Whether the programs start on an all-zero tape, as they do by
+    /// default. Without it the constants pass only knows the values a
+    /// program stores itself.
+    pub fn with_zero_tape(mut self, zero_tape: bool) -> Self {
+        self.zero_tape = zero_tape;
+        self
+    }
+
+    /// Whether `pass` runs
+    pub fn is_enabled(&self, pass: Pass) -> bool {
+        self.enabled[pass.index()]
+    }
+
+    /// The enabled passes, in order
+    pub fn passes(&self) -> impl Iterator<Item = Pass> + '_ {
+        Pass::ALL.iter().copied().filter(move |&pass| self.is_enabled(pass))
+    }
+
+    /// Run the enabled passes over `program`
+    pub fn run(&self, program: &Program) -> Program {
+        self.run_with_stats(program).0
+    }
+
+    /// Run the enabled passes over `program`, also returning what every run
+    /// of a pass changed, in the order they ran
+    pub fn run_with_stats(&self, program: &Program) -> (Program, Vec<PassStats>) {
+        let mut program = program.clone();
+        let mut stats = Vec::new();
+
+        let rounds = if self.repeat { MAX_ROUNDS } else { 1 };
+        for _ in 0..rounds {
+            let before = program.clone();
+            for pass in self.passes() {
+                let (optimized, loops_rewritten) = pass.run_on(&program, self.zero_tape);
+                stats.push(PassStats {
+                    pass,
+                    ops_before: program.len(),
+                    ops_after: optimized.len(),
+                    loops_rewritten,
+                });
+                program = optimized;
+            }
+            if program == before {
+                break;
+            }
+        }
+
+        (program, stats)
+    }
+}
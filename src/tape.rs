@@ -0,0 +1,266 @@
+//! Tape storage. A tape is either a plain heap allocation or, on x86-64
+//! Linux, an mmap region surrounded by `PROT_NONE` guard pages. With guard
+//! pages an out of bounds access from unchecked JIT code faults, and the
+//! SIGSEGV handler installed here turns the fault back into a return from
+//! the JIT code so the VM can exit with `VmExit::PtrOob`.
+
+use std::ops::{Deref, DerefMut};
+
+/// Size of the guard region on either side of a guarded tape. The JIT never
+/// moves the pointer further than this without touching memory or checking
+/// the bounds explicitly, so a stray pointer always lands in a guard first.
+pub const GUARD_SIZE: usize = 1024 * 1024;
+
+/// Status returned in `rax` by JIT code which was stopped by a guard page
+pub(crate) const GUARD_FAULT: usize = usize::MAX;
+
+/// The cells of an `Emu`
+pub enum Tape {
+    /// Tape on the heap
+    Heap(Vec<u8>),
+
+    /// Tape in an mmap region between two guard regions
+    Guarded {
+        /// Start of the whole mapping, including the low guard
+        base: *mut u8,
+
+        /// Size of the whole mapping
+        mapping_size: usize,
+
+        /// Number of usable cells
+        len: usize,
+    },
+}
+
+// The guarded mapping is owned exclusively by its `Tape`
+unsafe impl Send for Tape {}
+
+impl Tape {
+    /// Allocate `size` zeroed cells on the heap
+    pub fn new(size: usize) -> Self {
+        Tape::Heap(vec![0u8; size])
+    }
+
+    /// Allocate at least `size` zeroed cells between two guard regions. The
+    /// size is rounded up to whole pages so both guards touch the tape.
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    pub fn guarded(size: usize) -> Self {
+        const PROT_NONE: i32 = 0;
+        const PROT_READ_WRITE: i32 = 3;
+
+        const MAP_PRIVATE:   i32 = 0x02;
+        const MAP_ANONYMOUS: i32 = 0x20;
+        const MAP_NORESERVE: i32 = 0x4000;
+
+        const PAGE_SIZE: usize = 4096;
+
+        extern "C" {
+            fn mmap(addr: *mut u8, length: usize, prot: i32, flags: i32,
+                    fd: i32, offset: usize) -> *mut u8;
+            fn mprotect(addr: *mut u8, length: usize, prot: i32) -> i32;
+        }
+
+        install_fault_handler();
+
+        let len = size.max(1).div_ceil(PAGE_SIZE) * PAGE_SIZE;
+        let mapping_size = GUARD_SIZE + len + GUARD_SIZE;
+
+        unsafe {
+            let base = mmap(std::ptr::null_mut(), mapping_size, PROT_NONE,
+                            MAP_PRIVATE | MAP_ANONYMOUS | MAP_NORESERVE, -1, 0);
+            assert!(base as isize != -1, "Could not map guarded tape");
+
+            let ret = mprotect(base.add(GUARD_SIZE), len, PROT_READ_WRITE);
+            assert!(ret == 0, "Could not unprotect guarded tape");
+
+            Tape::Guarded { base, mapping_size, len }
+        }
+    }
+
+    /// Guard pages are only supported on x86-64 Linux, elsewhere the tape
+    /// stays on the heap
+    #[cfg(not(all(target_os = "linux", target_arch = "x86_64")))]
+    pub fn guarded(size: usize) -> Self {
+        Tape::new(size)
+    }
+
+    /// Whether the tape is surrounded by guard pages
+    pub fn is_guarded(&self) -> bool {
+        matches!(self, Tape::Guarded { .. })
+    }
+
+    /// Mark this tape as the one being accessed by JIT code on the current
+    /// thread, so faults in its guards are recovered until the returned
+    /// scope is dropped
+    pub(crate) fn enter_jit(&self) -> JitScope {
+        #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+        if let Tape::Guarded { base, mapping_size, .. } = self {
+            let mapping = (*base as usize, *base as usize + mapping_size);
+            ACTIVE_MAPPING.with(|active| active.set(mapping));
+        }
+        JitScope(())
+    }
+}
+
+impl Deref for Tape {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Tape::Heap(memory) => memory,
+            Tape::Guarded { base, len, .. } => unsafe {
+                std::slice::from_raw_parts(base.add(GUARD_SIZE), *len)
+            },
+        }
+    }
+}
+
+impl DerefMut for Tape {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        match self {
+            Tape::Heap(memory) => memory,
+            Tape::Guarded { base, len, .. } => unsafe {
+                std::slice::from_raw_parts_mut(base.add(GUARD_SIZE), *len)
+            },
+        }
+    }
+}
+
+impl Drop for Tape {
+    fn drop(&mut self) {
+        #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+        if let Tape::Guarded { base, mapping_size, .. } = *self {
+            extern "C" {
+                fn munmap(addr: *mut u8, length: usize) -> i32;
+            }
+            unsafe { munmap(base, mapping_size); }
+        }
+    }
+}
+
+/// While alive, faults in the guards of the tape it was created from are
+/// turned into `GUARD_FAULT` returns from the JIT code
+pub(crate) struct JitScope(());
+
+impl Drop for JitScope {
+    fn drop(&mut self) {
+        #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+        ACTIVE_MAPPING.with(|active| active.set((0, 0)));
+    }
+}
+
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+thread_local! {
+    /// Mapping (start, end) of the guarded tape the JIT is running on
+    static ACTIVE_MAPPING: std::cell::Cell<(usize, usize)> =
+        const { std::cell::Cell::new((0, 0)) };
+}
+
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+mod signal {
+    /// glibc `struct sigaction` on x86-64
+    #[repr(C)]
+    pub struct SigAction {
+        pub sa_sigaction: usize,
+        pub sa_mask: [u64; 16],
+        pub sa_flags: i32,
+        pub sa_restorer: usize,
+    }
+
+    pub const SIGSEGV: i32 = 11;
+
+    pub const SA_SIGINFO: i32 = 0x4;
+    pub const SA_ONSTACK: i32 = 0x0800_0000;
+
+    pub const SIG_DFL: usize = 0;
+    pub const SIG_IGN: usize = 1;
+
+    /// Offset of `si_addr` in `siginfo_t`
+    pub const SI_ADDR: usize = 16;
+
+    /// Offset of `uc_mcontext.gregs` in `ucontext_t`
+    pub const GREGS: usize = 40;
+
+    pub const REG_RDX: usize = 12;
+    pub const REG_RAX: usize = 13;
+    pub const REG_RCX: usize = 14;
+    pub const REG_RSP: usize = 15;
+    pub const REG_RIP: usize = 16;
+
+    extern "C" {
+        pub fn sigaction(signum: i32, act: *const SigAction,
+                         oldact: *mut SigAction) -> i32;
+    }
+}
+
+/// The handler which was installed before ours, faults which are not in a
+/// guard region are forwarded to it
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+static mut PREVIOUS_HANDLER: Option<signal::SigAction> = None;
+
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+fn install_fault_handler() {
+    use signal::*;
+    static INSTALL: std::sync::Once = std::sync::Once::new();
+
+    INSTALL.call_once(|| unsafe {
+        let action = SigAction {
+            sa_sigaction: fault_handler as *const () as usize,
+            sa_mask: [0; 16],
+            sa_flags: SA_SIGINFO | SA_ONSTACK,
+            sa_restorer: 0,
+        };
+        let mut previous = std::mem::zeroed::<SigAction>();
+        assert!(sigaction(SIGSEGV, &action, &mut previous) == 0,
+                "Could not install SIGSEGV handler");
+        PREVIOUS_HANDLER = Some(previous);
+    });
+}
+
+/// Recover from a fault in a guard region of the active tape. JIT code never
+/// pushes onto the stack, so at the faulting instruction `rsp` points at the
+/// return address into `Emu::run_machine_code`: the handler performs that
+/// `ret` itself with `GUARD_FAULT` in `rax`, the faulting address in `rdx`,
+/// the data pointer or the cell a `MulAdd`, `Add` or `Set` targets, and the
+/// address of the faulting instruction in `rcx`.
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+extern "C" fn fault_handler(signum: i32, info: *mut u8, context: *mut u8) {
+    use signal::*;
+
+    unsafe {
+        let fault_addr = *(info.add(SI_ADDR) as *const usize);
+        let (start, end) = ACTIVE_MAPPING.with(|active| active.get());
+
+        if fault_addr >= start && fault_addr < end {
+            let gregs = context.add(GREGS) as *mut usize;
+            let rsp = *gregs.add(REG_RSP);
+
+            *gregs.add(REG_RDX) = fault_addr;
+            *gregs.add(REG_RAX) = GUARD_FAULT;
+            *gregs.add(REG_RCX) = *gregs.add(REG_RIP);
+            *gregs.add(REG_RIP) = *(rsp as *const usize);
+            *gregs.add(REG_RSP) = rsp + 8;
+            return;
+        }
+
+        // Not ours, hand the fault to whoever was installed before us
+        let previous = (*std::ptr::addr_of!(PREVIOUS_HANDLER)).as_ref()
+            .expect("SIGSEGV handler ran before it was installed");
+        match previous.sa_sigaction {
+            SIG_DFL | SIG_IGN => {
+                // Restore the default action, returning re-executes the
+                // faulting instruction which then kills the process
+                sigaction(signum, previous, std::ptr::null_mut());
+            },
+            handler if previous.sa_flags & SA_SIGINFO != 0 => {
+                let handler: extern "C" fn(i32, *mut u8, *mut u8) =
+                    std::mem::transmute(handler);
+                handler(signum, info, context);
+            },
+            handler => {
+                let handler: extern "C" fn(i32) = std::mem::transmute(handler);
+                handler(signum);
+            }
+        }
+    }
+}
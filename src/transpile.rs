@@ -0,0 +1,403 @@
+//! Source backends. The optimized IR `Engine::Vm3` runs is emitted as
+//! source, one statement per operation and a `while` loop per bracket:
+//!
+//! - `generate_c` writes a portable C99 program, which can be built with any
+//!   C compiler and checked against the engines.
+//! - `generate_rust` writes a self-contained Rust `run` function over a
+//!   caller provided tape, `Read` and `Write`, for embedding programs
+//!   without a JIT or any unsafe code.
+//!
+//! The generated code behaves like `Engine::Vm3` with the given options:
+//! cells wrap at their width, `,` follows the EOF policy and flushes the
+//! output first, the output is flushed at exit, and with bounds checks a
+//! pointer leaving the tape stops the program.
+
+use crate::aot::AotOptions;
+use crate::cell::CellWidth;
+use crate::emu::EofPolicy;
+use crate::ir::{BfOperation, Program};
+
+use std::fmt::Write;
+
+/// C type of a cell
+fn c_type(width: CellWidth) -> &'static str {
+    match width {
+        CellWidth::U8  => "uint8_t",
+        CellWidth::U16 => "uint16_t",
+        CellWidth::U32 => "uint32_t",
+        CellWidth::U64 => "uint64_t",
+    }
+}
+
+/// Rust type of a cell
+fn rust_type(width: CellWidth) -> &'static str {
+    match width {
+        CellWidth::U8  => "u8",
+        CellWidth::U16 => "u16",
+        CellWidth::U32 => "u32",
+        CellWidth::U64 => "u64",
+    }
+}
+
+/// C literal of a cell delta, wide enough for 64-bit cells
+fn c_literal(value: u64) -> String {
+    if value <= i32::MAX as u64 {
+        value.to_string()
+    } else {
+        format!("{}ull", value)
+    }
+}
+
+/// C literal of a multiply factor, unsigned so the product cannot overflow
+/// a signed `int`
+fn c_factor(value: u64) -> String {
+    if value <= u32::MAX as u64 {
+        format!("{}u", value)
+    } else {
+        format!("{}ull", value)
+    }
+}
+
+/// Whether the operation moves the data pointer
+fn moves_ptr(operation: &BfOperation) -> bool {
+    matches!(operation, BfOperation::IncPtr(_) | BfOperation::DecPtr(_)
+                      | BfOperation::ScanRight(_) | BfOperation::ScanLeft(_))
+}
+
+/// Whether the operation writes to the output
+fn writes_output(operation: &BfOperation) -> bool {
+    matches!(operation, BfOperation::WriteStdout | BfOperation::WriteConst(_))
+}
+
+/// Whether the operation is bounds checked
+fn is_checked(operation: &BfOperation) -> bool {
+    moves_ptr(operation) || matches!(operation, BfOperation::MulAdd { .. })
+        || matches!(operation, BfOperation::Add { offset, .. } | BfOperation::Set { offset, .. }
+                               if *offset != 0)
+}
+
+/// Write the bounds check of the cell `cells` away from `p`, changed by the
+/// operation at source `offset`, if `checks` is set and return the C
+/// expression of its index
+fn c_target(c: &mut String, indent: &str, cells: isize, offset: usize, checks: bool) -> String {
+    if cells == 0 {
+        return "p".to_string();
+    }
+    let distance = cells.unsigned_abs();
+    if cells < 0 {
+        if checks {
+            let _ = writeln!(c, "{}if (p < {}u) out_of_bounds({});", indent, distance, offset);
+        }
+        format!("p - {}u", distance)
+    } else {
+        if checks {
+            let _ = writeln!(c, "{}if (CELLS - p <= {}u) out_of_bounds({});", indent, distance, offset);
+        }
+        format!("p + {}u", distance)
+    }
+}
+
+/// Write the bounds check of the cell `cells` away from `p`, changed by the
+/// operation at source `offset`, if `checks` is set and return the Rust
+/// expression of its index
+fn rust_target(rust: &mut String, indent: &str, cells: isize, offset: usize, checks: bool) -> String {
+    if cells == 0 {
+        return "p".to_string();
+    }
+    let distance = cells.unsigned_abs();
+    if !checks {
+        let op = if cells < 0 { "wrapping_sub" } else { "wrapping_add" };
+        return format!("p.{}({})", op, distance);
+    }
+
+    let (out_of_bounds, target) = if cells < 0 {
+        (format!("p < {}", distance), format!("p - {}", distance))
+    } else {
+        (format!("tape.len() - p <= {}", distance), format!("p + {}", distance))
+    };
+    let _ = writeln!(rust, "{0}if {1} {{\n{0}    output.flush()?;\n\
+                            {0}    return Ok(Exit::PtrOob({2}));\n{0}}}",
+                     indent, out_of_bounds, offset);
+    target
+}
+
+/// Translate the program, optimized by `AotOptions::optimizer`, into C
+pub fn generate_c(program: &Program, options: &AotOptions) -> String {
+    let program = options.optimizer.run(program);
+    let width = options.jit.cell_width;
+    let cell = c_type(width);
+    let mut c = String::new();
+
+    // Only emit the helpers the program uses, they are static
+    let reads = program.ops.contains(&BfOperation::ReadStdin);
+    let writes = program.ops.iter().any(writes_output);
+    let checked = program.ops.iter().any(is_checked);
+
+    // Writing to a `String` cannot fail
+    let _ = write!(c, r#"/* Generated by BrainfuckRVm */
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+
+#define CELLS {cells}
+
+static {cell} tape[CELLS];
+"#, cells = options.tape_cells, cell = cell);
+
+    if writes {
+        c.push_str(r#"
+static void output(int byte) {
+    putchar(byte);
+    if (byte == '\n') fflush(stdout);
+}
+"#);
+    }
+    if reads {
+        c.push_str(r#"
+static int input(void) {
+    fflush(stdout);
+    return getchar();
+}
+"#);
+    }
+    if options.jit.bounds_checks && checked {
+        c.push_str(r#"
+static void out_of_bounds(long offset) {
+    fflush(stdout);
+    fprintf(stderr, "data pointer out of bounds at offset %ld\n", offset);
+    exit(2);
+}
+"#);
+    }
+
+    let _ = writeln!(c, "\nint main(void) {{\n    size_t p = 0;");
+    if reads {
+        c.push_str("    int c;\n");
+    }
+    let _ = writeln!(c, "\n    setvbuf(stdout, NULL, {}, 8192);\n",
+                     if options.unbuffered { "_IONBF" } else { "_IOFBF" });
+
+    let mut depth = 1;
+    for (idx, operation) in program.ops.iter().enumerate() {
+        let indent = "    ".repeat(depth);
+        let offset = program.offsets[idx];
+        let checks = options.jit.bounds_checks;
+
+        let _ = match *operation {
+            BfOperation::IncPtr(times) if checks => writeln!(c,
+                "{0}if (CELLS - p <= {1}u) out_of_bounds({2});\n{0}p += {1}u;",
+                indent, times, offset),
+            BfOperation::DecPtr(times) if checks => writeln!(c,
+                "{0}if (p < {1}u) out_of_bounds({2});\n{0}p -= {1}u;",
+                indent, times, offset),
+            BfOperation::IncPtr(times) => writeln!(c, "{}p += {}u;", indent, times),
+            BfOperation::DecPtr(times) => writeln!(c, "{}p -= {}u;", indent, times),
+            BfOperation::IncData(times) => {
+                writeln!(c, "{}tape[p] += {};", indent, c_literal(width.truncate(times)))
+            },
+            BfOperation::DecData(times) => {
+                writeln!(c, "{}tape[p] -= {};", indent, c_literal(width.truncate(times)))
+            },
+            BfOperation::WriteStdout => writeln!(c, "{}output((unsigned char)tape[p]);", indent),
+            BfOperation::WriteConst(byte) => writeln!(c, "{}output({});", indent, byte),
+            BfOperation::ReadStdin => match options.jit.eof_policy {
+                EofPolicy::Unchanged => writeln!(c,
+                    "{}if ((c = input()) != EOF) tape[p] = ({})c;", indent, cell),
+                EofPolicy::Zero => writeln!(c,
+                    "{}tape[p] = (c = input()) != EOF ? ({})c : 0;", indent, cell),
+                EofPolicy::MinusOne => writeln!(c,
+                    "{0}tape[p] = (c = input()) != EOF ? ({1})c : ({1})-1;", indent, cell),
+                EofPolicy::Stop => writeln!(c,
+                    "{0}if ((c = input()) == EOF) return 4;\n{0}tape[p] = ({1})c;", indent, cell),
+            },
+            BfOperation::SetZero => writeln!(c, "{}tape[p] = 0;", indent),
+            BfOperation::ScanRight(stride) if checks => writeln!(c,
+                "{0}while (tape[p]) {{\n{0}    if (CELLS - p <= {1}u) out_of_bounds({2});\n\
+                 {0}    p += {1}u;\n{0}}}", indent, stride, offset),
+            BfOperation::ScanLeft(stride) if checks => writeln!(c,
+                "{0}while (tape[p]) {{\n{0}    if (p < {1}u) out_of_bounds({2});\n\
+                 {0}    p -= {1}u;\n{0}}}", indent, stride, offset),
+            BfOperation::ScanRight(stride) => writeln!(c, "{}while (tape[p]) p += {}u;", indent, stride),
+            BfOperation::ScanLeft(stride) => writeln!(c, "{}while (tape[p]) p -= {}u;", indent, stride),
+            BfOperation::MulAdd { offset: cells, factor } => {
+                let target = c_target(&mut c, &indent, cells, offset, checks);
+                writeln!(c, "{}tape[{}] += tape[p] * {};", indent, target,
+                         c_factor(width.truncate(factor)))
+            },
+            BfOperation::Add { offset: cells, delta } => {
+                let target = c_target(&mut c, &indent, cells, offset, checks);
+                writeln!(c, "{}tape[{}] += {};", indent, target, c_literal(width.truncate(delta)))
+            },
+            BfOperation::Set { offset: cells, value } => {
+                let target = c_target(&mut c, &indent, cells, offset, checks);
+                writeln!(c, "{}tape[{}] = {};", indent, target, c_literal(width.truncate(value)))
+            },
+            BfOperation::LoopStart(_) => {
+                depth += 1;
+                writeln!(c, "{}while (tape[p]) {{", indent)
+            },
+            BfOperation::LoopEnd(_) => {
+                depth -= 1;
+                writeln!(c, "{}}}", "    ".repeat(depth))
+            },
+        };
+    }
+
+    c.push_str("    return fflush(stdout) == EOF ? 1 : 0;\n}\n");
+    c
+}
+
+/// Translate the program, optimized by `AotOptions::optimizer`, into Rust.
+/// The result is a module body with an `Exit` enum and a function
+///
+/// ```text
+/// pub fn run(tape: &mut [u8], input: &mut dyn Read, output: &mut dyn Write)
+///         -> io::Result<Exit>
+/// ```
+///
+/// whose tape element is the cell type. The size of the tape is that of the
+/// slice, `AotOptions::tape_cells` and `unbuffered` do not apply; wrap the
+/// output in a `BufWriter` to buffer it. Without bounds checks a pointer
+/// leaving the tape panics on the next access.
+pub fn generate_rust(program: &Program, options: &AotOptions) -> String {
+    let program = options.optimizer.run(program);
+    let width = options.jit.cell_width;
+    let cell = rust_type(width);
+    let checks = options.jit.bounds_checks;
+    let mut rust = String::new();
+
+    let reads = program.ops.contains(&BfOperation::ReadStdin);
+    let writes = program.ops.iter().any(writes_output);
+    let moves = program.ops.iter().any(moves_ptr);
+    let checked = program.ops.iter().any(is_checked);
+
+    // Writing to a `String` cannot fail
+    let _ = write!(rust, r#"// Generated by BrainfuckRVm
+
+use std::io::{{self, Read, Write}};
+
+/// How the program stopped
+#[allow(dead_code)]  // not every program can stop every way
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Exit {{
+    /// The program ran to completion
+    Done,
+
+    /// `,` found the end of the input
+    InputExhausted,
+
+    /// The data pointer left the tape at this source offset
+    PtrOob(usize),
+}}
+"#);
+
+    if reads {
+        rust.push_str(r#"
+/// Read one byte, `None` at the end of the input
+fn read_byte(input: &mut dyn Read) -> io::Result<Option<u8>> {
+    let mut buffer = [0; 1];
+    match input.read_exact(&mut buffer) {
+        Ok(()) => Ok(Some(buffer[0])),
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+"#);
+    }
+
+    let _ = writeln!(rust, r#"
+/// Run the program on `tape`, which must be all zeros, starting at its
+/// first cell
+#[allow(unused_assignments)]  // trailing pointer moves
+pub fn run(tape: &mut [{cell}], {input}: &mut dyn Read, {output}: &mut dyn Write)
+        -> io::Result<Exit> {{
+    let {mutable}p = 0usize;
+"#, cell = cell, input = if reads { "input" } else { "_input" },
+        output = if reads || writes || (checks && checked) { "output" } else { "_output" },
+        mutable = if moves { "mut " } else { "" });
+
+    let mut depth = 1;
+    for (idx, operation) in program.ops.iter().enumerate() {
+        let indent = "    ".repeat(depth);
+        let offset = program.offsets[idx];
+
+        let _ = match *operation {
+            BfOperation::IncPtr(times) if checks => writeln!(rust,
+                "{0}if tape.len() - p <= {1} {{\n{0}    output.flush()?;\n\
+                 {0}    return Ok(Exit::PtrOob({2}));\n{0}}}\n{0}p += {1};",
+                indent, times, offset),
+            BfOperation::DecPtr(times) if checks => writeln!(rust,
+                "{0}if p < {1} {{\n{0}    output.flush()?;\n\
+                 {0}    return Ok(Exit::PtrOob({2}));\n{0}}}\n{0}p -= {1};",
+                indent, times, offset),
+            BfOperation::IncPtr(times) => writeln!(rust, "{}p = p.wrapping_add({});", indent, times),
+            BfOperation::DecPtr(times) => writeln!(rust, "{}p = p.wrapping_sub({});", indent, times),
+            BfOperation::IncData(times) => writeln!(rust,
+                "{}tape[p] = tape[p].wrapping_add({});", indent, width.truncate(times)),
+            BfOperation::DecData(times) => writeln!(rust,
+                "{}tape[p] = tape[p].wrapping_sub({});", indent, width.truncate(times)),
+            BfOperation::WriteStdout if width == CellWidth::U8 => writeln!(rust,
+                "{}output.write_all(&[tape[p]])?;", indent),
+            BfOperation::WriteStdout => writeln!(rust,
+                "{}output.write_all(&[tape[p] as u8])?;", indent),
+            BfOperation::WriteConst(byte) => writeln!(rust, "{}output.write_all(&[{}])?;", indent, byte),
+            BfOperation::ReadStdin => {
+                let _ = writeln!(rust, "{}output.flush()?;", indent);
+                match options.jit.eof_policy {
+                    EofPolicy::Unchanged => writeln!(rust,
+                        "{0}if let Some(byte) = read_byte(input)? {{\n\
+                         {0}    tape[p] = byte.into();\n{0}}}", indent),
+                    EofPolicy::Zero => writeln!(rust,
+                        "{}tape[p] = read_byte(input)?.map_or(0, Into::into);", indent),
+                    EofPolicy::MinusOne => writeln!(rust,
+                        "{}tape[p] = read_byte(input)?.map_or({}::MAX, Into::into);", indent, cell),
+                    EofPolicy::Stop => writeln!(rust,
+                        "{0}match read_byte(input)? {{\n\
+                         {0}    Some(byte) => tape[p] = byte.into(),\n\
+                         {0}    None => return Ok(Exit::InputExhausted),\n{0}}}", indent),
+                }
+            },
+            BfOperation::SetZero => writeln!(rust, "{}tape[p] = 0;", indent),
+            BfOperation::ScanRight(stride) if checks => writeln!(rust,
+                "{0}while tape[p] != 0 {{\n{0}    if tape.len() - p <= {1} {{\n\
+                 {0}        output.flush()?;\n{0}        return Ok(Exit::PtrOob({2}));\n\
+                 {0}    }}\n{0}    p += {1};\n{0}}}", indent, stride, offset),
+            BfOperation::ScanLeft(stride) if checks => writeln!(rust,
+                "{0}while tape[p] != 0 {{\n{0}    if p < {1} {{\n\
+                 {0}        output.flush()?;\n{0}        return Ok(Exit::PtrOob({2}));\n\
+                 {0}    }}\n{0}    p -= {1};\n{0}}}", indent, stride, offset),
+            BfOperation::ScanRight(stride) => writeln!(rust,
+                "{0}while tape[p] != 0 {{\n{0}    p = p.wrapping_add({1});\n{0}}}", indent, stride),
+            BfOperation::ScanLeft(stride) => writeln!(rust,
+                "{0}while tape[p] != 0 {{\n{0}    p = p.wrapping_sub({1});\n{0}}}", indent, stride),
+            BfOperation::MulAdd { offset: cells, factor } => {
+                let target = rust_target(&mut rust, &indent, cells, offset, checks);
+                writeln!(rust, "{0}tape[{1}] = tape[{1}].wrapping_add(tape[p].wrapping_mul({2}));",
+                         indent, target, width.truncate(factor))
+            },
+            BfOperation::Add { offset: cells, delta } => {
+                let target = rust_target(&mut rust, &indent, cells, offset, checks);
+                writeln!(rust, "{0}tape[{1}] = tape[{1}].wrapping_add({2});",
+                         indent, target, width.truncate(delta))
+            },
+            BfOperation::Set { offset: cells, value } => {
+                let target = rust_target(&mut rust, &indent, cells, offset, checks);
+                writeln!(rust, "{}tape[{}] = {};", indent, target, width.truncate(value))
+            },
+            BfOperation::LoopStart(_) => {
+                depth += 1;
+                writeln!(rust, "{}while tape[p] != 0 {{", indent)
+            },
+            BfOperation::LoopEnd(_) => {
+                depth -= 1;
+                writeln!(rust, "{}}}", "    ".repeat(depth))
+            },
+        };
+    }
+
+    if writes || reads {
+        rust.push_str("\n    output.flush()?;\n");
+    }
+    rust.push_str("    Ok(Exit::Done)\n}\n");
+    rust
+}
@@ -0,0 +1,425 @@
+//! WebAssembly backend. The optimized IR is compiled into a module
+//! with a hand-written encoder, for running untrusted programs in a sandbox
+//! or in the browser.
+//!
+//! The module exports its linear memory as `memory`, which holds the tape
+//! in little-endian cells from address zero, and a function `run` taking no
+//! arguments. I/O goes through two imports:
+//!
+//! - `env.read() -> i32` returns the next input byte, or -1 at the end of
+//!   the input
+//! - `env.write(i32)` outputs the low byte of its argument
+//!
+//! `run` returns zero once the program finishes, -1 when `,` hit the end of
+//! the input with `EofPolicy::Stop`, and the source offset plus one of the
+//! operation which failed its bounds check. Without bounds checks a pointer
+//! leaving the tape stays inside the sandbox: accesses beyond the memory
+//! trap, but the tape is rounded up to whole 64 KiB pages.
+
+use crate::aot::AotOptions;
+use crate::cell::CellWidth;
+use crate::emu::EofPolicy;
+use crate::ir::{BfOperation, Program};
+
+use std::convert::TryFrom;
+
+/// Size of a WebAssembly memory page
+pub const PAGE_SIZE: usize = 64 * 1024;
+
+/// Returned by `run` when the input ran out with `EofPolicy::Stop`
+pub const INPUT_EXHAUSTED: i32 = -1;
+
+/// Value type of the data pointer and of bytes
+const I32: u8 = 0x7f;
+
+/// Function indices, the imports come first
+const READ: u32 = 0;
+const WRITE: u32 = 1;
+const RUN: u32 = 2;
+
+/// Locals of `run`
+const PTR: u32 = 0;
+const BYTE: u32 = 1;
+const TARGET: u32 = 2;
+
+/// Opcodes
+mod op {
+    pub const BLOCK: u8 = 0x02;
+    pub const LOOP: u8 = 0x03;
+    pub const IF: u8 = 0x04;
+    pub const ELSE: u8 = 0x05;
+    pub const END: u8 = 0x0b;
+    pub const BR: u8 = 0x0c;
+    pub const BR_IF: u8 = 0x0d;
+    pub const RETURN: u8 = 0x0f;
+    pub const CALL: u8 = 0x10;
+    pub const LOCAL_GET: u8 = 0x20;
+    pub const LOCAL_SET: u8 = 0x21;
+    pub const LOCAL_TEE: u8 = 0x22;
+    pub const I32_CONST: u8 = 0x41;
+    pub const I64_CONST: u8 = 0x42;
+    pub const I32_EQZ: u8 = 0x45;
+    pub const I32_LT_S: u8 = 0x48;
+    pub const I32_LT_U: u8 = 0x49;
+    pub const I32_GE_U: u8 = 0x4f;
+    pub const I64_EQZ: u8 = 0x50;
+    pub const I32_ADD: u8 = 0x6a;
+    pub const I32_SUB: u8 = 0x6b;
+    pub const I32_MUL: u8 = 0x6c;
+    pub const I64_ADD: u8 = 0x7c;
+    pub const I64_SUB: u8 = 0x7d;
+    pub const I64_MUL: u8 = 0x7e;
+    pub const I64_EXTEND_I32_U: u8 = 0xad;
+
+    /// Block type of blocks without results
+    pub const EMPTY: u8 = 0x40;
+}
+
+/// Append `value` as unsigned LEB128
+fn uleb(out: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = value as u8 & 0x7f;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+/// Append `value` as signed LEB128
+fn sleb(out: &mut Vec<u8>, mut value: i64) {
+    loop {
+        let byte = value as u8 & 0x7f;
+        value >>= 7;
+        if (value == 0 && byte & 0x40 == 0) || (value == -1 && byte & 0x40 != 0) {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+/// Append a length prefixed name
+fn name(out: &mut Vec<u8>, name: &str) {
+    uleb(out, name.len() as u64);
+    out.extend_from_slice(name.as_bytes());
+}
+
+/// Append a section with its id and size
+fn section(out: &mut Vec<u8>, id: u8, contents: &[u8]) {
+    out.push(id);
+    uleb(out, contents.len() as u64);
+    out.extend_from_slice(contents);
+}
+
+/// Body of the `run` function being assembled
+struct Code {
+    code: Vec<u8>,
+
+    width: CellWidth,
+}
+
+impl Code {
+    fn op(&mut self, op: u8) {
+        self.code.push(op);
+    }
+
+    /// An instruction with one index or block type immediate
+    fn op_index(&mut self, op: u8, index: u32) {
+        self.code.push(op);
+        uleb(&mut self.code, index as u64);
+    }
+
+    fn i32_const(&mut self, value: i32) {
+        self.code.push(op::I32_CONST);
+        sleb(&mut self.code, value as i64);
+    }
+
+    /// Push a cell sized constant
+    fn cell_const(&mut self, value: u64) {
+        if self.width == CellWidth::U64 {
+            self.code.push(op::I64_CONST);
+            sleb(&mut self.code, value as i64);
+        } else {
+            self.i32_const(value as i32);
+        }
+    }
+
+    /// Load the current cell, the data pointer is on the stack
+    fn load(&mut self) {
+        let opcode = match self.width {
+            CellWidth::U8  => 0x2d,  // i32.load8_u
+            CellWidth::U16 => 0x2f,  // i32.load16_u
+            CellWidth::U32 => 0x28,  // i32.load
+            CellWidth::U64 => 0x29,  // i64.load
+        };
+        self.mem_op(opcode);
+    }
+
+    /// Store the current cell, the data pointer and value are on the stack
+    fn store(&mut self) {
+        let opcode = match self.width {
+            CellWidth::U8  => 0x3a,  // i32.store8
+            CellWidth::U16 => 0x3b,  // i32.store16
+            CellWidth::U32 => 0x36,  // i32.store
+            CellWidth::U64 => 0x37,  // i64.store
+        };
+        self.mem_op(opcode);
+    }
+
+    /// A load or store at offset zero with the natural alignment
+    fn mem_op(&mut self, opcode: u8) {
+        self.code.push(opcode);
+        uleb(&mut self.code, self.width.bytes().trailing_zeros() as u64);
+        uleb(&mut self.code, 0);
+    }
+
+    /// Add `bytes` to the data pointer, or subtract them
+    fn move_ptr(&mut self, arith: u8, bytes: usize) {
+        self.op_index(op::LOCAL_GET, PTR);
+        self.i32_const(i32::try_from(bytes).expect("pointer movement too large"));
+        self.op(arith);
+        self.op_index(op::LOCAL_SET, PTR);
+    }
+
+    /// Move the data pointer right by `bytes`, returning `status` when it
+    /// leaves a tape of `tape_size` bytes with `checked`
+    fn inc_ptr(&mut self, bytes: usize, checked: bool, tape_size: usize, status: i32) {
+        self.move_ptr(op::I32_ADD, bytes);
+        if checked {
+            self.op_index(op::LOCAL_GET, PTR);
+            self.i32_const(i32::try_from(tape_size).expect("tape too large"));
+            self.op(op::I32_GE_U);
+            self.return_if(status);
+        }
+    }
+
+    /// Move the data pointer left by `bytes`, returning `status` when it
+    /// would leave the tape with `checked`
+    fn dec_ptr(&mut self, bytes: usize, checked: bool, status: i32) {
+        if checked {
+            self.op_index(op::LOCAL_GET, PTR);
+            self.i32_const(i32::try_from(bytes).expect("pointer movement too large"));
+            self.op(op::I32_LT_U);
+            self.return_if(status);
+        }
+        self.move_ptr(op::I32_SUB, bytes);
+    }
+
+    /// Return `status` if the condition on the stack holds
+    fn return_if(&mut self, status: i32) {
+        self.op_index(op::IF, op::EMPTY as u32);
+        self.i32_const(status);
+        self.op(op::RETURN);
+        self.op(op::END);
+    }
+
+    /// Set the `TARGET` local to the address of the cell `bytes` away from
+    /// the current one, returning `status` when it is off a tape of
+    /// `tape_size` bytes with `checked`. Without checks a negative target
+    /// wraps around to an address beyond the memory and traps.
+    fn target(&mut self, bytes: isize, checked: bool, tape_size: usize, status: i32) {
+        let distance = i32::try_from(bytes.unsigned_abs()).expect("cell offset too large");
+        if checked && bytes < 0 {
+            self.op_index(op::LOCAL_GET, PTR);
+            self.i32_const(distance);
+            self.op(op::I32_LT_U);
+            self.return_if(status);
+        }
+
+        self.op_index(op::LOCAL_GET, PTR);
+        self.i32_const(distance);
+        self.op(if bytes < 0 { op::I32_SUB } else { op::I32_ADD });
+        self.op_index(op::LOCAL_SET, TARGET);
+        if checked && bytes > 0 {
+            self.op_index(op::LOCAL_GET, TARGET);
+            self.i32_const(i32::try_from(tape_size).expect("tape too large"));
+            self.op(op::I32_GE_U);
+            self.return_if(status);
+        }
+    }
+
+    /// Store `value` in the current cell
+    fn store_const(&mut self, value: u64) {
+        self.op_index(op::LOCAL_GET, PTR);
+        self.cell_const(value);
+        self.store();
+    }
+}
+
+/// Compile the program, optimized by `AotOptions::optimizer`, into a binary
+/// WebAssembly module with a tape of `AotOptions::tape_cells`
+pub fn generate_wasm(program: &Program, options: &AotOptions) -> Vec<u8> {
+    let program = options.optimizer.run(program);
+    let width = options.jit.cell_width;
+    let cell_size = width.bytes();
+    let tape_size = options.tape_cells * cell_size;
+    let checked = options.jit.bounds_checks;
+    let (add, sub, mul) = if width == CellWidth::U64 {
+        (op::I64_ADD, op::I64_SUB, op::I64_MUL)
+    } else {
+        (op::I32_ADD, op::I32_SUB, op::I32_MUL)
+    };
+    let eqz = if width == CellWidth::U64 { op::I64_EQZ } else { op::I32_EQZ };
+
+    let mut code = Code { code: Vec::new(), width };
+    for (idx, operation) in program.ops.iter().enumerate() {
+        let status = i32::try_from(program.offsets[idx] + 1).expect("program too large");
+
+        match *operation {
+            BfOperation::IncPtr(times) => code.inc_ptr(times * cell_size, checked, tape_size, status),
+            BfOperation::DecPtr(times) => code.dec_ptr(times * cell_size, checked, status),
+            BfOperation::IncData(times) | BfOperation::DecData(times) => {
+                let arith = if matches!(operation, BfOperation::IncData(_)) { add } else { sub };
+                code.op_index(op::LOCAL_GET, PTR);
+                code.op_index(op::LOCAL_GET, PTR);
+                code.load();
+                code.cell_const(width.truncate(times));
+                code.op(arith);
+                code.store();
+            },
+            BfOperation::WriteStdout => {
+                // Cells are little-endian, the low byte is the first one
+                code.op_index(op::LOCAL_GET, PTR);
+                code.code.push(0x2d);  // i32.load8_u
+                uleb(&mut code.code, 0);
+                uleb(&mut code.code, 0);
+                code.op_index(op::CALL, WRITE);
+            },
+            BfOperation::ReadStdin => {
+                code.op_index(op::CALL, READ);
+                code.op_index(op::LOCAL_TEE, BYTE);
+                code.i32_const(0);
+                code.op(op::I32_LT_S);
+                code.op_index(op::IF, op::EMPTY as u32);
+                match options.jit.eof_policy {
+                    EofPolicy::Unchanged => {},
+                    EofPolicy::Zero => code.store_const(0),
+                    EofPolicy::MinusOne => code.store_const(width.truncate(u64::MAX)),
+                    EofPolicy::Stop => {
+                        code.i32_const(INPUT_EXHAUSTED);
+                        code.op(op::RETURN);
+                    },
+                }
+                code.op(op::ELSE);
+                code.op_index(op::LOCAL_GET, PTR);
+                code.op_index(op::LOCAL_GET, BYTE);
+                if width == CellWidth::U64 {
+                    code.op(op::I64_EXTEND_I32_U);
+                }
+                code.store();
+                code.op(op::END);
+            },
+            BfOperation::SetZero => code.store_const(0),
+            BfOperation::ScanRight(stride) | BfOperation::ScanLeft(stride) => {
+                code.op_index(op::BLOCK, op::EMPTY as u32);
+                code.op_index(op::LOOP, op::EMPTY as u32);
+                code.op_index(op::LOCAL_GET, PTR);
+                code.load();
+                code.op(eqz);
+                code.op_index(op::BR_IF, 1);
+                if matches!(operation, BfOperation::ScanRight(_)) {
+                    code.inc_ptr(stride * cell_size, checked, tape_size, status);
+                } else {
+                    code.dec_ptr(stride * cell_size, checked, status);
+                }
+                code.op_index(op::BR, 0);
+                code.op(op::END);
+                code.op(op::END);
+            },
+            BfOperation::MulAdd { offset, factor } => {
+                code.target(offset * cell_size as isize, checked, tape_size, status);
+                code.op_index(op::LOCAL_GET, TARGET);
+                code.op_index(op::LOCAL_GET, TARGET);
+                code.load();
+                code.op_index(op::LOCAL_GET, PTR);
+                code.load();
+                code.cell_const(width.truncate(factor));
+                code.op(mul);
+                code.op(add);
+                code.store();
+            },
+            BfOperation::Add { offset, delta } => {
+                code.target(offset * cell_size as isize, checked, tape_size, status);
+                code.op_index(op::LOCAL_GET, TARGET);
+                code.op_index(op::LOCAL_GET, TARGET);
+                code.load();
+                code.cell_const(width.truncate(delta));
+                code.op(add);
+                code.store();
+            },
+            BfOperation::Set { offset, value } => {
+                code.target(offset * cell_size as isize, checked, tape_size, status);
+                code.op_index(op::LOCAL_GET, TARGET);
+                code.cell_const(width.truncate(value));
+                code.store();
+            },
+            BfOperation::WriteConst(byte) => {
+                code.i32_const(byte.into());
+                code.op_index(op::CALL, WRITE);
+            },
+            BfOperation::LoopStart(_) => {
+                // Leave the outer block when the cell is zero, `]` jumps
+                // back to the inner loop
+                code.op_index(op::BLOCK, op::EMPTY as u32);
+                code.op_index(op::LOOP, op::EMPTY as u32);
+                code.op_index(op::LOCAL_GET, PTR);
+                code.load();
+                code.op(eqz);
+                code.op_index(op::BR_IF, 1);
+            },
+            BfOperation::LoopEnd(_) => {
+                code.op_index(op::BR, 0);
+                code.op(op::END);
+                code.op(op::END);
+            },
+        }
+    }
+    code.i32_const(0);
+    code.op(op::END);
+
+    let mut module = Vec::new();
+    module.extend_from_slice(b"\0asm");
+    module.extend_from_slice(&1u32.to_le_bytes());
+
+    // Types: () -> i32 for `read` and `run`, (i32) -> () for `write`
+    section(&mut module, 1, &[2, 0x60, 0, 1, I32, 0x60, 1, I32, 0]);
+
+    let mut imports = vec![2];
+    for (field, kind) in &[("read", 0), ("write", 1)] {
+        name(&mut imports, "env");
+        name(&mut imports, field);
+        imports.extend_from_slice(&[0x00, *kind]);
+    }
+    section(&mut module, 2, &imports);
+
+    section(&mut module, 3, &[1, 0]);
+
+    // One memory of fixed size holding the tape
+    let pages = tape_size.div_ceil(PAGE_SIZE).max(1) as u64;
+    let mut memory = vec![1, 0x01];
+    uleb(&mut memory, pages);
+    uleb(&mut memory, pages);
+    section(&mut module, 5, &memory);
+
+    let mut exports = vec![2];
+    name(&mut exports, "run");
+    exports.push(0x00);
+    uleb(&mut exports, RUN as u64);
+    name(&mut exports, "memory");
+    exports.extend_from_slice(&[0x02, 0]);
+    section(&mut module, 7, &exports);
+
+    // The body declares the data pointer, the byte read and the address a
+    // `MulAdd`, `Add` and `Set` targets as locals
+    let mut body = vec![1, 3, I32];
+    body.extend_from_slice(&code.code);
+    let mut bodies = vec![1];
+    uleb(&mut bodies, body.len() as u64);
+    bodies.extend_from_slice(&body);
+    section(&mut module, 10, &bodies);
+
+    module
+}
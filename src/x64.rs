@@ -0,0 +1,629 @@
+//! A small x86-64 encoder covering the instructions the JIT emits. Branches
+//! go to `Label`s: backward branches use a rel8 displacement when it fits,
+//! forward branches a rel32 one which is patched once the label is bound.
+//!
+//! With the `keystone` feature every instruction is also kept as Intel
+//! syntax text, and `Assembler::cross_check` compares the encoding against
+//! the one Keystone produces.
+
+use crate::cell::CellWidth;
+
+use std::convert::TryFrom;
+
+/// General purpose registers, in encoding order
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Reg {
+    Rax, Rcx, Rdx, Rbx, Rsp, Rbp, Rsi, Rdi,
+    R8, R9, R10, R11, R12, R13, R14, R15,
+}
+
+impl Reg {
+    /// Low three bits of the register number, used in ModRM and SIB
+    fn low(self) -> u8 {
+        self as u8 & 7
+    }
+
+    /// Whether the register needs a REX extension bit
+    fn extended(self) -> bool {
+        self as u8 >= 8
+    }
+
+    /// Name of the register at `size`
+    pub fn name(self, size: Size) -> &'static str {
+        const NAMES: [[&str; 16]; 4] = [
+            ["al", "cl", "dl", "bl", "spl", "bpl", "sil", "dil",
+             "r8b", "r9b", "r10b", "r11b", "r12b", "r13b", "r14b", "r15b"],
+            ["ax", "cx", "dx", "bx", "sp", "bp", "si", "di",
+             "r8w", "r9w", "r10w", "r11w", "r12w", "r13w", "r14w", "r15w"],
+            ["eax", "ecx", "edx", "ebx", "esp", "ebp", "esi", "edi",
+             "r8d", "r9d", "r10d", "r11d", "r12d", "r13d", "r14d", "r15d"],
+            ["rax", "rcx", "rdx", "rbx", "rsp", "rbp", "rsi", "rdi",
+             "r8", "r9", "r10", "r11", "r12", "r13", "r14", "r15"],
+        ];
+        NAMES[size as usize][self as usize]
+    }
+}
+
+/// Operand sizes
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Size {
+    Byte,
+    Word,
+    Dword,
+    Qword,
+}
+
+impl Size {
+    /// Intel syntax size keyword of a memory operand
+    pub fn name(self) -> &'static str {
+        match self {
+            Size::Byte  => "byte",
+            Size::Word  => "word",
+            Size::Dword => "dword",
+            Size::Qword => "qword",
+        }
+    }
+
+    /// Interpret the low bits of `imm` as a signed value of this size
+    fn sign_extend(self, imm: i64) -> i64 {
+        match self {
+            Size::Byte  => imm as i8 as i64,
+            Size::Word  => imm as i16 as i64,
+            Size::Dword => imm as i32 as i64,
+            Size::Qword => imm,
+        }
+    }
+}
+
+impl From<CellWidth> for Size {
+    fn from(width: CellWidth) -> Size {
+        match width {
+            CellWidth::U8  => Size::Byte,
+            CellWidth::U16 => Size::Word,
+            CellWidth::U32 => Size::Dword,
+            CellWidth::U64 => Size::Qword,
+        }
+    }
+}
+
+/// A memory operand `[base + disp]`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Mem {
+    pub base: Reg,
+    pub disp: i32,
+}
+
+impl Mem {
+    pub fn new(base: Reg, disp: i32) -> Mem {
+        Mem { base, disp }
+    }
+
+    /// Intel syntax text of the operand at `size`
+    pub(crate) fn text(self, size: Size) -> String {
+        match self.disp {
+            0 => format!("{} ptr [{}]", size.name(), self.base.name(Size::Qword)),
+            disp if disp < 0 => format!("{} ptr [{} - 0x{:x}]", size.name(),
+                                        self.base.name(Size::Qword), -(disp as i64)),
+            disp => format!("{} ptr [{} + 0x{:x}]", size.name(),
+                            self.base.name(Size::Qword), disp),
+        }
+    }
+}
+
+/// Arithmetic operations sharing the `/digit` encodings
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Alu {
+    Add = 0,
+    Sub = 5,
+    Cmp = 7,
+}
+
+impl Alu {
+    fn name(self) -> &'static str {
+        match self {
+            Alu::Add => "add",
+            Alu::Sub => "sub",
+            Alu::Cmp => "cmp",
+        }
+    }
+}
+
+/// Branch conditions
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Cond {
+    /// Unsigned below (`jb`)
+    Below = 0x2,
+
+    /// Unsigned above or equal (`jae`)
+    AboveEqual = 0x3,
+
+    /// Equal or zero (`je`, `jz`)
+    Equal = 0x4,
+
+    /// Not equal or not zero (`jne`, `jnz`)
+    NotEqual = 0x5,
+
+    /// Unsigned above (`ja`)
+    Above = 0x7,
+
+    /// Negative (`js`)
+    Sign = 0x8,
+}
+
+/// A branch target, bound to a position with `Assembler::bind`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Label(usize);
+
+/// A rel32 displacement waiting for its label to be bound
+struct Fixup {
+    /// Position of the displacement in the code
+    at: usize,
+
+    label: Label,
+}
+
+/// One encoded instruction as Intel syntax text, for cross-checking
+#[cfg(feature = "keystone")]
+struct Listing {
+    /// Position and length of the encoding in the code
+    at: usize,
+    len: usize,
+
+    /// Text of the instruction, `None` for branches
+    text: Option<String>,
+
+    /// Target of a branch
+    label: Option<Label>,
+}
+
+/// Machine code being assembled
+#[derive(Default)]
+pub struct Assembler {
+    code: Vec<u8>,
+
+    /// Position of every label, once bound
+    labels: Vec<Option<usize>>,
+
+    fixups: Vec<Fixup>,
+
+    #[cfg(feature = "keystone")]
+    listing: Vec<Listing>,
+}
+
+impl Assembler {
+    pub fn new() -> Self {
+        Assembler::default()
+    }
+
+    /// Current position in the code
+    pub fn position(&self) -> usize {
+        self.code.len()
+    }
+
+    /// Create a label which is not bound yet
+    pub fn new_label(&mut self) -> Label {
+        self.labels.push(None);
+        Label(self.labels.len() - 1)
+    }
+
+    /// Bind `label` to the current position
+    pub fn bind(&mut self, label: Label) {
+        assert!(self.labels[label.0].is_none(), "label bound twice");
+        self.labels[label.0] = Some(self.code.len());
+    }
+
+    /// Resolve all branches and return the machine code
+    pub fn finish(mut self) -> Vec<u8> {
+        self.resolve();
+        self.code
+    }
+
+    /// Patch the rel32 displacement of every forward branch
+    fn resolve(&mut self) {
+        for fixup in self.fixups.drain(..) {
+            let target = self.labels[fixup.label.0].expect("branch to unbound label");
+            let rel = target as i64 - (fixup.at as i64 + 4);
+            let rel = i32::try_from(rel).expect("branch out of rel32 range");
+            self.code[fixup.at..fixup.at + 4].copy_from_slice(&rel.to_le_bytes());
+        }
+    }
+
+    /// Record the text of the instruction encoded since `at`
+    #[cfg(feature = "keystone")]
+    fn list(&mut self, at: usize, text: impl FnOnce() -> String, label: Option<Label>) {
+        let len = self.code.len() - at;
+        let text = if label.is_none() { Some(text()) } else { None };
+        self.listing.push(Listing { at, len, text, label });
+    }
+
+    #[cfg(not(feature = "keystone"))]
+    fn list(&mut self, _at: usize, _text: impl FnOnce() -> String, _label: Option<Label>) {}
+
+    /// Emit a REX prefix if any of its bits are set or `force`d
+    fn rex(&mut self, w: bool, r: bool, b: bool, force: bool) {
+        let rex = 0x40 | (w as u8) << 3 | (r as u8) << 2 | b as u8;
+        if rex != 0x40 || force {
+            self.code.push(rex);
+        }
+    }
+
+    /// Emit the prefixes for an operation of `size` with `reg` in the ModRM
+    /// reg field and `rm` in the r/m field
+    fn prefixes(&mut self, size: Size, reg: Option<Reg>, rm: Reg) {
+        if size == Size::Word {
+            self.code.push(0x66);
+        }
+        // spl, bpl, sil and dil only exist with a REX prefix
+        let force = size == Size::Byte && reg.is_some_and(|reg| (4..8).contains(&(reg as u8)));
+        self.rex(size == Size::Qword, reg.is_some_and(Reg::extended), rm.extended(), force);
+    }
+
+    /// ModRM byte for a register operand
+    fn modrm_reg(&mut self, reg: u8, rm: Reg) {
+        self.code.push(0xc0 | (reg & 7) << 3 | rm.low());
+    }
+
+    /// ModRM, SIB and displacement for a memory operand
+    fn modrm_mem(&mut self, reg: u8, mem: Mem) {
+        // rbp and r13 have no encoding without a displacement
+        let mode = if mem.disp == 0 && mem.base.low() != 5 {
+            0x00
+        } else if i8::try_from(mem.disp).is_ok() {
+            0x40
+        } else {
+            0x80
+        };
+        self.code.push(mode | (reg & 7) << 3 | mem.base.low());
+
+        // rsp and r12 as a base always need a SIB byte
+        if mem.base.low() == 4 {
+            self.code.push(0x24);
+        }
+
+        match mode {
+            0x40 => self.code.push(mem.disp as u8),
+            0x80 => self.code.extend_from_slice(&mem.disp.to_le_bytes()),
+            _ => {}
+        }
+    }
+
+    /// Emit an immediate of `size`, at most 32 bits
+    fn imm(&mut self, size: Size, imm: i64) {
+        match size {
+            Size::Byte  => self.code.push(imm as u8),
+            Size::Word  => self.code.extend_from_slice(&(imm as u16).to_le_bytes()),
+            _           => self.code.extend_from_slice(&(imm as u32).to_le_bytes()),
+        }
+    }
+
+    /// `op reg, imm` on a 64-bit register
+    pub fn alu_reg_imm(&mut self, op: Alu, reg: Reg, imm: i32) {
+        let at = self.position();
+        self.prefixes(Size::Qword, None, reg);
+        if i8::try_from(imm).is_ok() {
+            self.code.push(0x83);
+            self.modrm_reg(op as u8, reg);
+            self.code.push(imm as u8);
+        } else if reg == Reg::Rax {
+            self.code.push((op as u8) << 3 | 0x05);
+            self.imm(Size::Dword, imm as i64);
+        } else {
+            self.code.push(0x81);
+            self.modrm_reg(op as u8, reg);
+            self.imm(Size::Dword, imm as i64);
+        }
+        self.list(at, || format!("{} {}, 0x{:x}", op.name(), reg.name(Size::Qword), imm), None);
+    }
+
+    /// `op size ptr [mem], imm`. The immediate is truncated to `size`,
+    /// qword operations take a sign extended 32-bit immediate.
+    pub fn alu_mem_imm(&mut self, op: Alu, size: Size, mem: Mem, imm: i64) {
+        let imm = size.sign_extend(imm);
+        assert!(i32::try_from(imm).is_ok(), "immediate does not fit 32 bits");
+
+        let at = self.position();
+        self.prefixes(size, None, mem.base);
+        if size == Size::Byte {
+            self.code.push(0x80);
+            self.modrm_mem(op as u8, mem);
+            self.code.push(imm as u8);
+        } else if i8::try_from(imm).is_ok() {
+            self.code.push(0x83);
+            self.modrm_mem(op as u8, mem);
+            self.code.push(imm as u8);
+        } else {
+            self.code.push(0x81);
+            self.modrm_mem(op as u8, mem);
+            self.imm(size, imm);
+        }
+        self.list(at, || format!("{} {}, {}", op.name(), mem.text(size), imm), None);
+    }
+
+    /// `op size ptr [mem], reg`
+    pub fn alu_mem_reg(&mut self, op: Alu, size: Size, mem: Mem, reg: Reg) {
+        let at = self.position();
+        self.prefixes(size, Some(reg), mem.base);
+        let opcode = (op as u8) << 3 | if size == Size::Byte { 0x00 } else { 0x01 };
+        self.code.push(opcode);
+        self.modrm_mem(reg.low(), mem);
+        self.list(at, || format!("{} {}, {}", op.name(), mem.text(size), reg.name(size)), None);
+    }
+
+    /// `op dst, src` on 64-bit registers
+    pub fn alu_reg_reg(&mut self, op: Alu, dst: Reg, src: Reg) {
+        let at = self.position();
+        self.prefixes(Size::Qword, Some(src), dst);
+        self.code.push((op as u8) << 3 | 0x01);
+        self.modrm_reg(src.low(), dst);
+        self.list(at, || format!("{} {}, {}", op.name(), dst.name(Size::Qword), src.name(Size::Qword)), None);
+    }
+
+    /// `cmp a, b` on 64-bit registers
+    pub fn cmp_reg_reg(&mut self, a: Reg, b: Reg) {
+        let at = self.position();
+        self.prefixes(Size::Qword, Some(b), a);
+        self.code.push(0x39);
+        self.modrm_reg(b.low(), a);
+        self.list(at, || format!("cmp {}, {}", a.name(Size::Qword), b.name(Size::Qword)), None);
+    }
+
+    /// `test a, b` on 64-bit registers
+    pub fn test_reg_reg(&mut self, a: Reg, b: Reg) {
+        let at = self.position();
+        self.prefixes(Size::Qword, Some(b), a);
+        self.code.push(0x85);
+        self.modrm_reg(b.low(), a);
+        self.list(at, || format!("test {}, {}", a.name(Size::Qword), b.name(Size::Qword)), None);
+    }
+
+    /// `inc reg` on a 64-bit register
+    pub fn inc(&mut self, reg: Reg) {
+        let at = self.position();
+        self.prefixes(Size::Qword, None, reg);
+        self.code.push(0xff);
+        self.modrm_reg(0, reg);
+        self.list(at, || format!("inc {}", reg.name(Size::Qword)), None);
+    }
+
+    /// `dec reg` on a 64-bit register
+    pub fn dec(&mut self, reg: Reg) {
+        let at = self.position();
+        self.prefixes(Size::Qword, None, reg);
+        self.code.push(0xff);
+        self.modrm_reg(1, reg);
+        self.list(at, || format!("dec {}", reg.name(Size::Qword)), None);
+    }
+
+    /// `mov dst, src` on 64-bit registers
+    pub fn mov_reg_reg(&mut self, dst: Reg, src: Reg) {
+        let at = self.position();
+        self.prefixes(Size::Qword, Some(src), dst);
+        self.code.push(0x89);
+        self.modrm_reg(src.low(), dst);
+        self.list(at, || format!("mov {}, {}", dst.name(Size::Qword), src.name(Size::Qword)), None);
+    }
+
+    /// `mov reg, imm` on a 64-bit register, with the short sign extended
+    /// form when the immediate fits
+    pub fn mov_reg_imm(&mut self, reg: Reg, imm: u64) {
+        let at = self.position();
+        self.prefixes(Size::Qword, None, reg);
+        if i32::try_from(imm as i64).is_ok() {
+            self.code.push(0xc7);
+            self.modrm_reg(0, reg);
+            self.imm(Size::Dword, imm as i64);
+        } else {
+            self.code.push(0xb8 | reg.low());
+            self.code.extend_from_slice(&imm.to_le_bytes());
+        }
+        self.list(at, || format!("mov {}, 0x{:x}", reg.name(Size::Qword), imm), None);
+    }
+
+    /// `mov reg, qword ptr [mem]`
+    pub fn mov_reg_mem(&mut self, reg: Reg, mem: Mem) {
+        let at = self.position();
+        self.prefixes(Size::Qword, Some(reg), mem.base);
+        self.code.push(0x8b);
+        self.modrm_mem(reg.low(), mem);
+        self.list(at, || format!("mov {}, {}", reg.name(Size::Qword), mem.text(Size::Qword)), None);
+    }
+
+    /// `mov size ptr [mem], reg`
+    pub fn mov_mem_reg(&mut self, size: Size, mem: Mem, reg: Reg) {
+        let at = self.position();
+        self.prefixes(size, Some(reg), mem.base);
+        self.code.push(if size == Size::Byte { 0x88 } else { 0x89 });
+        self.modrm_mem(reg.low(), mem);
+        self.list(at, || format!("mov {}, {}", mem.text(size), reg.name(size)), None);
+    }
+
+    /// `mov size ptr [mem], imm`, truncated like `alu_mem_imm`
+    pub fn mov_mem_imm(&mut self, size: Size, mem: Mem, imm: i64) {
+        let imm = size.sign_extend(imm);
+        assert!(i32::try_from(imm).is_ok(), "immediate does not fit 32 bits");
+
+        let at = self.position();
+        self.prefixes(size, None, mem.base);
+        self.code.push(if size == Size::Byte { 0xc6 } else { 0xc7 });
+        self.modrm_mem(0, mem);
+        self.imm(size, imm);
+        self.list(at, || format!("mov {}, {}", mem.text(size), imm), None);
+    }
+
+    /// `movzx reg32, byte ptr [mem]`, which also clears the upper half of
+    /// the 64-bit register
+    pub fn movzx_reg_mem8(&mut self, reg: Reg, mem: Mem) {
+        let at = self.position();
+        self.prefixes(Size::Dword, Some(reg), mem.base);
+        self.code.extend_from_slice(&[0x0f, 0xb6]);
+        self.modrm_mem(reg.low(), mem);
+        self.list(at, || format!("movzx {}, {}", reg.name(Size::Dword), mem.text(Size::Byte)), None);
+    }
+
+    /// Load a `size` value from `[mem]` zero extended into the 64-bit
+    /// register: `movzx` for bytes and words, a plain `mov` otherwise
+    pub fn movzx_reg_mem(&mut self, size: Size, reg: Reg, mem: Mem) {
+        match size {
+            Size::Byte => self.movzx_reg_mem8(reg, mem),
+            Size::Qword => self.mov_reg_mem(reg, mem),
+            Size::Word => {
+                let at = self.position();
+                self.prefixes(Size::Dword, Some(reg), mem.base);
+                self.code.extend_from_slice(&[0x0f, 0xb7]);
+                self.modrm_mem(reg.low(), mem);
+                self.list(at, || format!("movzx {}, {}", reg.name(Size::Dword), mem.text(Size::Word)), None);
+            },
+            Size::Dword => {
+                let at = self.position();
+                self.prefixes(Size::Dword, Some(reg), mem.base);
+                self.code.push(0x8b);
+                self.modrm_mem(reg.low(), mem);
+                self.list(at, || format!("mov {}, {}", reg.name(Size::Dword), mem.text(Size::Dword)), None);
+            },
+        }
+    }
+
+    /// `imul dst, src, imm` on 64-bit registers
+    pub fn imul_reg_imm(&mut self, dst: Reg, src: Reg, imm: i32) {
+        let at = self.position();
+        self.prefixes(Size::Qword, Some(dst), src);
+        if i8::try_from(imm).is_ok() {
+            self.code.push(0x6b);
+            self.modrm_reg(dst.low(), src);
+            self.code.push(imm as u8);
+        } else {
+            self.code.push(0x69);
+            self.modrm_reg(dst.low(), src);
+            self.imm(Size::Dword, imm as i64);
+        }
+        self.list(at, || format!("imul {}, {}, {}", dst.name(Size::Qword),
+                                 src.name(Size::Qword), imm), None);
+    }
+
+    /// `imul dst, src` on 64-bit registers
+    pub fn imul_reg_reg(&mut self, dst: Reg, src: Reg) {
+        let at = self.position();
+        self.prefixes(Size::Qword, Some(dst), src);
+        self.code.extend_from_slice(&[0x0f, 0xaf]);
+        self.modrm_reg(dst.low(), src);
+        self.list(at, || format!("imul {}, {}", dst.name(Size::Qword), src.name(Size::Qword)), None);
+    }
+
+    /// `xor reg32, reg32`, zeroing the whole register
+    pub fn zero(&mut self, reg: Reg) {
+        let at = self.position();
+        self.prefixes(Size::Dword, Some(reg), reg);
+        self.code.push(0x31);
+        self.modrm_reg(reg.low(), reg);
+        self.list(at, || format!("xor {0}, {0}", reg.name(Size::Dword)), None);
+    }
+
+    /// `call qword ptr [mem]`
+    pub fn call_mem(&mut self, mem: Mem) {
+        let at = self.position();
+        self.prefixes(Size::Dword, None, mem.base);
+        self.code.push(0xff);
+        self.modrm_mem(2, mem);
+        self.list(at, || format!("call {}", mem.text(Size::Qword)), None);
+    }
+
+    /// `call label`, always with a rel32 displacement
+    pub fn call(&mut self, label: Label) {
+        let at = self.position();
+        self.code.push(0xe8);
+        self.fixups.push(Fixup { at: self.code.len(), label });
+        self.code.extend_from_slice(&[0; 4]);
+        self.list(at, String::new, Some(label));
+    }
+
+    /// `ret`
+    pub fn ret(&mut self) {
+        let at = self.position();
+        self.code.push(0xc3);
+        self.list(at, || "ret".to_string(), None);
+    }
+
+    /// `syscall`
+    pub fn syscall(&mut self) {
+        let at = self.position();
+        self.code.extend_from_slice(&[0x0f, 0x05]);
+        self.list(at, || "syscall".to_string(), None);
+    }
+
+    /// `jmp label`
+    pub fn jmp(&mut self, label: Label) {
+        self.branch(&[0xeb], &[0xe9], label);
+    }
+
+    /// `jcc label`
+    pub fn jcc(&mut self, cond: Cond, label: Label) {
+        self.branch(&[0x70 | cond as u8], &[0x0f, 0x80 | cond as u8], label);
+    }
+
+    /// Emit a branch with the rel8 form if the label is bound and close
+    /// enough, else with the rel32 form
+    fn branch(&mut self, short: &[u8], near: &[u8], label: Label) {
+        let at = self.position();
+
+        if let Some(target) = self.labels[label.0] {
+            let rel = target as i64 - (at + short.len() + 1) as i64;
+            if let Ok(rel) = i8::try_from(rel) {
+                self.code.extend_from_slice(short);
+                self.code.push(rel as u8);
+                self.list(at, String::new, Some(label));
+                return;
+            }
+        }
+
+        self.code.extend_from_slice(near);
+        self.fixups.push(Fixup { at: self.code.len(), label });
+        self.code.extend_from_slice(&[0; 4]);
+        self.list(at, String::new, Some(label));
+    }
+
+    /// Resolve all branches and compare every instruction against the
+    /// encoding Keystone produces for its text. Branches are not compared,
+    /// Keystone picks its own displacement sizes; instead their decoded
+    /// targets are checked against the labels.
+    #[cfg(feature = "keystone")]
+    pub fn cross_check(mut self) -> Result<Vec<u8>, String> {
+        use keystone::{Arch, Keystone, OptionType};
+        use std::convert::TryInto;
+
+        self.resolve();
+
+        let engine = Keystone::new(Arch::X86, keystone::MODE_64)
+            .expect("Could not initialize keystone engine");
+        engine.option(OptionType::SYNTAX, keystone::OPT_SYNTAX_INTEL)
+            .expect("Could not set option to intel syntax");
+
+        for entry in &self.listing {
+            let bytes = &self.code[entry.at..entry.at + entry.len];
+
+            if let Some(text) = &entry.text {
+                let expected = engine.asm(text.clone(), entry.at as u64)
+                    .map_err(|err| format!("keystone rejected `{}`: {}", text, err))?
+                    .bytes;
+                if bytes != &expected[..] {
+                    return Err(format!("`{}` at 0x{:x}: encoded as {:02x?}, keystone {:02x?}",
+                                       text, entry.at, bytes, expected));
+                }
+            }
+
+            if let Some(label) = entry.label {
+                // The displacement is the tail of the instruction, rel8 for
+                // two byte branches and rel32 otherwise
+                let rel = match bytes.len() {
+                    2 => bytes[1] as i8 as i64,
+                    _ => i32::from_le_bytes(bytes[bytes.len() - 4..].try_into().unwrap()) as i64,
+                };
+                let target = (entry.at + entry.len) as i64 + rel;
+                if Some(target as usize) != self.labels[label.0] {
+                    return Err(format!("branch at 0x{:x} does not reach its label", entry.at));
+                }
+            }
+        }
+
+        Ok(self.code)
+    }
+}
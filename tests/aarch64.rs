@@ -0,0 +1,211 @@
+//! AArch64 code generation. On other hosts the code cannot run, so compare it
+//! against a listing checked with `llvm-mc -triple=aarch64 --disassemble`.
+//!
+//! When `qemu-aarch64` is installed an AArch64 build of the command line
+//! tool also runs a set of programs with both JIT engines, which must agree
+//! with `vm3` on the host. Build it with
+//! `cargo build --target aarch64-unknown-linux-gnu` and point
+//! `QEMU_LD_PREFIX` at the AArch64 sysroot, or set `BRAINFUCK_RVM_AARCH64`
+//! to a binary built elsewhere.
+
+use brainfuck_rvm::jit::{generate_jit_for, JitOptions, Target};
+use brainfuck_rvm::Program;
+
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+/// Programs run under qemu, including some which leave the tape far from
+/// any access
+const PROGRAMS: &[(&str, &str)] = &[
+    ("hello", include_str!("../programs/hello.bf")),
+    ("echo-line", ",----------[++++++++++.,----------]"),
+    ("off-the-end", "+[>+]"),
+    ("off-the-start", "+>++<<+"),
+    ("idioms", "+++[-]>+>+>+<<[>]++++++++[->+++>--<<]>>[<]>>[-<<<+>>>]<<<."),
+    ("scan-off-the-end", "+[[>]+]"),
+    ("offsets-off-the-end", "+[>+>++<]"),
+    ("constants", "[comment.]++++++++[->++++++++<]>+.>-.<[-][.]<<+"),
+];
+
+const INPUT: &[u8] = b"aarch64\n";
+
+/// Run the command line tool at `binary`, under `qemu-aarch64` if given,
+/// with `args` on `source`. Returns the exit code, stdout and the final
+/// tape.
+fn run(qemu: bool, binary: &Path, name: &str, args: &[&str], source: &str)
+        -> (Option<i32>, Vec<u8>, Vec<u8>) {
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR"));
+    let program = dir.join(format!("aarch64-{}.bf", name));
+    let tape = dir.join(format!("aarch64-{}.{}.{}.tape", name, qemu, args.join("")));
+    fs::write(&program, source).unwrap();
+
+    let mut command = if qemu {
+        let mut command = Command::new("qemu-aarch64");
+        command.arg(binary);
+        command
+    } else {
+        Command::new(binary)
+    };
+    let mut child = command
+        .args(["--tape", "4096"])
+        .args(args)
+        .arg("--dump-tape")
+        .arg(&tape)
+        .arg(&program)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let _ = child.stdin.take().unwrap().write_all(INPUT);
+
+    let output = child.wait_with_output().unwrap();
+    (output.status.code(), output.stdout, fs::read(&tape).unwrap())
+}
+
+#[test]
+fn encodes_checked_program() {
+    let program = Program::parse("+[-<].,").unwrap();
+    let options = JitOptions { bounds_checks: true, ..JitOptions::default() };
+    let code = generate_jit_for(Target::AArch64, &program, &options).unwrap();
+
+    let expected: &[u32] = &[
+        0xaa1e03f8, // mov x24, x30
+        0x39400289, // ldrb w9, [x20]
+        0x11000529, // add w9, w9, #1
+        0x39000289, // strb w9, [x20]
+        0x39400289, // ldrb w9, [x20]
+        0x34000109, // cbz w9, end
+        0x39400289, // ldrb w9, [x20]
+        0x51000529, // sub w9, w9, #1
+        0x39000289, // strb w9, [x20]
+        0xd1000694, // sub x20, x20, #1
+        0xeb15029f, // cmp x20, x21
+        0x54000243, // b.lo oob
+        0x17fffff8, // b start
+        0x39400281, // end: ldrb w1, [x20]
+        0xaa1703e0, // mov x0, x23
+        0xf94006f0, // ldr x16, [x23, #8]
+        0xd63f0200, // blr x16
+        0xb5000140, // cbnz x0, host_error
+        0xaa1703e0, // mov x0, x23
+        0xf94002f0, // ldr x16, [x23]
+        0xd63f0200, // blr x16
+        0xf104001f, // cmp x0, #256
+        0x54000060, // b.eq resume
+        0x54000088, // b.hi host_error
+        0x39000280, // strb w0, [x20]
+        0xd2800000, // resume: mov x0, #0
+        0xd65f0300, // ret x24
+        0x92800020, // host_error: mov x0, #-2
+        0xd65f0300, // ret x24
+        0xaa1403e1, // oob: mov x1, x20
+        0x91000694, // add x20, x20, #1
+        0xd2800080, // mov x0, #4
+        0xd65f0300, // ret x24
+    ];
+    let words: Vec<u32> = code.chunks(4)
+        .map(|word| u32::from_le_bytes([word[0], word[1], word[2], word[3]]))
+        .collect();
+    assert_eq!(words, expected);
+}
+
+#[test]
+fn encodes_optimized_program() {
+    let program = Program::parse("[-][>>]<[->++<<->]").unwrap().optimize();
+    let options = JitOptions { bounds_checks: true, ..JitOptions::default() };
+    let code = generate_jit_for(Target::AArch64, &program, &options).unwrap();
+
+    // The leading `[-]` clears a cell which is still zero and is dropped
+    let expected: &[u32] = &[
+        0xaa1e03f8, // mov x24, x30
+        0x14000004, // b test
+        0x91000a94, // step: add x20, x20, #2
+        0xeb16029f, // cmp x20, x22
+        0x540003a2, // b.hs scan_oob
+        0x39400289, // test: ldrb w9, [x20]
+        0x35ffff89, // cbnz w9, step
+        0xd1000694, // sub x20, x20, #1
+        0xeb15029f, // cmp x20, x21
+        0x54000383, // b.lo oob
+        0x39400289, // start: ldrb w9, [x20]
+        0x34000249, // cbz w9, end
+        0x9100068b, // add x11, x20, #1
+        0xeb16017f, // cmp x11, x22
+        0x54000362, // b.hs right_oob
+        0x39400289, // ldrb w9, [x20]
+        0x3940016a, // ldrb w10, [x11]
+        0xd280004c, // mov x12, #2
+        0x1b0c292a, // madd w10, w9, w12, w10
+        0x3900016a, // strb w10, [x11]
+        0xd100068b, // sub x11, x20, #1
+        0xeb15017f, // cmp x11, x21
+        0x540002c3, // b.lo left_oob
+        0x39400289, // ldrb w9, [x20]
+        0x3940016a, // ldrb w10, [x11]
+        0x4b09014a, // sub w10, w10, w9
+        0x3900016a, // strb w10, [x11]
+        0x3900029f, // strb wzr, [x20]
+        0x17ffffee, // b start
+        0xd2800000, // end: mov x0, #0
+        0xd65f0300, // ret x24
+        0x92800020, // mov x0, #-2
+        0xd65f0300, // ret x24
+        0xaa1403e1, // scan_oob: mov x1, x20
+        0xd1000a94, // sub x20, x20, #2
+        0xd2800080, // mov x0, #4
+        0xd65f0300, // ret x24
+        0xaa1403e1, // oob: mov x1, x20
+        0x91000694, // add x20, x20, #1
+        0xd2800100, // mov x0, #8
+        0xd65f0300, // ret x24
+        0xaa0b03e1, // right_oob: mov x1, x11
+        0xd2800180, // mov x0, #12
+        0xd65f0300, // ret x24
+        0xaa0b03e1, // left_oob: mov x1, x11
+        0xd2800200, // mov x0, #16
+        0xd65f0300, // ret x24
+    ];
+    let words: Vec<u32> = code.chunks(4)
+        .map(|word| u32::from_le_bytes([word[0], word[1], word[2], word[3]]))
+        .collect();
+    assert_eq!(words, expected);
+}
+
+#[test]
+fn runs_under_qemu() {
+    let binary = env::var_os("BRAINFUCK_RVM_AARCH64").map(PathBuf::from).unwrap_or_else(|| {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("target/aarch64-unknown-linux-gnu/debug/BrainfuckRVm")
+    });
+    if Command::new("qemu-aarch64").arg("--version").output().is_err() || !binary.exists() {
+        eprintln!("no qemu-aarch64 or AArch64 build, skipping");
+        return;
+    }
+    let host = Path::new(env!("CARGO_BIN_EXE_BrainfuckRVm"));
+
+    // Guard pages fall back to a heap tape on AArch64, where the JIT must
+    // then check every movement
+    let far = format!("{}+", ">".repeat(75000));
+    let programs = PROGRAMS.iter().map(|&(name, source)| (name, source.to_string()))
+        .chain([("far", far)]);
+
+    for (name, source) in programs {
+        for checks in &["--checked", "--guard-pages"] {
+            for cell_width in &["8", "16"] {
+                let args = [*checks, "--cell-width", cell_width];
+                let expected = run(false, host, name, &[&args[..], &["-e", "vm3"]].concat(),
+                                   &source);
+                for engine in &["jit", "jitopt"] {
+                    let actual = run(true, &binary, name,
+                                     &[&args[..], &["-e", engine]].concat(), &source);
+                    assert_eq!(actual, expected, "{} {:?}: {} under qemu differs from vm3",
+                               name, args, engine);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,127 @@
+//! Standalone executables: every program is compiled ahead of time, run as
+//! its own process and must agree with `Engine::Vm3` on output and exit code.
+#![cfg(all(target_os = "linux", target_arch = "x86_64"))]
+
+use brainfuck_rvm::aot::{generate_elf, AotOptions};
+use brainfuck_rvm::jit::JitOptions;
+use brainfuck_rvm::tape::GUARD_SIZE;
+use brainfuck_rvm::{CellWidth, Emu, Engine, EofPolicy, Program, SharedBuffer};
+
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+const PROGRAMS: &[(&str, &str)] = &[
+    ("hello", include_str!("../programs/hello.bf")),
+    ("echo", ",[.,]"),
+    ("wrap", "->->-<<[>.>.<<+]"),
+    ("nested", "++[>++[>++<-]<-]>>[-<+>]<."),
+    ("off-the-end", "+[>+]"),
+    ("off-the-start", "+>++<<"),
+    ("read-past-eof", ">+,.>+,.>+,.>+,."),
+    ("idioms", "+++[-]>+>+>+<<[>]++++++++[->+++>--<<]>>[<]>>[-<<<+>>>]<<<."),
+    ("scan-off-the-end", "+[[>]+]"),
+    ("multiply-off-the-start", "+[-<+>]"),
+    ("offsets-off-the-end", "+[>+>++<]"),
+    ("constants", "[comment.]++++++++[->++++++++<]>+.>-.<[-][.]<<+"),
+];
+
+const INPUT: &[u8] = b"ab\n";
+
+/// Compile and run `source`, returning the exit code and stdout
+fn run_elf(name: &str, source: &str, options: &AotOptions) -> (Option<i32>, Vec<u8>) {
+    let elf = generate_elf(&Program::parse(source).unwrap(), options).unwrap();
+    let path = PathBuf::from(env!("CARGO_TARGET_TMPDIR"))
+        .join(format!("{}-{}-{:?}", name, options.jit.cell_width, options.jit.eof_policy));
+    fs::OpenOptions::new().write(true).create(true).truncate(true).mode(0o755)
+        .open(&path).unwrap()
+        .write_all(&elf).unwrap();
+
+    let mut child = Command::new(&path)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let _ = child.stdin.take().unwrap().write_all(INPUT);
+
+    let output = child.wait_with_output().unwrap();
+    (output.status.code(), output.stdout)
+}
+
+/// Run `source` with `Engine::Vm3`, returning the exit code and output
+fn run_vm3(source: &str, options: &AotOptions) -> (Option<i32>, Vec<u8>) {
+    let output = SharedBuffer::new();
+    let mut emu = Emu::new(options.tape_cells)
+        .with_cell_width(options.jit.cell_width)
+        .with_eof_policy(options.jit.eof_policy)
+        .with_input(INPUT)
+        .with_output(output.clone());
+    let exit = emu.run_program(Engine::Vm3, &Program::parse(source).unwrap());
+    drop(emu);
+    (exit.map(|exit| exit.exit_code()), output.contents())
+}
+
+#[test]
+fn executables_agree_with_vm3() {
+    for (name, source) in PROGRAMS {
+        for &cell_width in &[CellWidth::U8, CellWidth::U64] {
+            for &eof_policy in &[EofPolicy::Zero, EofPolicy::Stop] {
+                let options = AotOptions {
+                    jit: JitOptions { bounds_checks: true, cell_width, eof_policy },
+                    tape_cells: 64,
+                    unbuffered: false,
+                    ..AotOptions::default()
+                };
+                assert_eq!(run_elf(name, source, &options), run_vm3(source, &options),
+                           "{} ({}, {:?})", name, cell_width, eof_policy);
+            }
+        }
+    }
+}
+
+#[test]
+fn input_exhausted_has_its_own_exit_code() {
+    let options = AotOptions {
+        jit: JitOptions { eof_policy: EofPolicy::Stop, ..JitOptions::default() },
+        tape_cells: 64,
+        ..AotOptions::default()
+    };
+    assert_eq!(run_elf("stop-at-eof", ",.,.,.,.", &options), (Some(4), b"ab\n".to_vec()));
+}
+
+#[test]
+fn tape_has_a_guard_above_it() {
+    let elf = generate_elf(&Program::parse(",").unwrap(), &AotOptions::default()).unwrap();
+    let path = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("guard-above");
+    fs::OpenOptions::new().write(true).create(true).truncate(true).mode(0o755)
+        .open(&path).unwrap()
+        .write_all(&elf).unwrap();
+
+    // The executable waits for its input, by then the guard is mapped
+    // without any access right just behind the tape
+    let mut child = Command::new(&path)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap();
+    let has_guard = (0..500).any(|_| {
+        let maps = fs::read_to_string(format!("/proc/{}/maps", child.id())).unwrap_or_default();
+        let found = maps.lines().any(|line| {
+            let mut fields = line.split_whitespace();
+            let (range, perms) = (fields.next().unwrap(), fields.next().unwrap());
+            let (start, end) = range.split_once('-').unwrap();
+            let size = u64::from_str_radix(end, 16).unwrap() - u64::from_str_radix(start, 16).unwrap();
+            perms == "---p" && size == GUARD_SIZE as u64
+        });
+        if !found {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        found
+    });
+    drop(child.stdin.take());
+    assert_eq!(child.wait().unwrap().code(), Some(0));
+    assert!(has_guard, "no guard mapping above the tape");
+}
@@ -0,0 +1,130 @@
+//! Bytecode compilation and its serialized format: loop targets are
+//! resolved, pointer movements are fused with the bracket after them and
+//! loaded bytecode runs like the program it was compiled from.
+
+use brainfuck_rvm::bytecode::{Bytecode, Instruction, LoadError, VERSION};
+use brainfuck_rvm::{CellWidth, Emu, Engine, EofPolicy, Program, SharedBuffer, VmExit};
+
+const PROGRAMS: &[&str] = &[
+    include_str!("../programs/hello.bf"),
+    include_str!("../programs/mandelbrot.bf"),
+    ",----------[++++++++++.,----------]",
+    "++++[->+++>--<<]>[->>+<<]>>>---[+<<<+>>>]<[-<+>]",
+    ">+>++<<->>>+++<.<[->>+<<]>>.",
+    "+[>+>++<]",
+    "[comment.]++++++++[->++++++++<]>+.>-.<[-][.]<<+",
+];
+
+#[test]
+fn fuses_movements_with_brackets() {
+    let program = Program::parse("+[>[-]<-]>.").unwrap().fold();
+    let bytecode = Bytecode::compile(&program);
+
+    assert_eq!(bytecode.instructions, [
+        Instruction::Add(1),
+        Instruction::JumpIfZero(9),
+        Instruction::MoveJumpIfZero { delta: 1, target: 6 },
+        Instruction::JumpIfZero(6),
+        Instruction::Add(u64::MAX),
+        Instruction::JumpIfNonZero(4),
+        Instruction::Move(-1),
+        Instruction::Add(u64::MAX),
+        Instruction::JumpIfNonZero(2),
+        Instruction::Move(1),
+        Instruction::Write,
+    ]);
+    assert_eq!(bytecode.offsets, program.offsets);
+}
+
+#[test]
+fn serializes_to_a_stable_format() {
+    let bytecode = Bytecode::compile(&Program::parse("+[-].").unwrap());
+
+    assert_eq!(bytecode.to_bytes(), [
+        b'B', b'F', b'B', b'C', 1, 0,   // magic, version
+        5,                              // instructions
+        1, 2,                           // Add(1)
+        10, 4,                          // JumpIfZero(4)
+        1, 1,                           // Add(-1)
+        11, 2,                          // JumpIfNonZero(2)
+        2,                              // Write
+        0, 2, 2, 2, 2,                  // offsets
+        0x4b, 0x85, 0xe0, 0x90,         // CRC-32
+    ]);
+}
+
+/// A machine with a short tape reading a line of input into `output`
+fn new_emu(cell_width: CellWidth, output: &SharedBuffer) -> Emu {
+    Emu::new(64)
+        .with_cell_width(cell_width)
+        .with_eof_policy(EofPolicy::Zero)
+        .with_input(&b"bytecode\n"[..])
+        .with_output(output.clone())
+}
+
+#[test]
+fn loaded_bytecode_runs_like_the_source() {
+    for source in PROGRAMS {
+        let program = Program::parse(source).unwrap();
+        let bytecode = Bytecode::compile(&program.optimize());
+        let loaded = Bytecode::from_bytes(&bytecode.to_bytes()).unwrap();
+        assert_eq!(loaded, bytecode);
+
+        for &cell_width in &[CellWidth::U8, CellWidth::U64] {
+            let output = SharedBuffer::new();
+            let mut emu = new_emu(cell_width, &output);
+            let exit = emu.run_compiled(&loaded);
+
+            let expected_output = SharedBuffer::new();
+            let mut expected = new_emu(cell_width, &expected_output);
+            let expected_exit = expected.run_program(Engine::Vm3, &program);
+
+            assert_eq!(exit.map(|exit| exit.exit_code()),
+                       expected_exit.map(|exit| exit.exit_code()));
+            assert_eq!(output.contents(), expected_output.contents());
+            assert_eq!(&emu.memory[..], &expected.memory[..]);
+        }
+    }
+}
+
+#[test]
+fn rejects_damaged_bytecode() {
+    let bytes = Bytecode::compile(&Program::parse("+[-].").unwrap()).to_bytes();
+
+    assert_eq!(Bytecode::from_bytes(b"+[-]."), Err(LoadError::NotBytecode));
+    assert_eq!(Bytecode::from_bytes(&bytes[..bytes.len() - 1]),
+               Err(LoadError::ChecksumMismatch));
+
+    let mut flipped = bytes.clone();
+    flipped[8] ^= 1;
+    assert_eq!(Bytecode::from_bytes(&flipped), Err(LoadError::ChecksumMismatch));
+
+    let mut newer = bytes;
+    newer[4..6].copy_from_slice(&(VERSION + 1).to_le_bytes());
+    assert_eq!(Bytecode::from_bytes(&newer), Err(LoadError::UnsupportedVersion(VERSION + 1)));
+}
+
+#[test]
+fn runs_hostile_bytecode() {
+    // Well-formed files with a valid checksum which move further than any
+    // tape, overflowing the pointer
+    let moves: &[&[Instruction]] = &[
+        &[Instruction::Move(1), Instruction::Move(isize::MAX)],
+        &[Instruction::Move(isize::MIN)],
+        &[Instruction::Add(1), Instruction::Scan(isize::MAX)],
+        &[Instruction::Move(1), Instruction::MulAdd { offset: isize::MAX, factor: 1 }],
+        &[Instruction::Move(1), Instruction::MoveJumpIfZero { delta: isize::MAX, target: 2 }],
+    ];
+
+    for instructions in moves {
+        let bytecode = Bytecode {
+            instructions: instructions.to_vec(),
+            offsets: (0..instructions.len()).collect(),
+        };
+        let loaded = Bytecode::from_bytes(&bytecode.to_bytes()).unwrap();
+
+        let output = SharedBuffer::new();
+        let exit = new_emu(CellWidth::U8, &output).run_compiled(&loaded);
+        assert!(matches!(exit, Some(VmExit::PtrOob { .. })), "{:?}: {:?}", instructions, exit);
+    }
+}
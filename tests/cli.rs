@@ -0,0 +1,60 @@
+//! The command line tool: the exit codes it reports for the way a program
+//! stopped, with the default engine and options.
+
+use std::fs;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+/// Run `source` with the extra `args`, returning the exit code
+fn exit_code(name: &str, args: &[&str], source: &str) -> Option<i32> {
+    let program = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join(format!("cli-{}.bf", name));
+    fs::write(&program, source).unwrap();
+
+    Command::new(env!("CARGO_BIN_EXE_BrainfuckRVm"))
+        .args(args)
+        .arg(&program)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .unwrap()
+        .code()
+}
+
+#[test]
+fn interpreters_stop_off_the_tape() {
+    let programs = [
+        ("off-the-start", "<+"),
+        ("off-the-end", "+[>+]"),
+        ("loop-off-the-start", "+[<+]"),
+    ];
+
+    for engine in ["vm", "vm2"] {
+        let name = format!("{}-clean", engine);
+        assert_eq!(exit_code(&name, &["--engine", engine], "+[-]"), Some(0), "{}", name);
+        for (name, source) in programs {
+            let name = format!("{}-{}", engine, name);
+            assert_eq!(exit_code(&name, &["--engine", engine, "--tape", "100"], source), Some(2),
+                       "{}", name);
+        }
+    }
+}
+
+#[test]
+fn default_engine_stops_off_the_tape() {
+    let programs = [
+        ("off-the-start", "<+"),
+        ("off-the-end", "+[>+]"),
+        ("loop-off-the-start", "+[<+]"),
+    ];
+
+    for (name, source) in programs {
+        assert_eq!(exit_code(name, &["--tape", "100"], source), Some(2), "{}", name);
+    }
+}
+
+#[test]
+fn input_exhausted_has_its_own_exit_code() {
+    assert_eq!(exit_code("stop-at-eof", &["--eof", "stop"], ",[.,]"), Some(4));
+    assert_eq!(exit_code("zero-at-eof", &["--eof", "zero"], ",[.,]"), Some(0));
+}
@@ -0,0 +1,193 @@
+//! Differential test: every program is run through every engine at every
+//! cell width and optimization level and the exit code, output and final
+//! tape must match byte for byte.
+
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+const ENGINES: &[&str] = &["vm", "vm2", "vm3", "bytecode", "jit", "jitopt"];
+
+const CELL_WIDTHS: &[&str] = &["8", "16", "32", "64"];
+
+/// Small programs exercising wrapping, neighbouring cells and I/O
+const PROGRAMS: &[(&str, &str)] = &[
+    ("hello", include_str!("../programs/hello.bf")),
+    ("wrap-down", "->+>-<<-"),
+    ("wrap-up", "-[+>+<]>+++"),
+    ("borrow", "+>->+>->+<<<<-"),
+    ("long-run", "++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++
+                  ++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++
+                  ++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++
+                  ++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++>>"),
+    ("echo-line", ",----------[++++++++++.,----------]"),
+    ("nested", "++[>++[>++<-]<-]>>[-<+>]<."),
+    ("no-carry", "->->++++++++++++++++[<++++++++++++++++>-]<[>+<-]"),
+    ("off-the-end", "+[>+]"),
+    ("off-the-start", "+>++<<"),
+    ("clear", "+++[-]>-[+]>++++++++++[-]"),
+    ("scan", "+>+>+>>+>>>+<<<<<<<[>]>[>>]<<[<]>>>>[<<]"),
+    ("scan-off-the-end", "+[[>]+]"),
+    ("multiply", "++++[->+++>--<<]>[->>+<<]>>>---[+<<<+>>>]<[-<+>]"),
+    ("offsets", ">+>++<<->>>+++<.<[->>+<<]>>."),
+    ("offsets-off-the-end", "+[>+>++<]"),
+    ("offsets-off-the-start", ">>+[<+<++>]"),
+    ("constants", "[comment.]++++++++[->++++++++<]>+.>-.<[-][.]<<+"),
+    ("excursion", "<>"),
+    ("run-excursion", "+>><<<+>>"),
+    ("loop-excursion", "+[-<<>>]"),
+    ("multiply-excursion", "++[->+<<>]>."),
+    ("ends-off-the-start", "+.<"),
+];
+
+const INPUT: &[u8] = b"differential\n";
+
+/// Small tape on which the JIT checks every pointer movement
+const CHECKED: &[&str] = &["--tape", "64", "--checked"];
+
+/// Bytes of the tape for `far_programs`
+const FAR_TAPE: usize = 700000;
+
+/// Programs moving the pointer 600000 bytes between accesses, which is less
+/// than the guard region of a guarded tape. The first access is on a tape
+/// of `FAR_TAPE` bytes, the others are off the end, the last one by more
+/// than the guard region. Wide cells keep the programs short enough for the
+/// one to one `jit` engine.
+fn far_programs(cell_bytes: usize) -> Vec<(String, String)> {
+    let far = ">".repeat(600000 / cell_bytes);
+    ["far-write", "far-read", "far-known-write"].iter().zip([".", ",", "+."])
+        .map(|(name, access)| {
+            let source = format!("{far}{access}{far}{access}{far}+", far = far, access = access);
+            (name.to_string(), source)
+        })
+        .collect()
+}
+
+/// Run `source` with `engine` on the tape set up by `tape` and with the
+/// extra `args`, returning the exit code, stdout and the final tape
+fn run(name: &str, engine: &str, tape: &[&str], args: &[&str], source: &str)
+        -> (Option<i32>, Vec<u8>, Vec<u8>) {
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR"));
+    let program = dir.join(format!("{}.bf", name));
+    let dump = format!("{}.{}.{}{}.tape", name, engine, tape.join(""), args.join(""));
+    let dump = dir.join(dump.replace('/', "_"));
+    fs::write(&program, source).unwrap();
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_BrainfuckRVm"))
+        .args(["--engine", engine])
+        .args(tape)
+        .args(args)
+        .arg("--dump-tape")
+        .arg(&dump)
+        .arg(&program)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    // Programs which never read exit without draining stdin
+    let _ = child.stdin.take().unwrap().write_all(INPUT);
+
+    let output = child.wait_with_output().unwrap();
+    (output.status.code(), output.stdout, fs::read(&dump).unwrap())
+}
+
+/// Run `source` through every engine on the small `CHECKED` tape with
+/// `args` and compare against the first one
+fn assert_engines_agree(name: &str, args: &[&str], source: &str) {
+    assert_engines_agree_on(CHECKED, name, args, source);
+}
+
+/// Same as `assert_engines_agree` on the tape set up by `tape`
+fn assert_engines_agree_on(tape: &[&str], name: &str, args: &[&str], source: &str) {
+    let (expected_code, expected_output, expected_tape) =
+        run(name, ENGINES[0], tape, args, source);
+
+    for engine in &ENGINES[1..] {
+        let (code, output, tape) = run(name, engine, tape, args, source);
+        assert_eq!(code, expected_code, "{} {:?}: exit code of {} differs from {}",
+                   name, args, engine, ENGINES[0]);
+        assert_eq!(output, expected_output, "{} {:?}: output of {} differs from {}",
+                   name, args, engine, ENGINES[0]);
+        assert_eq!(tape, expected_tape, "{} {:?}: tape of {} differs from {}",
+                   name, args, engine, ENGINES[0]);
+    }
+}
+
+#[test]
+fn engines_agree() {
+    for (name, source) in PROGRAMS {
+        for cell_width in CELL_WIDTHS {
+            assert_engines_agree(name, &["--cell-width", cell_width], source);
+        }
+    }
+}
+
+#[test]
+fn optimization_levels_agree() {
+    let pipelines: &[&[&str]] = &[
+        &["-O0"],
+        &["-O1"],
+        &["-O3"],
+        &["--no-pass", "fold"],
+        &["--no-pass", "idioms"],
+        &["--no-pass", "offsets"],
+        &["--no-pass", "constants"],
+    ];
+    for (name, source) in PROGRAMS {
+        for args in pipelines {
+            assert_engines_agree(name, args, source);
+        }
+    }
+}
+
+#[test]
+fn eof_policies_agree() {
+    // Reads past the end of the input into cells which start at one
+    let source = "+,>".repeat(20);
+
+    for eof in &["unchanged", "zero", "minus-one", "stop"] {
+        for cell_width in &["8", "16"] {
+            assert_engines_agree("read-past-eof",
+                                 &["--cell-width", cell_width, "--eof", eof], &source);
+        }
+    }
+}
+
+#[test]
+fn excursions_agree() {
+    // Leave the tape and come back or stop without accessing a cell out
+    // there, on a tape which fills whole pages so guard pages sit right
+    // next to it
+    let cells = 4096;
+    let far = ">".repeat(cells);
+    let programs = [
+        ("excursion-off-the-start", "<>".to_string()),
+        ("excursion-off-the-end", format!("{}<", far)),
+        ("run-excursion-off-the-end", format!("+{}<<+", far)),
+        ("ends-off-the-end", format!("+.{}", far)),
+    ];
+
+    for (name, source) in &programs {
+        for checks in &["--guard-pages", "--checked"] {
+            assert_engines_agree_on(&["--tape", &cells.to_string(), checks], name, &[], source);
+        }
+    }
+}
+
+#[test]
+fn far_moves_agree() {
+    // Unchecked JIT code relies on the guard pages to stop the last access,
+    // and must check the moves which take it further than that
+    for (cell_width, cell_bytes) in &[("32", 4), ("64", 8)] {
+        let cells = (FAR_TAPE / cell_bytes).to_string();
+        for (name, source) in far_programs(*cell_bytes) {
+            for checks in &["--guard-pages", "--checked"] {
+                // At the end of the input `,` leaves the cell alone
+                assert_engines_agree_on(&["--tape", &cells, checks], &name,
+                                        &["--cell-width", cell_width, "--input", "/dev/null"],
+                                        &source);
+            }
+        }
+    }
+}
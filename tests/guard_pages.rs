@@ -0,0 +1,34 @@
+//! Guarded tapes: unchecked JIT code which walks into a guard page stops
+//! with `PtrOob` at the source position of the operation which faulted.
+#![cfg(all(target_os = "linux", target_arch = "x86_64"))]
+
+use brainfuck_rvm::{Emu, Engine, JitCache, Program, VmExit};
+use std::sync::Arc;
+
+#[test]
+fn faults_report_their_source_offset() {
+    // The access after the pointer left the tape faults, the movement
+    // itself is not checked
+    let programs: &[(&str, usize)] = &[
+        ("<+", 1),
+        ("+[<+]", 3),
+        ("+[>+]", 3),
+        ("+\n<<<-", 5),
+    ];
+
+    for &engine in &[Engine::Jit, Engine::JitOpt] {
+        for &(source, offset) in programs {
+            let mut emu = Emu::new(100)
+                .enable_guard_pages()
+                .enable_jit(Arc::new(JitCache::new(1024 * 1024)));
+            assert!(emu.memory.is_guarded());
+
+            match emu.run_program(engine, &Program::parse(source).unwrap()) {
+                Some(VmExit::PtrOob { offset: Some(found), .. }) => {
+                    assert_eq!(found, offset, "{:?} {:?}", engine, source);
+                },
+                exit => panic!("{:?} {:?}: unexpected exit {:?}", engine, source, exit),
+            }
+        }
+    }
+}
@@ -0,0 +1,90 @@
+//! In-memory I/O: every engine reads `,` from a byte slice and writes `.` to
+//! a `SharedBuffer`, buffering its output the same way.
+
+use brainfuck_rvm::{BfInput, CellWidth, Emu, Engine, EofPolicy, JitCache, Program, SharedBuffer};
+use std::io::{self, Write};
+use std::sync::Arc;
+
+const ENGINES: &[Engine] = &[
+    Engine::Vm, Engine::Vm2, Engine::Vm3, Engine::Bytecode, Engine::Jit, Engine::JitOpt,
+];
+
+#[test]
+fn buffers_in_every_engine() {
+    // Echo the input until EOF, which stores zero
+    let program = Program::parse(",[.,]").unwrap();
+    let input: &[u8] = b"in memory \xff\n";
+
+    for &engine in ENGINES {
+        for &cell_width in &[CellWidth::U8, CellWidth::U32] {
+            let output = SharedBuffer::new();
+            let mut emu = Emu::new(16)
+                .with_cell_width(cell_width)
+                .with_eof_policy(EofPolicy::Zero)
+                .with_input(input)
+                .with_output(output.clone());
+            if engine.is_jit() {
+                emu = emu.enable_jit(Arc::new(JitCache::new(1024 * 1024)));
+            }
+
+            emu.run_program(engine, &program);
+            assert_eq!(output.contents(), input, "{:?} ({})", engine, cell_width);
+        }
+    }
+}
+
+/// Input which records the output written before each read
+struct Prompted {
+    output: SharedBuffer,
+    seen: SharedBuffer,
+}
+
+impl BfInput for Prompted {
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        self.seen.write_all(&self.output.contents())?;
+        Ok(Some(b'!'))
+    }
+}
+
+#[test]
+fn output_is_flushed_before_input_and_at_exit() {
+    // A prompt without newline, then echo the answer without newline
+    let program = Program::parse("++++++++[>++++++++<-]>-.,.").unwrap();
+
+    for &engine in ENGINES {
+        let output = SharedBuffer::new();
+        let seen = SharedBuffer::new();
+        let input = Prompted { output: output.clone(), seen: seen.clone() };
+        let mut emu = Emu::new(16)
+            .with_input(input)
+            .with_output(output.clone());
+        if engine.is_jit() {
+            emu = emu.enable_jit(Arc::new(JitCache::new(1024 * 1024)));
+        }
+
+        emu.run_program(engine, &program);
+        assert_eq!(seen.contents(), b"?", "{:?}", engine);
+        assert_eq!(output.contents(), b"?!", "{:?}", engine);
+    }
+}
+
+#[test]
+fn reused_tape_keeps_its_cells() {
+    // The second program must see the cell the first one read
+    let first = Program::parse(",").unwrap();
+    let second = Program::parse(".").unwrap();
+
+    for &engine in ENGINES {
+        let output = SharedBuffer::new();
+        let mut emu = Emu::new(16)
+            .with_input(&b"A"[..])
+            .with_output(output.clone());
+        if engine.is_jit() {
+            emu = emu.enable_jit(Arc::new(JitCache::new(1024 * 1024)));
+        }
+
+        emu.run_program(engine, &first);
+        emu.run_program(engine, &second);
+        assert_eq!(output.contents(), b"A", "{:?}", engine);
+    }
+}
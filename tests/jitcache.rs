@@ -0,0 +1,30 @@
+//! One `JitCache` shared by several `Emu`s, each of which must run the code
+//! generated for its own program and options.
+
+use brainfuck_rvm::{CellWidth, Emu, Engine, JitCache};
+use std::sync::Arc;
+
+#[test]
+fn shared_cache_runs_every_program() {
+    let jit_cache = Arc::new(JitCache::new(1024 * 1024));
+    let runs: &[(&str, CellWidth, &[u8])] = &[
+        ("++++++++[>++++++++<-]>+", CellWidth::U8, b"A"),
+        ("++++++++[>++++++++<-]>++", CellWidth::U8, b"B"),
+        ("++++++++[>++++++++<-]>+", CellWidth::U8, b"A"),
+        ("--[-->+<]>", CellWidth::U8, b"\x7f"),
+        ("--[-->+<]>", CellWidth::U16, b"\xff\x7f"),
+    ];
+
+    for &engine in &[Engine::Jit, Engine::JitOpt] {
+        for (source, cell_width, expected) in runs {
+            let mut emu = Emu::new(16)
+                .with_cell_width(*cell_width)
+                .enable_jit(jit_cache.clone());
+
+            emu.run_engine(engine, source).unwrap();
+            let cell = cell_width.bytes();
+            assert_eq!(&emu.memory[cell..2 * cell], *expected, "{:?} {} ({})",
+                       engine, source, cell_width);
+        }
+    }
+}
@@ -0,0 +1,36 @@
+//! Cross-check the built-in encoder against Keystone, run with
+//! `cargo test --features keystone`.
+
+#![cfg(feature = "keystone")]
+
+use brainfuck_rvm::jit::{cross_check, JitOptions};
+use brainfuck_rvm::{CellWidth, EofPolicy, Program};
+
+const PROGRAMS: &[&str] = &[
+    include_str!("../programs/hello.bf"),
+    include_str!("../programs/mandelbrot.bf"),
+    ",[.,]>>>>-<<<<+++++++++++++++++++++++++++++++++++++++++++++++++++++++++++",
+    "[-]+[>>]<[<][->+>--<<]>[-<<<+>>>>++++++++++++++++++++++++++++++++++++++++++++++++++++++++<]",
+];
+
+#[test]
+fn encoder_matches_keystone() {
+    let widths = [CellWidth::U8, CellWidth::U16, CellWidth::U32, CellWidth::U64];
+    let policies = [EofPolicy::Unchanged, EofPolicy::Zero, EofPolicy::MinusOne, EofPolicy::Stop];
+
+    for source in PROGRAMS {
+        let program = Program::parse(source).unwrap();
+        for &cell_width in &widths {
+            for &eof_policy in &policies {
+                for &bounds_checks in &[false, true] {
+                    let options = JitOptions { bounds_checks, cell_width, eof_policy };
+                    for program in &[program.clone(), program.fold(), program.optimize()] {
+                        if let Err(err) = cross_check(program, &options) {
+                            panic!("{:?}: {}", options, err);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
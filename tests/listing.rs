@@ -0,0 +1,140 @@
+//! IR listings and disassembled JIT code. The disassembler must decode all
+//! code both backends generate, the AArch64 text is checked against the
+//! `llvm-mc` listing of `tests/aarch64.rs`.
+
+use brainfuck_rvm::disasm::{disassemble, jit_listing};
+use brainfuck_rvm::jit::{generate_jit_for, JitOptions, Target};
+use brainfuck_rvm::{CellWidth, EofPolicy, Program};
+
+const PROGRAMS: &[&str] = &[
+    include_str!("../programs/hello.bf"),
+    include_str!("../programs/mandelbrot.bf"),
+    ",----------[++++++++++.,----------]",
+    "+[-<].,",
+    "->-------------------------------------------------------------------<[>.<+]",
+    "++++[->+++>--<<]>[->>+<<]>>>---[+<<<+>>>]<[-<+>]",
+    ",[>+>++<]>>+[<+<++>]",
+];
+
+#[test]
+fn lists_ir() {
+    let source = ",[->+<]>.";
+    let program = Program::parse(source).unwrap().optimize();
+    assert_eq!(program.listing(source).lines().collect::<Vec<_>>(), [
+        "     0  1:1       read [ptr]",
+        "     1  1:2       loop -> 4",
+        "     2  1:5           [ptr+1] += [ptr] * 1",
+        "     3  1:3           [ptr] = 0",
+        "     4  1:7       end -> 1",
+        "     5  1:8       ptr += 1",
+        "     6  1:9       write [ptr]",
+    ]);
+}
+
+#[test]
+fn decodes_all_jit_code() {
+    for source in PROGRAMS {
+        let parsed = Program::parse(source).unwrap();
+        for program in &[parsed.clone(), parsed.optimize()] {
+            for &cell_width in &[CellWidth::U8, CellWidth::U16, CellWidth::U32, CellWidth::U64] {
+                for &eof_policy in &[EofPolicy::Unchanged, EofPolicy::MinusOne, EofPolicy::Stop] {
+                    for &bounds_checks in &[false, true] {
+                        for &target in &[Target::X86_64, Target::AArch64] {
+                            let options = JitOptions { bounds_checks, cell_width, eof_policy };
+                            let code = generate_jit_for(target, program, &options).unwrap();
+                            let instructions = disassemble(target, &code);
+
+                            let raw = instructions.iter()
+                                .find(|instruction| instruction.text.starts_with('.'));
+                            assert_eq!(raw, None, "{:?} {:?}", target, options);
+                            let len: usize = instructions.iter()
+                                .map(|instruction| instruction.len)
+                                .sum();
+                            assert_eq!(len, code.len());
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn decodes_aarch64() {
+    let program = Program::parse("+[-<].,").unwrap();
+    let options = JitOptions { bounds_checks: true, ..JitOptions::default() };
+    let code = generate_jit_for(Target::AArch64, &program, &options).unwrap();
+
+    let text: Vec<String> = disassemble(Target::AArch64, &code).into_iter()
+        .map(|instruction| instruction.text)
+        .collect();
+    assert_eq!(text, [
+        "mov x24, x30",
+        "ldrb w9, [x20]",
+        "add w9, w9, #0x1",
+        "strb w9, [x20]",
+        "ldrb w9, [x20]",
+        "cbz w9, 0x34",
+        "ldrb w9, [x20]",
+        "sub w9, w9, #0x1",
+        "strb w9, [x20]",
+        "sub x20, x20, #0x1",
+        "cmp x20, x21",
+        "b.lo 0x74",
+        "b 0x10",
+        "ldrb w1, [x20]",
+        "mov x0, x23",
+        "ldr x16, [x23, #8]",
+        "blr x16",
+        "cbnz x0, 0x6c",
+        "mov x0, x23",
+        "ldr x16, [x23]",
+        "blr x16",
+        "cmp x0, #0x100",
+        "b.eq 0x64",
+        "b.hi 0x6c",
+        "strb w0, [x20]",
+        "movz x0, #0x0",
+        "ret x24",
+        "movn x0, #0x1",
+        "ret x24",
+        "mov x1, x20",
+        "add x20, x20, #0x1",
+        "movz x0, #0x4",
+        "ret x24",
+    ]);
+}
+
+#[test]
+fn annotates_jit_code_with_source_positions() {
+    let source = "+\n[-<]";
+    let program = Program::parse(source).unwrap();
+    let options = JitOptions { bounds_checks: true, ..JitOptions::default() };
+
+    let listing = jit_listing(Target::X86_64, &program, source, &options).unwrap();
+    assert_eq!(listing.lines().collect::<Vec<_>>(), [
+        "; 0 at 1:1: [ptr] += 1",
+        "     0:  41 80 45 00 01                 add byte ptr [r13], 1",
+        "; 1 at 2:1: loop -> 4",
+        "     5:  41 80 7d 00 00                 cmp byte ptr [r13], 0",
+        "     a:  0f 84 14 00 00 00              je 0x24",
+        "; 2 at 2:2: [ptr] -= 1",
+        "    10:  41 80 6d 00 01                 sub byte ptr [r13], 1",
+        "; 3 at 2:3: ptr -= 1",
+        "    15:  49 83 ed 01                    sub r13, 0x1",
+        "    19:  4d 39 f5                       cmp r13, r14",
+        "    1c:  0f 82 0d 00 00 00              jb 0x2f",
+        "; 4 at 2:4: end -> 1",
+        "    22:  eb e1                          jmp 0x5",
+        "; exit",
+        "    24:  31 c0                          xor eax, eax",
+        "    26:  c3                             ret",
+        "    27:  48 c7 c0 fe ff ff ff           mov rax, 0xfffffffffffffffe",
+        "    2e:  c3                             ret",
+        "; out of line, 3 at 2:3: ptr -= 1",
+        "    2f:  4c 89 ea                       mov rdx, r13",
+        "    32:  49 83 c5 01                    add r13, 0x1",
+        "    36:  48 c7 c0 05 00 00 00           mov rax, 0x5",
+        "    3d:  c3                             ret",
+    ]);
+}
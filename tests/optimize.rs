@@ -0,0 +1,206 @@
+//! The loop idioms `Program::optimize` replaces, loops it must leave alone,
+//! the runs it addresses relative to the data pointer and the constants it
+//! propagates, and the levels and statistics of the `Optimizer`. Most
+//! programs start with `,` so the optimizer cannot know the value of the
+//! first cell.
+
+use brainfuck_rvm::optimize::PassStats;
+use brainfuck_rvm::{BfOperation, Optimizer, Pass, Program};
+
+fn optimize(source: &str) -> Vec<BfOperation> {
+    Program::parse(source).unwrap().optimize().ops
+}
+
+#[test]
+fn replaces_idioms() {
+    use BfOperation::*;
+
+    assert_eq!(optimize(",[-]>,[+++]"), [ReadStdin, SetZero, IncPtr(1), ReadStdin, SetZero]);
+    assert_eq!(optimize(",[>]<[<<<]"), [ReadStdin, ScanRight(1), DecPtr(1), ScanLeft(3)]);
+
+    // The brackets stay around multiply loops, resolved to the new indices
+    assert_eq!(optimize(",[->+++>>--<<<]"), [
+        ReadStdin,
+        LoopStart(5),
+        MulAdd { offset: 1, factor: 3 },
+        MulAdd { offset: 3, factor: 2u64.wrapping_neg() },
+        SetZero,
+        LoopEnd(1),
+    ]);
+
+    // Counting up negates the factors, cells without a net change are
+    // dropped
+    assert_eq!(optimize(",[<-<+>+>+-+]"), [
+        ReadStdin,
+        LoopStart(4),
+        MulAdd { offset: -2, factor: u64::MAX },
+        SetZero,
+        LoopEnd(1),
+    ]);
+}
+
+#[test]
+fn keeps_other_loops() {
+    use BfOperation::*;
+
+    for source in &[",[--]", ",[->+<<]", ",[-->+<]", ",[->.<]", ",[->,<]", ",[>+]", ",[-<>]"] {
+        let ops = optimize(source);
+        assert_eq!(ops[1], LoopStart(ops.len() - 1), "{}", source);
+        assert!(!ops.iter().any(|operation| {
+            matches!(operation, SetZero | ScanRight(_) | ScanLeft(_) | MulAdd { .. })
+        }), "{}", source);
+    }
+}
+
+#[test]
+fn addresses_cells() {
+    use BfOperation::*;
+
+    assert_eq!(optimize(",[>+>+<<-.]"), [
+        ReadStdin,
+        LoopStart(6),
+        Add { offset: 1, delta: 1 },
+        Add { offset: 2, delta: 1 },
+        Add { offset: 0, delta: u64::MAX },
+        WriteStdout,
+        LoopEnd(1),
+    ]);
+
+    // One pointer movement per run, cells without a net change are dropped
+    assert_eq!(optimize(",[>+>+-.[<<->]]"), [
+        ReadStdin,
+        LoopStart(9),
+        Add { offset: 1, delta: 1 },
+        IncPtr(2),
+        WriteStdout,
+        LoopStart(8),
+        Add { offset: -2, delta: u64::MAX },
+        DecPtr(1),
+        LoopEnd(5),
+        LoopEnd(1),
+    ]);
+
+    // A run reaching further than the cells it changes and where it ends
+    // checks the furthest cell with an `Add` of zero
+    assert_eq!(optimize(",>>><+<<"), [
+        ReadStdin,
+        Add { offset: 3, delta: 0 },
+        Set { offset: 2, value: 1 },
+    ]);
+
+    // Single operations stay as they are
+    assert_eq!(optimize(",[+.>.<]"), [
+        ReadStdin,
+        LoopStart(7),
+        IncData(1),
+        WriteStdout,
+        IncPtr(1),
+        WriteStdout,
+        DecPtr(1),
+        LoopEnd(1),
+    ]);
+}
+
+#[test]
+fn propagates_constants() {
+    use BfOperation::*;
+
+    // Comment loops at the start never run, known cells are only stored
+    // when the program ends
+    assert_eq!(optimize("[comment, loop.]+++.--."), [
+        WriteConst(3),
+        WriteConst(1),
+        Set { offset: 0, value: 1 },
+    ]);
+
+    // Multiply loops with a known counter run at compile time, known cells
+    // are stored before every pointer movement
+    assert_eq!(optimize("++++++++[->++++++++<]>+.[-]"), [
+        Set { offset: 1, value: 65 },
+        IncPtr(1),
+        WriteConst(b'A'),
+        SetZero,
+    ]);
+
+    // A loop ends on a zero cell, and `[-]` leaves one
+    assert_eq!(optimize(",[.,][],[-][]"), [
+        ReadStdin,
+        LoopStart(4),
+        WriteStdout,
+        ReadStdin,
+        LoopEnd(1),
+        ReadStdin,
+        SetZero,
+    ]);
+
+    // A counter of 256 is only zero at 8-bit cells, the loop stays
+    assert_eq!(optimize(&("+".repeat(256) + "[->+<]")), [
+        Set { offset: 0, value: 256 },
+        LoopStart(4),
+        MulAdd { offset: 1, factor: 1 },
+        SetZero,
+        LoopEnd(1),
+    ]);
+
+    // On a tape which may hold anything only stored cells are known
+    let program = Program::parse("[-].>.+++.").unwrap();
+    assert_eq!(Optimizer::default().with_zero_tape(false).run(&program).ops, [
+        WriteConst(0),
+        SetZero,
+        IncPtr(1),
+        WriteStdout,
+        IncData(3),
+        WriteStdout,
+    ]);
+}
+
+#[test]
+fn levels_select_passes() {
+    let program = Program::parse(",>>+<<[->+<]>>[-]<.").unwrap();
+
+    assert_eq!(Optimizer::from_level(0).unwrap().run(&program), program);
+    assert_eq!(Optimizer::from_level(1).unwrap().run(&program), program.fold());
+    assert_eq!(Optimizer::from_level(2).unwrap().run(&program), program.optimize());
+    assert_eq!(Optimizer::default(), Optimizer::from_level(2).unwrap());
+    assert_eq!(Optimizer::from_level(4), None);
+
+    // Without constant propagation the known cell is cleared at run time
+    let ops = Optimizer::default().disable(Pass::Constants).run(&program).ops;
+    assert_eq!(ops.iter().filter(|&&operation| operation == BfOperation::SetZero).count(), 2);
+    assert!(!ops.contains(&BfOperation::WriteConst(0)));
+    assert_eq!(Optimizer::default().passes().collect::<Vec<_>>(), Pass::ALL);
+}
+
+#[test]
+fn records_pass_stats() {
+    use BfOperation::*;
+
+    // The multiply loop runs at compile time
+    let (program, stats) = Optimizer::default()
+        .run_with_stats(&Program::parse("+++[->++<]>.,>+>-<[-]").unwrap());
+    assert_eq!(program.ops, [
+        Set { offset: 1, value: 6 },
+        IncPtr(1),
+        WriteConst(6),
+        ReadStdin,
+        Set { offset: 1, value: 1 },
+        Set { offset: 2, value: u64::MAX },
+        IncPtr(1),
+        SetZero,
+    ]);
+    assert_eq!(stats, [
+        PassStats { pass: Pass::Fold, ops_before: 21, ops_after: 18, loops_rewritten: 0 },
+        PassStats { pass: Pass::Idioms, ops_before: 18, ops_after: 14, loops_rewritten: 2 },
+        PassStats { pass: Pass::Offsets, ops_before: 14, ops_after: 12, loops_rewritten: 0 },
+        PassStats { pass: Pass::Constants, ops_before: 12, ops_after: 8, loops_rewritten: 1 },
+    ]);
+
+    // Removing the clear of a zero cell leaves `><` for a second round of
+    // `-O3` to turn into a check of the cell it visits, a third one finds
+    // nothing left to do
+    let (program, stats) = Optimizer::from_level(3).unwrap()
+        .run_with_stats(&Program::parse(",>[-]<").unwrap());
+    assert_eq!(program.ops, [ReadStdin, Add { offset: 1, delta: 0 }]);
+    assert_eq!(stats.len(), 3 * Pass::ALL.len());
+    assert_eq!(stats[Pass::ALL.len()..].iter().map(PassStats::ops_removed).sum::<isize>(), 1);
+}
@@ -0,0 +1,174 @@
+//! Source backends: every program is translated, built with the system C
+//! compiler or rustc and must agree with `Engine::Vm3` on output and exit
+//! code.
+
+use brainfuck_rvm::aot::AotOptions;
+use brainfuck_rvm::jit::JitOptions;
+use brainfuck_rvm::transpile::{generate_c, generate_rust};
+use brainfuck_rvm::{CellWidth, Emu, Engine, EofPolicy, Program, SharedBuffer};
+
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+const PROGRAMS: &[(&str, &str)] = &[
+    ("hello", include_str!("../programs/hello.bf")),
+    ("echo-line", ",----------[++++++++++.,----------]"),
+    ("wrap", "->->-<<[>.>.<<+]"),
+    ("big-delta", "->-------------------------------------------------------------------
+                  ------------------------------------------------------------------
+                  ------------------------------------------------------------------
+                  -------------------------------------------------------------<[>.<+]"),
+    ("nested", "++[>++[>++<-]<-]>>[-<+>]<."),
+    ("off-the-end", "+[>+]"),
+    ("off-the-start", "+>++<<"),
+    ("read-past-eof", ">+,.>+,.>+,.>+,."),
+    ("idioms", "+++[-]>+>+>+<<[>]++++++++[->+++>--<<]>>[<]>>[-<<<+>>>]<<<."),
+    ("scan-off-the-end", "+[[>]+]"),
+    ("multiply-off-the-start", "+[-<+>]"),
+    ("offsets-off-the-end", "+[>+>++<]"),
+    ("constants", "[comment.]++++++++[->++++++++<]>+.>-.<[-][.]<<+"),
+];
+
+const INPUT: &[u8] = b"ab\n";
+
+const CELL_WIDTHS: &[CellWidth] = &[CellWidth::U8, CellWidth::U16, CellWidth::U32, CellWidth::U64];
+
+/// Run the executable at `path`, returning the exit code and stdout
+fn run_executable(path: &Path) -> (Option<i32>, Vec<u8>) {
+    let mut child = Command::new(path)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let _ = child.stdin.take().unwrap().write_all(INPUT);
+
+    let output = child.wait_with_output().unwrap();
+    (output.status.code(), output.stdout)
+}
+
+/// Run `source` with `Engine::Vm3`, returning the exit code and output
+fn run_vm3(source: &str, options: &AotOptions) -> (Option<i32>, Vec<u8>) {
+    let output = SharedBuffer::new();
+    let mut emu = Emu::new(options.tape_cells)
+        .with_cell_width(options.jit.cell_width)
+        .with_eof_policy(options.jit.eof_policy)
+        .with_input(INPUT)
+        .with_output(output.clone());
+    let exit = emu.run_program(Engine::Vm3, &Program::parse(source).unwrap());
+    drop(emu);
+    (exit.map(|exit| exit.exit_code()), output.contents())
+}
+
+/// Every combination of cell width and EOF policy, with bounds checks
+fn all_options() -> Vec<AotOptions> {
+    let mut options = Vec::new();
+    for &cell_width in CELL_WIDTHS {
+        for &eof_policy in &[EofPolicy::Unchanged, EofPolicy::Zero, EofPolicy::MinusOne, EofPolicy::Stop] {
+            options.push(AotOptions {
+                jit: JitOptions { bounds_checks: true, cell_width, eof_policy },
+                tape_cells: 64,
+                unbuffered: false,
+                ..AotOptions::default()
+            });
+        }
+    }
+    options
+}
+
+#[test]
+fn c_agrees_with_vm3() {
+    if Command::new("cc").arg("--version").output().is_err() {
+        eprintln!("no C compiler, skipping");
+        return;
+    }
+
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR"));
+    for (name, source) in PROGRAMS {
+        for options in all_options() {
+            let stem = format!("{}-{}-{:?}", name, options.jit.cell_width, options.jit.eof_policy);
+            let c_path = dir.join(format!("{}.c", stem));
+            let exe_path = dir.join(format!("{}-c", stem));
+            fs::write(&c_path, generate_c(&Program::parse(source).unwrap(), &options)).unwrap();
+
+            let status = Command::new("cc")
+                .args(["-std=c99", "-Wall", "-Werror", "-O1", "-o"])
+                .arg(&exe_path)
+                .arg(&c_path)
+                .status()
+                .unwrap();
+            assert!(status.success(), "{}: C compiler failed", stem);
+
+            assert_eq!(run_executable(&exe_path), run_vm3(source, &options), "{}", stem);
+        }
+    }
+}
+
+#[test]
+fn rust_agrees_with_vm3() {
+    // Build every variant as a module of one crate, the first argument
+    // selects the one to run
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR"));
+    let mut main = String::new();
+    let mut arms = String::new();
+    let mut cases = Vec::new();
+    for (name, source) in PROGRAMS {
+        for options in all_options() {
+            let module = format!("case{}", cases.len());
+            let path = dir.join(format!("{}.rs", module));
+            fs::write(&path, generate_rust(&Program::parse(source).unwrap(), &options)).unwrap();
+
+            main.push_str(&format!("mod {} {{ include!({:?}); }}\n", module, path));
+            arms.push_str(&format!("        {} => run!({}, u{}),\n",
+                                   cases.len(), module, options.jit.cell_width.bits()));
+            cases.push((name, source, options));
+        }
+    }
+    main.push_str(&format!(r#"
+macro_rules! run {{
+    ($module:ident, $cell:ty) => {{{{
+        let mut tape = vec![0 as $cell; 64];
+        let exit = $module::run(&mut tape, &mut std::io::stdin(), &mut std::io::stdout()).unwrap();
+        match exit {{
+            $module::Exit::PtrOob(_) => 2,
+            $module::Exit::InputExhausted => 4,
+            _ => 0,
+        }}
+    }}}};
+}}
+
+fn main() {{
+    let case: usize = std::env::args().nth(1).unwrap().parse().unwrap();
+    std::process::exit(match case {{
+{}        _ => unreachable!(),
+    }});
+}}
+"#, arms));
+
+    let main_path = dir.join("cases.rs");
+    let exe_path = dir.join("cases");
+    fs::write(&main_path, main).unwrap();
+    let status = Command::new("rustc")
+        .args(["--edition", "2018", "-D", "warnings", "-o"])
+        .arg(&exe_path)
+        .arg(&main_path)
+        .status()
+        .unwrap();
+    assert!(status.success(), "rustc failed");
+
+    for (case, (name, source, options)) in cases.iter().enumerate() {
+        let mut child = Command::new(&exe_path)
+            .arg(case.to_string())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let _ = child.stdin.take().unwrap().write_all(INPUT);
+        let output = child.wait_with_output().unwrap();
+
+        assert_eq!((output.status.code(), output.stdout), run_vm3(source, options),
+                   "{}-{}-{:?}", name, options.jit.cell_width, options.jit.eof_policy);
+    }
+}
@@ -0,0 +1,123 @@
+//! WebAssembly backend: every module is validated and run in an embedded
+//! interpreter and must agree with `Engine::Vm3` on output and exit reason.
+
+use brainfuck_rvm::aot::AotOptions;
+use brainfuck_rvm::jit::JitOptions;
+use brainfuck_rvm::wasm::{generate_wasm, INPUT_EXHAUSTED};
+use brainfuck_rvm::{CellWidth, Emu, Engine, EofPolicy, Program, SharedBuffer, VmExit};
+
+use wasmi::{Caller, Engine as WasmEngine, Linker, Module, Store};
+
+const PROGRAMS: &[(&str, &str)] = &[
+    ("hello", include_str!("../programs/hello.bf")),
+    ("echo-line", ",----------[++++++++++.,----------]"),
+    ("wrap", "->->-<<[>.>.<<+]"),
+    ("big-delta", "->-------------------------------------------------------------------
+                  ------------------------------------------------------------------
+                  ------------------------------------------------------------------
+                  -------------------------------------------------------------<[>.<+]"),
+    ("nested", "++[>++[>++<-]<-]>>[-<+>]<."),
+    ("off-the-end", "+[>+]"),
+    ("off-the-start", "+>++<<"),
+    ("read-past-eof", ">+,.>+,.>+,.>+,."),
+    ("idioms", "+++[-]>+>+>+<<[>]++++++++[->+++>--<<]>>[<]>>[-<<<+>>>]<<<."),
+    ("scan-off-the-end", "+[[>]+]"),
+    ("multiply-off-the-start", "+[-<+>]"),
+    ("offsets-off-the-end", "+[>+>++<]"),
+    ("constants", "[comment.]++++++++[->++++++++<]>+.>-.<[-][.]<<+"),
+];
+
+const INPUT: &[u8] = b"ab\n";
+
+const CELL_WIDTHS: &[CellWidth] = &[CellWidth::U8, CellWidth::U16, CellWidth::U32, CellWidth::U64];
+
+/// Host state of a module: the remaining input and the output so far
+struct Io {
+    input: &'static [u8],
+    output: Vec<u8>,
+}
+
+/// Instantiate the module for `source` and call `run`, returning its status
+/// and the output
+fn run_wasm(source: &str, options: &AotOptions) -> (i32, Vec<u8>) {
+    let wasm = generate_wasm(&Program::parse(source).unwrap(), options);
+    let engine = WasmEngine::default();
+    let module = Module::new(&engine, &wasm[..]).expect("invalid module");
+
+    let mut store = Store::new(&engine, Io { input: INPUT, output: Vec::new() });
+    let mut linker = <Linker<Io>>::new(&engine);
+    linker.func_wrap("env", "read", |mut caller: Caller<'_, Io>| -> i32 {
+        let io = caller.data_mut();
+        match io.input.split_first() {
+            Some((&byte, rest)) => {
+                io.input = rest;
+                byte.into()
+            },
+            None => -1,
+        }
+    }).unwrap();
+    linker.func_wrap("env", "write", |mut caller: Caller<'_, Io>, byte: i32| {
+        caller.data_mut().output.push(byte as u8);
+    }).unwrap();
+
+    let instance = linker.instantiate(&mut store, &module).unwrap()
+        .start(&mut store).unwrap();
+    let run = instance.get_typed_func::<(), i32>(&store, "run").unwrap();
+    let status = run.call(&mut store, ()).unwrap();
+    (status, store.into_data().output)
+}
+
+/// Run `source` with `Engine::Vm3`, returning the status `run` should return
+/// and the output
+fn run_vm3(source: &str, options: &AotOptions) -> (i32, Vec<u8>) {
+    let output = SharedBuffer::new();
+    let mut emu = Emu::new(options.tape_cells)
+        .with_cell_width(options.jit.cell_width)
+        .with_eof_policy(options.jit.eof_policy)
+        .with_input(INPUT)
+        .with_output(output.clone());
+    let exit = emu.run_program(Engine::Vm3, &Program::parse(source).unwrap());
+    drop(emu);
+    let status = match exit {
+        Some(VmExit::Exit(_)) => 0,
+        Some(VmExit::InputExhausted { .. }) => INPUT_EXHAUSTED,
+        Some(VmExit::PtrOob { offset: Some(offset), .. }) => offset as i32 + 1,
+        exit => panic!("unexpected exit {:?}", exit),
+    };
+    (status, output.contents())
+}
+
+#[test]
+fn modules_agree_with_vm3() {
+    for (name, source) in PROGRAMS {
+        for &cell_width in CELL_WIDTHS {
+            for &eof_policy in &[EofPolicy::Unchanged, EofPolicy::Zero, EofPolicy::MinusOne, EofPolicy::Stop] {
+                let options = AotOptions {
+                    jit: JitOptions { bounds_checks: true, cell_width, eof_policy },
+                    tape_cells: 64,
+                    unbuffered: false,
+                    ..AotOptions::default()
+                };
+                assert_eq!(run_wasm(source, &options), run_vm3(source, &options),
+                           "{} ({}, {:?})", name, cell_width, eof_policy);
+            }
+        }
+    }
+}
+
+#[test]
+fn unchecked_pointer_traps_outside_memory() {
+    let options = AotOptions::default();
+    let wasm = generate_wasm(&Program::parse("<+").unwrap(), &options);
+    let engine = WasmEngine::default();
+    let module = Module::new(&engine, &wasm[..]).unwrap();
+
+    let mut store = Store::new(&engine, ());
+    let mut linker = <Linker<()>>::new(&engine);
+    linker.func_wrap("env", "read", || -> i32 { -1 }).unwrap();
+    linker.func_wrap("env", "write", |_: i32| {}).unwrap();
+    let instance = linker.instantiate(&mut store, &module).unwrap()
+        .start(&mut store).unwrap();
+    let run = instance.get_typed_func::<(), i32>(&store, "run").unwrap();
+    assert!(run.call(&mut store, ()).is_err());
+}